@@ -0,0 +1,322 @@
+use crate::region::ChunkUpgrader;
+use java_string::{JavaStr, JavaString};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{error, info, info_span, warn};
+use valence_anvil::RegionFolder;
+use valence_nbt::jcompound;
+use world_transmuter::version_names::get_version_by_id;
+use world_transmuter_engine::{JCompound, JList, JValue};
+
+const FLATTENING_VERSION: u32 = 1451; // 17w47a
+const BIOMES_3D_VERSION: u32 = 2203; // 19w36a
+const NEW_CHUNK_FORMAT_VERSION: u32 = 2844; // 21w43a
+
+const PALETTE: [&str; 4] = [
+    "minecraft:air",
+    "minecraft:stone",
+    "minecraft:dirt",
+    "minecraft:grass_block",
+];
+const LEGACY_BLOCK_IDS: [i8; 4] = [0, 1, 3, 2];
+
+/// Where region data for the benchmark comes from.
+pub enum Fixture {
+    /// Synthetic chunks generated at the given data version.
+    Generated(u32),
+    /// An existing region folder.
+    Folder(PathBuf),
+}
+
+/// Measures how many chunks per second are read, converted and written for each fixture.
+pub fn run_bench(to_version: u32, fixtures: &[Fixture], chunk_count: usize) {
+    let scratch =
+        std::env::temp_dir().join(format!("world-transmuter-bench-{}", std::process::id()));
+    if let Err(err) = std::fs::create_dir_all(&scratch) {
+        error!("Failed to create scratch dir: {err}");
+        return;
+    }
+
+    for fixture in fixtures {
+        match fixture {
+            Fixture::Generated(from_version) => {
+                let name = get_version_by_id(*from_version).map_or_else(
+                    || from_version.to_string(),
+                    |version| version.name.to_owned(),
+                );
+                let _span = info_span!("Benchmarking", message = name).entered();
+                if *from_version >= to_version {
+                    warn!("Not older than the target version, skipping");
+                    continue;
+                }
+                let regions_path = scratch.join(format!("fixture-{from_version}"));
+                if !generate_fixture(&regions_path, *from_version, chunk_count) {
+                    continue;
+                }
+                bench_region_folder(&regions_path, &scratch, to_version);
+            }
+            Fixture::Folder(regions_path) => {
+                let _span = info_span!(
+                    "Benchmarking",
+                    message = regions_path.to_string_lossy().as_ref()
+                )
+                .entered();
+                bench_region_folder(regions_path, &scratch, to_version);
+            }
+        }
+    }
+
+    if let Err(err) = std::fs::remove_dir_all(&scratch) {
+        error!("Failed to remove scratch dir: {err}");
+    }
+}
+
+fn bench_region_folder(regions_path: &Path, scratch: &Path, to_version: u32) {
+    let mut region_folder = RegionFolder::new(regions_path);
+    let positions: Vec<_> = match region_folder.all_chunk_positions() {
+        Ok(positions) => positions.filter_map(|pos| pos.ok()).collect(),
+        Err(err) => {
+            error!("Error listing chunks: {err}");
+            return;
+        }
+    };
+
+    let start = Instant::now();
+    let mut chunks = Vec::with_capacity(positions.len());
+    for (chunk_x, chunk_z) in positions {
+        match region_folder.get_chunk(chunk_x, chunk_z) {
+            Ok(Some(chunk)) => chunks.push((chunk_x, chunk_z, chunk.data)),
+            Ok(None) => {}
+            Err(err) => error!("Error reading chunk at {chunk_x}, {chunk_z}: {err}"),
+        }
+    }
+    log_phase("Read and decompress", chunks.len(), start.elapsed());
+
+    // the scratch folder has no legacy structure data and no entities folder, so only the
+    // conversion itself is measured
+    let chunk_upgrader = ChunkUpgrader::new(
+        JavaStr::from_str("minecraft:overworld"),
+        JavaStr::from_str("minecraft:noise"),
+        scratch,
+        to_version,
+        true,
+    );
+    let mut entity_region_folder = RegionFolder::new(scratch.join("entities"));
+    let start = Instant::now();
+    let mut num_failed = 0;
+    for (chunk_x, chunk_z, chunk) in &mut chunks {
+        if chunk_upgrader
+            .upgrade_chunk(*chunk_x, *chunk_z, chunk, &mut entity_region_folder)
+            .is_err()
+        {
+            num_failed += 1;
+        }
+    }
+    log_phase("Convert", chunks.len(), start.elapsed());
+    if num_failed > 0 {
+        warn!("{num_failed} chunks failed to convert");
+    }
+
+    let output_path = scratch.join("output");
+    let mut output_folder = RegionFolder::new(&output_path);
+    let start = Instant::now();
+    for (chunk_x, chunk_z, chunk) in &chunks {
+        if let Err(err) = output_folder.set_chunk(*chunk_x, *chunk_z, chunk) {
+            error!("Error writing chunk at {chunk_x}, {chunk_z}: {err}");
+        }
+    }
+    log_phase("Compress and write", chunks.len(), start.elapsed());
+    drop(output_folder);
+
+    if let Err(err) = std::fs::remove_dir_all(&output_path) {
+        error!("Failed to remove benchmark output: {err}");
+    }
+}
+
+fn log_phase(phase: &str, num_chunks: usize, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    info!(
+        "{phase}: {num_chunks} chunks in {seconds:.2}s ({:.0} chunks/s)",
+        num_chunks as f64 / seconds
+    );
+}
+
+#[must_use]
+fn generate_fixture(regions_path: &Path, from_version: u32, chunk_count: usize) -> bool {
+    if let Err(err) = std::fs::create_dir_all(regions_path) {
+        error!("Failed to create fixture dir: {err}");
+        return false;
+    }
+    let mut region_folder = RegionFolder::new(regions_path);
+    for index in 0..chunk_count {
+        let chunk_x = (index % 32) as i32;
+        let chunk_z = (index / 32) as i32;
+        let chunk = if from_version < FLATTENING_VERSION {
+            legacy_chunk(chunk_x, chunk_z, from_version)
+        } else if from_version < NEW_CHUNK_FORMAT_VERSION {
+            paletted_chunk(chunk_x, chunk_z, from_version)
+        } else {
+            new_format_chunk(chunk_x, chunk_z, from_version)
+        };
+        if let Err(err) = region_folder.set_chunk(chunk_x, chunk_z, &chunk) {
+            error!("Error writing fixture chunk at {chunk_x}, {chunk_z}: {err}");
+            return false;
+        }
+    }
+    true
+}
+
+/// The palette index of the block at the given height, giving each chunk a bit of terrain.
+fn block_at(y: i32) -> usize {
+    match y {
+        ..=59 => 1,
+        60..=62 => 2,
+        63 => 3,
+        _ => 0,
+    }
+}
+
+fn section_blocks(section_y: i32) -> impl Iterator<Item = usize> {
+    (0..4096).map(move |index| block_at(section_y * 16 + index / 256))
+}
+
+/// Packs palette indices into longs, four bits each.
+fn pack_block_states(section_y: i32) -> Vec<i64> {
+    let mut longs = vec![0i64; 256];
+    for (index, block) in section_blocks(section_y).enumerate() {
+        longs[index / 16] |= (block as i64) << ((index % 16) * 4);
+    }
+    longs
+}
+
+fn block_palette() -> JList {
+    JList::Compound(
+        PALETTE
+            .iter()
+            .map(|name| jcompound! { "Name" => JavaStr::from_str(name) })
+            .collect(),
+    )
+}
+
+fn pig(chunk_x: i32, chunk_z: i32) -> JCompound {
+    jcompound! {
+        "id" => JavaStr::from_str("minecraft:pig"),
+        "Pos" => JList::Double(vec![(chunk_x * 16 + 8) as f64, 64.0, (chunk_z * 16 + 8) as f64]),
+        "Motion" => JList::Double(vec![0.0, 0.0, 0.0]),
+        "Rotation" => JList::Float(vec![0.0, 0.0]),
+        "UUIDMost" => ((chunk_x as i64) << 32) | 0x4000,
+        "UUIDLeast" => chunk_z as i64 | i64::MIN,
+        "Health" => 10.0f32,
+    }
+}
+
+fn chest(chunk_x: i32, chunk_z: i32, legacy: bool) -> JCompound {
+    let mut item = jcompound! {
+        "Slot" => 0i8,
+        "id" => JavaStr::from_str("minecraft:stone"),
+        "Count" => 1i8,
+    };
+    if legacy {
+        item.insert("Damage", 0i16);
+    }
+    jcompound! {
+        "id" => JavaStr::from_str("minecraft:chest"),
+        "x" => chunk_x * 16,
+        "y" => 64,
+        "z" => chunk_z * 16,
+        "Items" => JList::Compound(vec![item]),
+    }
+}
+
+fn legacy_chunk(chunk_x: i32, chunk_z: i32, data_version: u32) -> JCompound {
+    let sections = (0..8)
+        .map(|section_y| {
+            jcompound! {
+                "Y" => section_y as i8,
+                "Blocks" => JValue::ByteArray(
+                    section_blocks(section_y).map(|block| LEGACY_BLOCK_IDS[block]).collect(),
+                ),
+                "Data" => JValue::ByteArray(vec![0; 2048]),
+                "BlockLight" => JValue::ByteArray(vec![0; 2048]),
+                "SkyLight" => JValue::ByteArray(vec![-1; 2048]),
+            }
+        })
+        .collect();
+    jcompound! {
+        "DataVersion" => data_version as i32,
+        "Level" => jcompound! {
+            "xPos" => chunk_x,
+            "zPos" => chunk_z,
+            "LastUpdate" => 0i64,
+            "InhabitedTime" => 0i64,
+            "TerrainPopulated" => 1i8,
+            "LightPopulated" => 1i8,
+            "V" => 1i8,
+            "Biomes" => JValue::ByteArray(vec![1; 256]),
+            "HeightMap" => JValue::IntArray(vec![64; 256]),
+            "Sections" => JList::Compound(sections),
+            "Entities" => JList::Compound(vec![pig(chunk_x, chunk_z)]),
+            "TileEntities" => JList::Compound(vec![chest(chunk_x, chunk_z, true)]),
+        },
+    }
+}
+
+fn paletted_chunk(chunk_x: i32, chunk_z: i32, data_version: u32) -> JCompound {
+    let sections = (0..8)
+        .map(|section_y| {
+            jcompound! {
+                "Y" => section_y as i8,
+                "Palette" => block_palette(),
+                "BlockStates" => JValue::LongArray(pack_block_states(section_y)),
+            }
+        })
+        .collect();
+    // biomes were stored per column until they became 3D in 19w36a
+    let num_biomes = if data_version < BIOMES_3D_VERSION {
+        256
+    } else {
+        1024
+    };
+    jcompound! {
+        "DataVersion" => data_version as i32,
+        "Level" => jcompound! {
+            "xPos" => chunk_x,
+            "zPos" => chunk_z,
+            "Status" => JavaStr::from_str("full"),
+            "LastUpdate" => 0i64,
+            "InhabitedTime" => 0i64,
+            "Biomes" => JValue::IntArray(vec![1; num_biomes]),
+            "Sections" => JList::Compound(sections),
+            "Entities" => JList::Compound(vec![pig(chunk_x, chunk_z)]),
+            "TileEntities" => JList::Compound(vec![chest(chunk_x, chunk_z, false)]),
+        },
+    }
+}
+
+fn new_format_chunk(chunk_x: i32, chunk_z: i32, data_version: u32) -> JCompound {
+    let sections = (-4..8)
+        .map(|section_y| {
+            jcompound! {
+                "Y" => section_y as i8,
+                "block_states" => jcompound! {
+                    "palette" => block_palette(),
+                    "data" => JValue::LongArray(pack_block_states(section_y)),
+                },
+                "biomes" => jcompound! {
+                    "palette" => JList::String(vec![JavaString::from("minecraft:plains")]),
+                },
+            }
+        })
+        .collect();
+    jcompound! {
+        "DataVersion" => data_version as i32,
+        "xPos" => chunk_x,
+        "yPos" => -4,
+        "zPos" => chunk_z,
+        "Status" => JavaStr::from_str("full"),
+        "LastUpdate" => 0i64,
+        "InhabitedTime" => 0i64,
+        "sections" => JList::Compound(sections),
+        "block_entities" => JList::Compound(vec![chest(chunk_x, chunk_z, false)]),
+    }
+}
@@ -0,0 +1,189 @@
+use crate::formats;
+use crate::formats::Format;
+use crate::individual_files::update_level_data;
+use crate::region::{upgrade_region_file, ChunkUpgrader};
+use crate::{data_type_by_name, try_upgrade};
+use java_string::JavaStr;
+use std::path::{Path, PathBuf};
+use tracing::{error, info_span};
+use valence_anvil::RegionFolder;
+use world_transmuter::version_names::get_version_by_id;
+use world_transmuter_engine::{JCompound, JValue};
+
+/// What to convert a standalone file as, and to which version.
+pub struct ConvertOptions<'a> {
+    pub type_name: &'a str,
+    /// The version to assume for data which doesn't record its own `DataVersion`.
+    pub from_version: Option<u32>,
+    pub to_version: u32,
+    pub dimension: &'a JavaStr,
+    pub generator: &'a JavaStr,
+    /// The world to read the legacy structure data of pre-1.13 chunks from. Without one, the
+    /// structures in those chunks aren't carried over.
+    pub world: Option<&'a Path>,
+    /// The format to write the output in, defaulting to the format of the input.
+    pub output_format: Option<Format>,
+}
+
+/// Upgrades a single file outside of a world, writing the result to `output`. Region files are
+/// copied to `output` and then upgraded chunk by chunk, any other file can be NBT (gzipped,
+/// zlib-compressed or uncompressed), SNBT or JSON.
+pub fn convert(input: &Path, output: &Path, options: &ConvertOptions) -> bool {
+    let _span = info_span!("Converting", message = input.to_string_lossy().as_ref()).entered();
+
+    if input.extension() == Some("mca".as_ref()) {
+        return convert_region_file(input, output, options);
+    }
+
+    let contents = match std::fs::read(input) {
+        Ok(contents) => contents,
+        Err(err) => {
+            error!("Failed to read {}: {}", input.to_string_lossy(), err);
+            return false;
+        }
+    };
+    let Some((mut data, input_format)) = formats::read_any(&contents) else {
+        error!("Failed to parse {}", input.to_string_lossy());
+        return false;
+    };
+
+    // level.dat keeps its data in a Data tag
+    let is_level_dat =
+        options.type_name == "level" && matches!(data.get("Data"), Some(JValue::Compound(_)));
+    let target = if is_level_dat {
+        let Some(JValue::Compound(level_data)) = data.get_mut("Data") else {
+            unreachable!()
+        };
+        level_data
+    } else {
+        &mut data
+    };
+    if !convert_compound(target, &input.to_string_lossy(), options) {
+        return false;
+    }
+
+    let output_format = options.output_format.unwrap_or(input_format);
+    if let Err(err) =
+        formats::write(data, output_format).and_then(|contents| std::fs::write(output, contents))
+    {
+        error!("Failed to write {}: {}", output.to_string_lossy(), err);
+        return false;
+    }
+    true
+}
+
+/// Upgrades a compound read from somewhere other than a world, using the version it records
+/// itself or the one in the options.
+#[must_use]
+pub fn convert_compound(data: &mut JCompound, name: &str, options: &ConvertOptions) -> bool {
+    let default_version = match options.from_version {
+        Some(from_version) => from_version,
+        None if data.contains_key("DataVersion") => 99,
+        None => {
+            error!("{name} has no DataVersion, use --from to say which version it's from");
+            return false;
+        }
+    };
+
+    if options.type_name == "level" {
+        // the same as for the level.dat of a world, which also moves old world generation settings
+        let from_version = data
+            .remove("DataVersion")
+            .and_then(|v| v.as_i32())
+            .map_or(default_version, |v| v as u32);
+        let Some(from_version) = get_version_by_id(from_version) else {
+            error!("{name} had unrecognized data version {from_version}");
+            return false;
+        };
+        if from_version.data_version > options.to_version {
+            error!("Cannot downgrade {name} from {}", from_version.name);
+            return false;
+        }
+        update_level_data(data, from_version.data_version, options.to_version);
+        return true;
+    }
+
+    if options.type_name == "chunk" {
+        // a lone chunk has nowhere to put its entities, so they stay in the chunk
+        let mut chunk_upgrader = ChunkUpgrader::new(
+            options.dimension,
+            options.generator,
+            options.world.unwrap_or(Path::new("")),
+            options.to_version,
+            true,
+        );
+        if options.world.is_none() {
+            chunk_upgrader.skip_legacy_structures();
+        }
+        if !data.contains_key("DataVersion") {
+            data.insert("DataVersion", default_version as i32);
+        }
+        let chunk_x = chunk_coordinate(data, "xPos");
+        let chunk_z = chunk_coordinate(data, "zPos");
+        return chunk_upgrader
+            .upgrade_chunk(
+                chunk_x,
+                chunk_z,
+                data,
+                &mut RegionFolder::new(PathBuf::new()),
+            )
+            .is_ok();
+    }
+
+    let Some(data_type) = data_type_by_name(options.type_name) else {
+        error!("Unknown data type {}", options.type_name);
+        return false;
+    };
+    try_upgrade(
+        data_type,
+        data,
+        || name.to_owned(),
+        options.to_version,
+        default_version,
+    )
+    .is_ok()
+}
+
+fn chunk_coordinate(chunk: &JCompound, key: &str) -> i32 {
+    let level = match chunk.get("Level") {
+        Some(JValue::Compound(level)) => level,
+        _ => chunk,
+    };
+    level.get(key).and_then(|v| v.as_i32()).unwrap_or(0)
+}
+
+fn convert_region_file(input: &Path, output: &Path, options: &ConvertOptions) -> bool {
+    if options.output_format.is_some() {
+        error!("--format can't be used with region files");
+        return false;
+    }
+    if options.from_version.is_some() {
+        error!("--from can't be used with region files, as every chunk records its own version");
+        return false;
+    }
+
+    let same_file = match (input.canonicalize(), output.canonicalize()) {
+        (Ok(input), Ok(output)) => input == output,
+        _ => false,
+    };
+    if !same_file {
+        if let Err(err) = std::fs::copy(input, output) {
+            error!(
+                "Failed to copy {} to {}: {}",
+                input.to_string_lossy(),
+                output.to_string_lossy(),
+                err
+            );
+            return false;
+        }
+    }
+
+    upgrade_region_file(
+        output,
+        options.type_name,
+        options.dimension,
+        options.generator,
+        options.world,
+        options.to_version,
+    )
+}
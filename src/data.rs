@@ -1,4 +1,5 @@
-use crate::upgrade;
+use crate::quarantine::{quarantine_file, FileFormat, Quarantine};
+use crate::{is_up_to_date, try_upgrade, UpgradeOutcome};
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
@@ -7,7 +8,7 @@ use std::io;
 use std::io::{ErrorKind, Read, Seek, SeekFrom};
 use std::path::Path;
 use std::sync::RwLockReadGuard;
-use tracing::{error, info_span};
+use tracing::{error, info, info_span};
 use valence_nbt::{from_binary, to_binary};
 use world_transmuter::types;
 use world_transmuter_engine::{JCompound, MapDataType};
@@ -42,45 +43,72 @@ pub fn read_data(dim_folder: &Path, name: impl Into<String>) -> io::Result<Optio
 pub fn upgrade_data(
     dim_folder: &Path,
     name: impl Into<String>,
+    type_name: &str,
     typ: impl FnOnce() -> RwLockReadGuard<'static, MapDataType<'static>>,
     to_version: u32,
     dry_run: bool,
-) {
+    quarantine: Option<&Quarantine>,
+) -> UpgradeOutcome {
     let name = name.into();
 
     let _span = info_span!("Upgrading data", message = name).entered();
 
+    let path = dim_folder.join("data").join(format!("{name}.dat"));
+
     let mut data = match read_data(dim_folder, name.clone()) {
         Ok(Some(data)) => data,
         Ok(None) => {
             error!("Error reading {name}.dat");
-            return;
+            quarantine_file(
+                quarantine,
+                &path,
+                type_name,
+                99,
+                FileFormat::Nbt,
+                &"failed to read",
+            );
+            return UpgradeOutcome::NotUpgraded;
         }
-        Err(err) if err.kind() == ErrorKind::NotFound => return,
+        Err(err) if err.kind() == ErrorKind::NotFound => return UpgradeOutcome::NotUpgraded,
         Err(err) => {
             error!("Error reading {name}.dat: {err}");
-            return;
+            quarantine_file(quarantine, &path, type_name, 99, FileFormat::Nbt, &err);
+            return UpgradeOutcome::NotUpgraded;
         }
     };
-    if !upgrade(typ, &mut data, || name.clone(), to_version, 99) {
-        return;
+    if is_up_to_date(&data, to_version) {
+        return UpgradeOutcome::UpToDate;
+    }
+    if let Err(failure) = try_upgrade(typ, &mut data, || name.clone(), to_version, 99) {
+        if failure.should_quarantine() {
+            quarantine_file(quarantine, &path, type_name, 99, FileFormat::Nbt, &failure);
+        }
+        return UpgradeOutcome::NotUpgraded;
     }
 
     if !dry_run {
-        let file = match File::create(dim_folder.join("data").join(format!("{name}.dat"))) {
+        let file = match File::create(&path) {
             Ok(file) => file,
             Err(err) => {
                 error!("Error opening {name}.dat for write: {err}");
-                return;
+                return UpgradeOutcome::NotUpgraded;
             }
         };
         if let Err(err) = to_binary(&data, GzEncoder::new(file, Compression::default()), "") {
             error!("Error writing to {name}.dat: {err}");
+            return UpgradeOutcome::NotUpgraded;
         }
     }
+
+    UpgradeOutcome::Upgraded
 }
 
-pub fn upgrade_map_data(world_folder: &Path, to_version: u32, dry_run: bool) {
+pub fn upgrade_map_data(
+    world_folder: &Path,
+    to_version: u32,
+    dry_run: bool,
+    quarantine: Option<&Quarantine>,
+) {
     let _span = info_span!("Upgrading map data").entered();
 
     let idcounts = match read_data(world_folder, "idcounts") {
@@ -99,13 +127,22 @@ pub fn upgrade_map_data(world_folder: &Path, to_version: u32, dry_run: bool) {
     let Some(map_count) = idcounts.get("map").and_then(|v| v.as_i32()) else {
         return;
     };
+    let mut num_up_to_date = 0;
     for map_id in 0..=map_count {
-        upgrade_data(
+        let outcome = upgrade_data(
             world_folder,
             format!("map_{map_id}"),
+            "saved_data_map_data",
             types::saved_data_map_data,
             to_version,
             dry_run,
+            quarantine,
         );
+        if outcome == UpgradeOutcome::UpToDate {
+            num_up_to_date += 1;
+        }
+    }
+    if num_up_to_date > 0 {
+        info!("Skipped {num_up_to_date} maps already at the target version");
     }
 }
@@ -1,16 +1,30 @@
-use std::io::ErrorKind;
 use crate::data::upgrade_data;
-use crate::region::{delete_legacy_dat_files, upgrade_chunks, upgrade_entities, upgrade_poi};
-use java_string::JavaStr;
-use std::path::Path;
-use tracing::{error, info_span};
+use crate::quarantine::Quarantine;
+use crate::region::{
+    chunks_task, delete_legacy_dat_files, entities_task, poi_task, upgrade_region_tasks,
+    ChunkUpgrader, RegionOptions, RegionTask, WorldBorder,
+};
+use java_string::{JavaStr, JavaString};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tracing::{error, info, info_span, warn, Span};
+use world_transmuter::json::parse_compound;
 use world_transmuter::types;
 use world_transmuter_engine::{JCompound, JValue};
 
 const FIRST_RAIDS_VERSION: u32 = 1912; // 18w47a
 const NETHER_RAIDS_RENAME: u32 = 2972; // 1.18.2-pre2
 
-fn get_custom_dimensions(level_dat: &JCompound) -> Vec<(&JavaStr, &JavaStr, &JavaStr)> {
+const VANILLA_DIMENSIONS: [&JavaStr; 3] = [
+    JavaStr::from_str("minecraft:overworld"),
+    JavaStr::from_str("minecraft:the_nether"),
+    JavaStr::from_str("minecraft:the_end"),
+];
+
+/// The (min_y, height) of the vanilla dimension types, before and after 21w37a.
+const VANILLA_HEIGHTS: [(i32, i32); 2] = [(0, 256), (-64, 384)];
+
+fn get_custom_dimensions(level_dat: &JCompound) -> Vec<&JavaStr> {
     let Some(JValue::Compound(world_gen_settings)) = level_dat.get("WorldGenSettings") else {
         return Vec::new();
     };
@@ -31,88 +45,336 @@ fn get_custom_dimensions(level_dat: &JCompound) -> Vec<(&JavaStr, &JavaStr, &Jav
                     | b"the_end"
             )
         })
-        .map(|dim| match dim.find(':') {
-            Some(colon_index) => (&dim[..], &dim[..colon_index], &dim[colon_index + 1..]),
-            None => (&dim[..], JavaStr::from_str("minecraft"), &dim[..]),
-        })
+        .map(|dim| &dim[..])
         .collect()
 }
 
-fn get_generator<'a>(
+fn get_dimension_folder(world: &Path, dim_id: &JavaStr) -> PathBuf {
+    let (dim_namespace, dim_path) = match dim_id.find(':') {
+        Some(colon_index) => (&dim_id[..colon_index], &dim_id[colon_index + 1..]),
+        None => (JavaStr::from_str("minecraft"), dim_id),
+    };
+    let mut dimension_dir = world
+        .join("dimensions")
+        .join(dim_namespace.as_str_lossy().as_ref());
+    for part in dim_path.split('/') {
+        dimension_dir.push(part.as_str_lossy().as_ref());
+    }
+    dimension_dir
+}
+
+/// Finds the dimensions under `dimensions/` which have region, entities or poi folders, whether
+/// or not level.dat knows about them.
+fn find_dimension_folders(world: &Path) -> Vec<JavaString> {
+    let mut dim_ids = Vec::new();
+    let namespaces = match std::fs::read_dir(world.join("dimensions")) {
+        Ok(namespaces) => namespaces,
+        Err(err) => {
+            if err.kind() != ErrorKind::NotFound {
+                error!("Failed to list dimensions folder: {err}");
+            }
+            return dim_ids;
+        }
+    };
+    for namespace in namespaces.flatten() {
+        let Some(namespace_name) = namespace.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        find_dimension_folders_in(&namespace.path(), &namespace_name, "", &mut dim_ids);
+    }
+    dim_ids.sort();
+    dim_ids
+}
+
+fn find_dimension_folders_in(
+    dir: &Path,
+    namespace: &str,
+    path: &str,
+    dim_ids: &mut Vec<JavaString>,
+) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            if err.kind() != ErrorKind::NotADirectory {
+                error!("Failed to list {}: {}", dir.to_string_lossy(), err);
+            }
+            return;
+        }
+    };
+    let mut is_dimension = false;
+    for entry in entries.flatten() {
+        if !entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            continue;
+        }
+        let Some(name) = entry.file_name().to_str().map(str::to_owned) else {
+            continue;
+        };
+        match name.as_str() {
+            "region" | "entities" | "poi" => is_dimension = true,
+            "data" => {}
+            _ => {
+                let sub_path = if path.is_empty() {
+                    name
+                } else {
+                    format!("{path}/{name}")
+                };
+                find_dimension_folders_in(&entry.path(), namespace, &sub_path, dim_ids);
+            }
+        }
+    }
+    if is_dimension && !path.is_empty() {
+        dim_ids.push(JavaString::from(format!("{namespace}:{path}")));
+    }
+}
+
+fn get_level_dat_dimension<'a>(
     level_dat: &'a JCompound,
-    dim_id: &(impl AsRef<JavaStr> + ?Sized),
-) -> &'a JavaStr {
+    dim_id: &JavaStr,
+) -> Option<&'a JCompound> {
     let Some(JValue::Compound(world_gen_settings)) = level_dat.get("WorldGenSettings") else {
-        return JavaStr::from_str("minecraft:noise");
+        return None;
     };
     let Some(JValue::Compound(dimensions)) = world_gen_settings.get("dimensions") else {
-        return JavaStr::from_str("minecraft:noise");
+        return None;
     };
-    let Some(JValue::Compound(dimension)) = dimensions.get(dim_id.as_ref()) else {
-        return JavaStr::from_str("minecraft:noise");
+    let short_id = dim_id.strip_prefix("minecraft:").unwrap_or(dim_id);
+    match dimensions.get(dim_id).or_else(|| dimensions.get(short_id)) {
+        Some(JValue::Compound(dimension)) => Some(dimension),
+        _ => None,
+    }
+}
+
+/// Reads a JSON file for the given registry entry out of the first folder datapack which has one.
+/// Zipped datapacks aren't searched.
+fn read_datapack_file(world: &Path, id: &JavaStr, registry: &str) -> Option<JCompound> {
+    let (namespace, path) = match id.find(':') {
+        Some(colon_index) => (&id[..colon_index], &id[colon_index + 1..]),
+        None => (JavaStr::from_str("minecraft"), id),
+    };
+    let datapacks = match std::fs::read_dir(world.join("datapacks")) {
+        Ok(datapacks) => datapacks,
+        Err(err) => {
+            if err.kind() != ErrorKind::NotFound {
+                warn!("Failed to list datapacks: {err}");
+            }
+            return None;
+        }
+    };
+    for datapack in datapacks.flatten() {
+        let file = datapack
+            .path()
+            .join("data")
+            .join(namespace.as_str_lossy().as_ref())
+            .join(registry)
+            .join(format!("{}.json", path.as_str_lossy()));
+        match std::fs::read_to_string(&file) {
+            Ok(json) => match parse_compound(JavaStr::from_str(&json), true) {
+                Ok(data) => return Some(data),
+                Err(_) => warn!("Failed to parse {}", file.to_string_lossy()),
+            },
+            Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {}
+            Err(err) => warn!("Failed to read {}: {}", file.to_string_lossy(), err),
+        }
+    }
+    None
+}
+
+/// Finds the definition of a dimension in level.dat, falling back to the datapack defining it.
+fn get_dimension(world: &Path, level_dat: &JCompound, dim_id: &JavaStr) -> Option<JCompound> {
+    match get_level_dat_dimension(level_dat, dim_id) {
+        Some(dimension) => Some(dimension.clone()),
+        None => read_datapack_file(world, dim_id, "dimension"),
+    }
+}
+
+fn get_generator(dim_id: &JavaStr, dimension: Option<&JCompound>) -> JavaString {
+    let generator = match dimension.and_then(|dimension| dimension.get("generator")) {
+        Some(JValue::Compound(generator)) => Some(generator),
+        _ => None,
+    };
+
+    match generator.and_then(|generator| generator.get("type")) {
+        Some(JValue::String(generator_type)) => generator_type.clone(),
+        _ => {
+            if !VANILLA_DIMENSIONS.contains(&dim_id) {
+                warn!("Couldn't find the generator of {dim_id}, assuming minecraft:noise");
+            }
+            JavaString::from("minecraft:noise")
+        }
+    }
+}
+
+/// Warns about dimensions whose dimension type, either inline in their definition or by ID from
+/// the datapacks, has a height other than the vanilla ones. The chunk converters only get the
+/// dimension and generator IDs, so they assume the height of the vanilla dimension types.
+fn check_dimension_height(world: &Path, dim_id: &JavaStr, dimension: Option<&JCompound>) {
+    let dimension_type = match dimension.and_then(|dimension| dimension.get("type")) {
+        Some(JValue::Compound(dimension_type)) => Some(dimension_type.clone()),
+        Some(JValue::String(type_id)) => {
+            let dimension_type = read_datapack_file(world, type_id, "dimension_type");
+            if dimension_type.is_none() && is_vanilla_dimension_type(type_id) {
+                return;
+            }
+            dimension_type
+        }
+        _ if VANILLA_DIMENSIONS.contains(&dim_id) => return,
+        _ => None,
     };
-    let Some(JValue::Compound(generator)) = dimension.get("generator") else {
-        return JavaStr::from_str("minecraft:noise");
+    let get = |key: &str| {
+        dimension_type
+            .as_ref()
+            .and_then(|dimension_type| dimension_type.get(key))
+            .and_then(|value| value.as_i32())
     };
-    let Some(JValue::String(gen_type)) = generator.get("type") else {
-        return JavaStr::from_str("minecraft:noise");
+
+    match (get("min_y"), get("height")) {
+        (Some(min_y), Some(height)) => {
+            if !VANILLA_HEIGHTS.contains(&(min_y, height)) {
+                warn!(
+                    "Dimension {dim_id} spans y={min_y} to y={}, its chunks will be upgraded as if it had a vanilla height",
+                    min_y + height - 1
+                );
+            }
+        }
+        _ => warn!(
+            "Couldn't resolve the height of the dimension type of {dim_id}, its chunks will be upgraded as if it had a vanilla height"
+        ),
+    }
+}
+
+fn is_vanilla_dimension_type(type_id: &JavaStr) -> bool {
+    matches!(
+        type_id
+            .strip_prefix("minecraft:")
+            .unwrap_or(type_id)
+            .as_bytes(),
+        b"overworld" | b"overworld_caves" | b"the_nether" | b"the_end"
+    )
+}
+
+/// Reads the world border out of level.dat. The border of the nether is scaled down to match its
+/// coordinates.
+fn get_world_border(level_dat: &JCompound, dim_id: &JavaStr) -> Option<WorldBorder> {
+    let get = |key: &str| level_dat.get(key).and_then(|value| value.as_f64());
+    let size = get("BorderSize")?;
+    let (center_x, center_z) = (get("BorderCenterX")?, get("BorderCenterZ")?);
+    let scale = if dim_id == "minecraft:the_nether" {
+        8.0
+    } else {
+        1.0
     };
-    &gen_type[..]
+    Some(WorldBorder {
+        min_x: (center_x - size / 2.0) / scale,
+        min_z: (center_z - size / 2.0) / scale,
+        max_x: (center_x + size / 2.0) / scale,
+        max_z: (center_z + size / 2.0) / scale,
+    })
+}
+
+struct Dimension {
+    id: JavaString,
+    folder: PathBuf,
+    generator_type: JavaString,
+    world_border: Option<WorldBorder>,
+    span: Span,
 }
 
-pub fn upgrade_dimensions(world: &Path, to_version: u32, dry_run: bool, level_dat: &JCompound) {
+pub fn upgrade_dimensions(
+    world: &Path,
+    to_version: u32,
+    dry_run: bool,
+    level_dat: &JCompound,
+    options: &RegionOptions,
+) {
     let _span = info_span!("Upgrading dimensions").entered();
 
-    let span = info_span!("Upgrading dimension", message = "the overworld").entered();
-    upgrade_dimension(
-        JavaStr::from_str("minecraft:overworld"),
-        get_generator(level_dat, "minecraft:overworld"),
-        world,
-        world,
-        to_version,
-        dry_run,
-    );
-    span.exit();
-
-    let span = info_span!("Upgrading dimension", message = "the nether").entered();
-    upgrade_dimension(
-        JavaStr::from_str("minecraft:the_nether"),
-        get_generator(level_dat, "minecraft:the_nether"),
-        world,
-        &world.join("DIM-1"),
-        to_version,
-        dry_run,
-    );
-    span.exit();
-
-    let span = info_span!("Upgrading dimension", message = "the end").entered();
-    upgrade_dimension(
-        JavaStr::from_str("minecraft:the_end"),
-        get_generator(level_dat, "minecraft:the_end"),
-        world,
-        &world.join("DIM1"),
-        to_version,
-        dry_run,
-    );
-    span.exit();
+    let mut dimensions = vec![
+        (
+            JavaString::from("minecraft:overworld"),
+            world.to_path_buf(),
+            info_span!("Upgrading dimension", message = "the overworld"),
+        ),
+        (
+            JavaString::from("minecraft:the_nether"),
+            world.join("DIM-1"),
+            info_span!("Upgrading dimension", message = "the nether"),
+        ),
+        (
+            JavaString::from("minecraft:the_end"),
+            world.join("DIM1"),
+            info_span!("Upgrading dimension", message = "the end"),
+        ),
+    ];
 
-    for (dim_id, dim_namespace, dim_path) in get_custom_dimensions(level_dat) {
-        let _span = info_span!(
+    let mut custom_dimensions: Vec<_> = get_custom_dimensions(level_dat)
+        .into_iter()
+        .map(|dim_id| {
+            if dim_id.contains(':') {
+                dim_id.to_owned()
+            } else {
+                JavaString::from(format!("minecraft:{dim_id}"))
+            }
+        })
+        .collect();
+    let mut num_unlisted = 0;
+    for dim_id in find_dimension_folders(world) {
+        if !custom_dimensions.contains(&dim_id) {
+            warn!("Found dimension {dim_id} on disk which isn't listed in level.dat, upgrading it too");
+            num_unlisted += 1;
+            custom_dimensions.push(dim_id);
+        }
+    }
+    if num_unlisted != 0 {
+        info!("Found {num_unlisted} dimensions on disk which aren't listed in level.dat");
+    }
+
+    for dim_id in custom_dimensions {
+        let span = info_span!(
             "Upgrading dimension",
             message = dim_id.as_str_lossy().as_ref()
-        )
-        .entered();
-        let mut dimension_dir = world.join(dim_namespace.as_str_lossy().as_ref());
-        for part in dim_path.split('/') {
-            dimension_dir.push(part.as_str_lossy().as_ref());
-        }
-        upgrade_dimension(
-            dim_id,
-            get_generator(level_dat, dim_id),
-            world,
-            &dimension_dir,
+        );
+        dimensions.push((dim_id.clone(), get_dimension_folder(world, &dim_id), span));
+    }
+
+    let dimensions: Vec<_> = dimensions
+        .into_iter()
+        .map(|(dim_id, folder, span)| {
+            let generator_type = span.in_scope(|| {
+                let dimension = get_dimension(world, level_dat, &dim_id);
+                check_dimension_height(world, &dim_id, dimension.as_ref());
+                get_generator(&dim_id, dimension.as_ref())
+            });
+            let world_border = if options.prune_outside_border {
+                get_world_border(level_dat, &dim_id)
+            } else {
+                None
+            };
+            Dimension {
+                id: dim_id,
+                folder,
+                generator_type,
+                world_border,
+                span,
+            }
+        })
+        .collect();
+
+    // the region folders of all dimensions share one work queue
+    let mut tasks = Vec::new();
+    for dimension in &dimensions {
+        let _span = dimension.span.enter();
+        add_region_tasks(&mut tasks, dimension, world, to_version, dry_run, options);
+    }
+    upgrade_region_tasks(&tasks, dry_run, options);
+
+    for dimension in &dimensions {
+        let _span = dimension.span.enter();
+        upgrade_raids(
+            &dimension.id,
+            &dimension.folder,
             to_version,
             dry_run,
+            options.quarantine.as_ref(),
         );
     }
 
@@ -121,7 +383,13 @@ pub fn upgrade_dimensions(world: &Path, to_version: u32, dry_run: bool, level_da
     }
 }
 
-fn upgrade_raids(dim_id: &JavaStr, dim_folder: &Path, to_version: u32, dry_run: bool) {
+fn upgrade_raids(
+    dim_id: &JavaStr,
+    dim_folder: &Path,
+    to_version: u32,
+    dry_run: bool,
+    quarantine: Option<&Quarantine>,
+) {
     if to_version < FIRST_RAIDS_VERSION {
         return;
     }
@@ -136,9 +404,11 @@ fn upgrade_raids(dim_id: &JavaStr, dim_folder: &Path, to_version: u32, dry_run:
                 upgrade_data(
                     dim_folder,
                     "raids_nether",
+                    "saved_data_raids",
                     types::saved_data_raids,
                     to_version,
                     dry_run,
+                    quarantine,
                 );
             } else if let Err(err) = std::fs::rename(raids_nether_file, raids_file) {
                 if err.kind() != ErrorKind::NotFound {
@@ -159,33 +429,47 @@ fn upgrade_raids(dim_id: &JavaStr, dim_folder: &Path, to_version: u32, dry_run:
     upgrade_data(
         dim_folder,
         raids_file,
+        "saved_data_raids",
         types::saved_data_raids,
         to_version,
         dry_run,
+        quarantine,
     );
 }
 
-fn upgrade_dimension(
-    dim_id: &JavaStr,
-    generator_type: &JavaStr,
-    world_folder: &Path,
-    dimension: &Path,
+fn add_region_tasks<'a>(
+    tasks: &mut Vec<RegionTask<'a>>,
+    dimension: &'a Dimension,
+    world_folder: &'a Path,
     to_version: u32,
     dry_run: bool,
+    options: &'a RegionOptions,
 ) {
-    // Upgrade entity chunks before regions, as regions may write to entities
-    upgrade_entities(dimension, to_version, dry_run);
+    let entities_task =
+        entities_task(&dimension.id, &dimension.folder, to_version, options).map(|task| {
+            tasks.push(task);
+            tasks.len() - 1
+        });
 
-    upgrade_chunks(
-        dim_id,
-        generator_type,
+    let mut chunk_upgrader = ChunkUpgrader::new(
+        &dimension.id,
+        &dimension.generator_type,
         world_folder,
-        dimension,
         to_version,
         dry_run,
     );
+    chunk_upgrader.set_retrogen_modes(options.retrogen.for_dimension(&dimension.id));
+    chunk_upgrader.set_unknown_ids(options.unknown_ids.as_ref());
+    chunk_upgrader.set_uuid_index(options.uuid_index.as_ref());
+    let mut chunks_task = chunks_task(chunk_upgrader, &dimension.folder);
+    // Upgrade entity chunks before regions, as regions may write to entities
+    if let Some(entities_task) = entities_task {
+        chunks_task.run_after(entities_task);
+    }
+    if let Some(world_border) = dimension.world_border {
+        chunks_task.prune_outside(world_border);
+    }
+    tasks.push(chunks_task);
 
-    upgrade_poi(dimension, to_version, dry_run);
-
-    upgrade_raids(dim_id, dimension, to_version, dry_run);
+    tasks.extend(poi_task(&dimension.folder, to_version));
 }
@@ -0,0 +1,124 @@
+mod snbt;
+
+use flate2::read::{GzDecoder, ZlibDecoder};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use java_string::JavaStr;
+use std::io;
+use std::io::Read;
+use valence_nbt::{from_binary, to_binary};
+use world_transmuter::json::{parse_compound, stringify_compound};
+use world_transmuter_engine::JCompound;
+
+/// The ways a single compound can be stored in a file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    GzipNbt,
+    ZlibNbt,
+    RawNbt,
+    Snbt,
+    Json,
+}
+
+impl Format {
+    pub const NAMES: [&'static str; 5] = ["gzip", "zlib", "raw", "snbt", "json"];
+
+    /// The formats which fit on a single line, for streams of records.
+    pub const TEXT_NAMES: [&'static str; 2] = ["snbt", "json"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gzip" => Some(Format::GzipNbt),
+            "zlib" => Some(Format::ZlibNbt),
+            "raw" => Some(Format::RawNbt),
+            "snbt" => Some(Format::Snbt),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format of a file from its contents.
+    pub fn detect(contents: &[u8]) -> Option<Self> {
+        match contents {
+            [0x1f, 0x8b, ..] => Some(Format::GzipNbt),
+            [cmf @ 0x78, flg, ..] if (*cmf as u16 * 256 + *flg as u16) % 31 == 0 => {
+                Some(Format::ZlibNbt)
+            }
+            // a compound tag
+            [0x0a, ..] => Some(Format::RawNbt),
+            _ => {
+                let text = std::str::from_utf8(contents).ok()?;
+                if !text.trim_start().starts_with('{') {
+                    None
+                } else if parse_compound(JavaStr::from_str(text), false).is_ok() {
+                    Some(Format::Json)
+                } else {
+                    Some(Format::Snbt)
+                }
+            }
+        }
+    }
+}
+
+/// Reads a compound in whichever format it's in, returning the format too so that it can be
+/// written back the same way.
+pub fn read_any(contents: &[u8]) -> Option<(JCompound, Format)> {
+    let format = Format::detect(contents)?;
+    read(contents, format).map(|compound| (compound, format))
+}
+
+pub fn read(contents: &[u8], format: Format) -> Option<JCompound> {
+    let mut decompressed = Vec::new();
+    let nbt = match format {
+        Format::GzipNbt => {
+            GzDecoder::new(contents)
+                .read_to_end(&mut decompressed)
+                .ok()?;
+            &decompressed[..]
+        }
+        Format::ZlibNbt => {
+            ZlibDecoder::new(contents)
+                .read_to_end(&mut decompressed)
+                .ok()?;
+            &decompressed[..]
+        }
+        Format::RawNbt => contents,
+        Format::Snbt => return snbt::parse_compound(std::str::from_utf8(contents).ok()?),
+        Format::Json => {
+            let json = std::str::from_utf8(contents).ok()?;
+            return parse_compound(JavaStr::from_str(json), true).ok();
+        }
+    };
+    from_binary(&mut &*nbt).ok().map(|(compound, _)| compound)
+}
+
+pub fn write(data: JCompound, format: Format) -> io::Result<Vec<u8>> {
+    match format {
+        Format::GzipNbt => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            to_binary(&data, &mut encoder, "").map_err(io::Error::other)?;
+            encoder.finish()
+        }
+        Format::ZlibNbt => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            to_binary(&data, &mut encoder, "").map_err(io::Error::other)?;
+            encoder.finish()
+        }
+        Format::RawNbt => {
+            let mut result = Vec::new();
+            to_binary(&data, &mut result, "").map_err(io::Error::other)?;
+            Ok(result)
+        }
+        Format::Snbt => Ok(snbt::stringify_compound(&data).into_bytes()),
+        Format::Json => Ok(stringify_compound(data, true, true).into_bytes()),
+    }
+}
+
+/// Writes a compound as a single line of text, or `None` if the format is binary.
+pub fn write_line(data: JCompound, format: Format) -> Option<String> {
+    match format {
+        Format::Snbt => Some(snbt::stringify_compound(&data)),
+        Format::Json => Some(stringify_compound(data, true, false)),
+        Format::GzipNbt | Format::ZlibNbt | Format::RawNbt => None,
+    }
+}
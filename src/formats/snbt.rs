@@ -0,0 +1,371 @@
+use java_string::JavaString;
+use std::fmt::Write;
+use world_transmuter_engine::{JCompound, JList, JValue};
+
+const MAX_DEPTH: usize = 512;
+
+/// Parses stringified NBT, as printed by `/data get`.
+pub fn parse_compound(snbt: &str) -> Option<JCompound> {
+    let mut reader = SnbtReader { rest: snbt };
+    reader.skip_whitespace();
+    if !reader.rest.starts_with('{') {
+        return None;
+    }
+    let JValue::Compound(compound) = reader.value(0)? else {
+        unreachable!()
+    };
+    reader.skip_whitespace();
+    reader.rest.is_empty().then_some(compound)
+}
+
+struct SnbtReader<'a> {
+    rest: &'a str,
+}
+
+fn is_unquoted_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '+')
+}
+
+/// Builds a list out of values which all have to be of the same type as the first one.
+macro_rules! into_list {
+    ($first:ident, $rest:ident, $($variant:ident),*) => {
+        match $first {
+            $(
+                JValue::$variant(first) => JList::$variant(
+                    std::iter::once(Some(first))
+                        .chain($rest.map(|value| match value {
+                            JValue::$variant(value) => Some(value),
+                            _ => None,
+                        }))
+                        .collect::<Option<_>>()?,
+                ),
+            )*
+        }
+    };
+}
+
+impl<'a> SnbtReader<'a> {
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start();
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        self.skip_whitespace();
+        match self.rest.strip_prefix(c) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Option<()> {
+        self.eat(c).then_some(())
+    }
+
+    fn value(&mut self, depth: usize) -> Option<JValue> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        self.skip_whitespace();
+        if self.eat('{') {
+            return self.compound(depth).map(JValue::Compound);
+        }
+        if self.eat('[') {
+            return self.list_or_array(depth);
+        }
+        if self.rest.starts_with(['"', '\'']) {
+            return self.quoted_string().map(JValue::String);
+        }
+        let token = self.unquoted_token()?;
+        Some(parse_unquoted(token))
+    }
+
+    fn compound(&mut self, depth: usize) -> Option<JCompound> {
+        let mut compound = JCompound::new();
+        if self.eat('}') {
+            return Some(compound);
+        }
+        loop {
+            self.skip_whitespace();
+            let key = if self.rest.starts_with(['"', '\'']) {
+                self.quoted_string()?
+            } else {
+                JavaString::from(self.unquoted_token()?)
+            };
+            self.expect(':')?;
+            let value = self.value(depth + 1)?;
+            compound.insert(key, value);
+            if self.eat('}') {
+                return Some(compound);
+            }
+            self.expect(',')?;
+        }
+    }
+
+    fn list_or_array(&mut self, depth: usize) -> Option<JValue> {
+        for (prefix, array_type) in [("B;", 'B'), ("I;", 'I'), ("L;", 'L')] {
+            if let Some(rest) = self.rest.strip_prefix(prefix) {
+                self.rest = rest;
+                return self.array(array_type);
+            }
+        }
+
+        let mut values = Vec::new();
+        if !self.eat(']') {
+            loop {
+                values.push(self.value(depth + 1)?);
+                if self.eat(']') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+
+        let mut rest = values.into_iter();
+        let Some(first) = rest.next() else {
+            return Some(JValue::List(JList::End));
+        };
+        let list = into_list!(
+            first, rest, Byte, Short, Int, Long, Float, Double, ByteArray, String, List, Compound,
+            IntArray, LongArray
+        );
+        Some(JValue::List(list))
+    }
+
+    fn array(&mut self, array_type: char) -> Option<JValue> {
+        let mut values = Vec::new();
+        if !self.eat(']') {
+            loop {
+                self.skip_whitespace();
+                values.push(parse_unquoted(self.unquoted_token()?));
+                if self.eat(']') {
+                    break;
+                }
+                self.expect(',')?;
+            }
+        }
+        let values = values.into_iter();
+        Some(match array_type {
+            'B' => JValue::ByteArray(
+                values
+                    .map(|value| match value {
+                        JValue::Byte(value) => Some(value),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?,
+            ),
+            'I' => JValue::IntArray(
+                values
+                    .map(|value| match value {
+                        JValue::Int(value) => Some(value),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?,
+            ),
+            _ => JValue::LongArray(
+                values
+                    .map(|value| match value {
+                        JValue::Long(value) => Some(value),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?,
+            ),
+        })
+    }
+
+    fn quoted_string(&mut self) -> Option<JavaString> {
+        let mut chars = self.rest.char_indices();
+        let (_, quote) = chars.next()?;
+        let mut result = String::new();
+        let mut escaped = false;
+        for (index, c) in chars {
+            if escaped {
+                result.push(match c {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    _ => c,
+                });
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == quote {
+                self.rest = &self.rest[index + c.len_utf8()..];
+                return Some(JavaString::from(result));
+            } else {
+                result.push(c);
+            }
+        }
+        None
+    }
+
+    fn unquoted_token(&mut self) -> Option<&'a str> {
+        let end = self
+            .rest
+            .find(|c| !is_unquoted_char(c))
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            return None;
+        }
+        let (token, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Some(token)
+    }
+}
+
+/// Works out the type of an unquoted value from its suffix, falling back to a string.
+fn parse_unquoted(token: &str) -> JValue {
+    match token {
+        "true" => return JValue::Byte(1),
+        "false" => return JValue::Byte(0),
+        _ => {}
+    }
+
+    let (body, suffix) = match token.char_indices().last() {
+        Some((index, c)) if c.is_ascii_alphabetic() => (&token[..index], Some(c)),
+        _ => (token, None),
+    };
+    let value = match suffix.map(|c| c.to_ascii_lowercase()) {
+        Some('b') => body.parse().ok().map(JValue::Byte),
+        Some('s') => body.parse().ok().map(JValue::Short),
+        Some('l') => body.parse().ok().map(JValue::Long),
+        Some('f') => body.parse().ok().map(JValue::Float),
+        Some('d') => body.parse().ok().map(JValue::Double),
+        Some(_) => None,
+        None => body.parse().ok().map(JValue::Int).or_else(|| {
+            body.contains(['.', 'e', 'E'])
+                .then(|| body.parse().ok().map(JValue::Double))
+                .flatten()
+        }),
+    };
+    value.unwrap_or_else(|| JValue::String(JavaString::from(token)))
+}
+
+/// Writes a compound as stringified NBT on a single line, as `/data get` does.
+pub fn stringify_compound(compound: &JCompound) -> String {
+    let mut result = String::new();
+    write_compound(&mut result, compound);
+    result
+}
+
+fn write_compound(result: &mut String, compound: &JCompound) {
+    result.push('{');
+    for (index, (key, value)) in compound.iter().enumerate() {
+        if index != 0 {
+            result.push(',');
+        }
+        let key = key.as_str_lossy();
+        if !key.is_empty() && key.chars().all(is_unquoted_char) {
+            result.push_str(&key);
+        } else {
+            write_string(result, &key);
+        }
+        result.push(':');
+        write_value(result, value);
+    }
+    result.push('}');
+}
+
+fn write_string(result: &mut String, string: &str) {
+    result.push('"');
+    for c in string.chars() {
+        match c {
+            '"' | '\\' => {
+                result.push('\\');
+                result.push(c);
+            }
+            // escaped so that records stay on one line
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            _ => result.push(c),
+        }
+    }
+    result.push('"');
+}
+
+fn write_values<T>(
+    result: &mut String,
+    prefix: &str,
+    values: &[T],
+    mut write: impl FnMut(&mut String, &T),
+) {
+    result.push('[');
+    result.push_str(prefix);
+    for (index, value) in values.iter().enumerate() {
+        if index != 0 {
+            result.push(',');
+        }
+        write(result, value);
+    }
+    result.push(']');
+}
+
+fn write_value(result: &mut String, value: &JValue) {
+    match value {
+        JValue::Byte(value) => write_byte(result, value),
+        JValue::Short(value) => write_short(result, value),
+        JValue::Int(value) => write_int(result, value),
+        JValue::Long(value) => write_long(result, value),
+        JValue::Float(value) => write_float(result, value),
+        JValue::Double(value) => write_double(result, value),
+        JValue::ByteArray(values) => write_values(result, "B;", values, write_byte),
+        JValue::String(value) => write_string(result, &value.as_str_lossy()),
+        JValue::List(list) => write_list(result, list),
+        JValue::Compound(compound) => write_compound(result, compound),
+        JValue::IntArray(values) => write_values(result, "I;", values, write_int),
+        JValue::LongArray(values) => write_values(result, "L;", values, write_long),
+    }
+}
+
+fn write_list(result: &mut String, list: &JList) {
+    match list {
+        JList::End => result.push_str("[]"),
+        JList::Byte(values) => write_values(result, "", values, write_byte),
+        JList::Short(values) => write_values(result, "", values, write_short),
+        JList::Int(values) => write_values(result, "", values, write_int),
+        JList::Long(values) => write_values(result, "", values, write_long),
+        JList::Float(values) => write_values(result, "", values, write_float),
+        JList::Double(values) => write_values(result, "", values, write_double),
+        JList::ByteArray(values) => write_values(result, "", values, |result, values| {
+            write_values(result, "B;", values, write_byte)
+        }),
+        JList::String(values) => write_values(result, "", values, |result, value| {
+            write_string(result, &value.as_str_lossy())
+        }),
+        JList::List(values) => write_values(result, "", values, write_list),
+        JList::Compound(values) => write_values(result, "", values, write_compound),
+        JList::IntArray(values) => write_values(result, "", values, |result, values| {
+            write_values(result, "I;", values, write_int)
+        }),
+        JList::LongArray(values) => write_values(result, "", values, |result, values| {
+            write_values(result, "L;", values, write_long)
+        }),
+    }
+}
+
+fn write_byte(result: &mut String, value: &i8) {
+    write!(result, "{value}b").unwrap();
+}
+
+fn write_short(result: &mut String, value: &i16) {
+    write!(result, "{value}s").unwrap();
+}
+
+fn write_int(result: &mut String, value: &i32) {
+    write!(result, "{value}").unwrap();
+}
+
+fn write_long(result: &mut String, value: &i64) {
+    write!(result, "{value}L").unwrap();
+}
+
+fn write_float(result: &mut String, value: &f32) {
+    write!(result, "{value}f").unwrap();
+}
+
+fn write_double(result: &mut String, value: &f64) {
+    write!(result, "{value}d").unwrap();
+}
@@ -1,15 +1,17 @@
-use crate::{upgrade, ADVANCEMENTS_AND_STATS_VERSION};
+use crate::quarantine::{quarantine_file, FileFormat, Quarantine};
+use crate::{is_up_to_date, try_upgrade, UpgradeOutcome, ADVANCEMENTS_AND_STATS_VERSION};
 use flate2::read::GzDecoder;
 use flate2::write::GzEncoder;
 use flate2::Compression;
-use java_string::JavaStr;
+use java_string::{JavaStr, JavaString};
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use std::fs::File;
-use std::io::{ErrorKind, Read, Write};
+use std::io::{ErrorKind, Read, Seek};
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::RwLockReadGuard;
-use tracing::{error, info_span, warn, Span};
-use valence_nbt::{from_binary, to_binary};
+use tracing::{error, info, info_span, warn, Span};
+use valence_nbt::{from_binary, jcompound, to_binary};
 use world_transmuter::json::{parse_compound, stringify_compound};
 use world_transmuter::types;
 use world_transmuter::version_names::{get_version_by_id, get_versions};
@@ -25,72 +27,238 @@ const OLD_SETTINGS_KEYS: [&str; 7] = [
     "BonusChest",
 ];
 
-pub fn upgrade_level_dat(world: &Path, to_version: u32, dry_run: bool) -> Option<JCompound> {
+const FIRST_WORLD_GEN_SETTINGS_VERSION: u32 = 2554; // 20w21a
+
+/// How to treat the `level.dat_old` and `playerdata/<uuid>.dat_old` backups the game keeps.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OldFilesMode {
+    /// Leave the backups at their old version.
+    Ignore,
+    /// Upgrade the backups alongside their primary files.
+    Upgrade,
+    /// Overwrite the backups with a copy of the newly written primary files.
+    Refresh,
+}
+
+/// The settings used to generate a minimal level.dat when neither level.dat nor level.dat_old can be read.
+pub struct LevelDatReconstruction {
+    pub seed: i64,
+    pub version: u32,
+}
+
+pub fn upgrade_level_dat(
+    world: &Path,
+    to_version: u32,
+    dry_run: bool,
+    old_files: OldFilesMode,
+    reconstruction: Option<&LevelDatReconstruction>,
+) -> Option<JCompound> {
     let _span = info_span!("Upgrading level.dat").entered();
-    fn update_data(data: &mut JCompound, from_version: u32, to_version: u32) {
-        data.remove("Player"); // TODO: what is this?
-
-        types::level().convert(data, from_version.into(), to_version.into());
-
-        data.insert("DataVersion", to_version as i32);
-
-        if to_version >= 2554 {
-            // 20w21a
-            let old_settings: Vec<_> = OLD_SETTINGS_KEYS
-                .iter()
-                .copied()
-                .filter_map(|old_settings_key| {
-                    data.remove(old_settings_key)
-                        .map(|value| (old_settings_key, value))
-                })
-                .collect();
-            if !matches!(data.get("WorldGenSettings"), Some(JValue::Compound(_))) {
-                data.insert("WorldGenSettings", JCompound::new());
+    let path = world.join("level.dat");
+    let level_dat = match read_level_dat(&path) {
+        Some(level_dat) => level_dat,
+        None => {
+            let level_dat = recover_level_dat(world, reconstruction)?;
+            if !dry_run && !write_recovered_level_dat(world, &level_dat) {
+                return None;
             }
-            let Some(JValue::Compound(world_gen_settings)) = data.get_mut("WorldGenSettings")
-            else {
-                unreachable!();
-            };
-            for (key, value) in old_settings {
-                world_gen_settings.insert(key, value);
+            level_dat
+        }
+    };
+    let data = upgrade_level_dat_compound(level_dat, &path, to_version, dry_run)?;
+
+    match old_files {
+        OldFilesMode::Ignore => {}
+        OldFilesMode::Upgrade => {
+            let old_path = world.join("level.dat_old");
+            if old_path.exists() {
+                let _span = info_span!("Upgrading level.dat_old").entered();
+                if let Some(old_level_dat) = read_level_dat(&old_path) {
+                    upgrade_level_dat_compound(old_level_dat, &old_path, to_version, dry_run);
+                }
+            }
+        }
+        OldFilesMode::Refresh => {
+            if !dry_run {
+                refresh_old_file(&path, &world.join("level.dat_old"));
             }
-            types::world_gen_settings().convert(
-                world_gen_settings,
-                from_version.into(),
-                to_version.into(),
-            );
         }
     }
 
-    let path = world.join("level.dat");
-    let Ok(mut file) = File::options().read(true).write(!dry_run).open(&path) else {
-        error!("Failed to open {}", path.to_string_lossy());
+    Some(data)
+}
+
+/// Upgrades the contents of the `Data` tag of a level.dat, moving the world generation settings of
+/// worlds from before 20w21a into `WorldGenSettings`.
+pub fn update_level_data(data: &mut JCompound, from_version: u32, to_version: u32) {
+    data.remove("Player"); // TODO: what is this?
+
+    types::level().convert(data, from_version.into(), to_version.into());
+
+    data.insert("DataVersion", to_version as i32);
+
+    if to_version >= FIRST_WORLD_GEN_SETTINGS_VERSION {
+        let old_settings: Vec<_> = OLD_SETTINGS_KEYS
+            .iter()
+            .copied()
+            .filter_map(|old_settings_key| {
+                data.remove(old_settings_key)
+                    .map(|value| (old_settings_key, value))
+            })
+            .collect();
+        if !matches!(data.get("WorldGenSettings"), Some(JValue::Compound(_))) {
+            data.insert("WorldGenSettings", JCompound::new());
+        }
+        let Some(JValue::Compound(world_gen_settings)) = data.get_mut("WorldGenSettings") else {
+            unreachable!();
+        };
+        for (key, value) in old_settings {
+            world_gen_settings.insert(key, value);
+        }
+        types::world_gen_settings().convert(
+            world_gen_settings,
+            from_version.into(),
+            to_version.into(),
+        );
+    }
+}
+
+/// Reads a level.dat file, returning `None` if it is missing, corrupt or has no `Data` tag.
+fn read_level_dat(path: &Path) -> Option<JCompound> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            error!("Failed to open {}: {}", path.to_string_lossy(), err);
+            return None;
+        }
+    };
+
+    let Some(level_dat) = read_compound(file) else {
+        error!("Failed to read {file_name}");
         return None;
     };
 
-    let Some(mut level_dat) = read_compound(&mut file) else {
-        error!("Failed to read level.dat");
+    if !matches!(level_dat.get("Data"), Some(JValue::Compound(_))) {
+        error!("Missing Data tag in {file_name}");
         return None;
+    }
+
+    Some(level_dat)
+}
+
+/// Writes the recovered level.dat as it is, moving the corrupt one to level.dat_corrupt, so that the
+/// world is left with a readable level.dat even if it can't be upgraded.
+fn write_recovered_level_dat(world: &Path, level_dat: &JCompound) -> bool {
+    let path = world.join("level.dat");
+    let tmp_path = world.join("level.dat.tmp");
+    let written = match File::create(&tmp_path) {
+        Ok(mut file) => write_compound(&mut file, level_dat),
+        Err(_) => false,
     };
+    if !written {
+        error!("Failed to write recovered level.dat");
+        let _ = std::fs::remove_file(&tmp_path);
+        return false;
+    }
 
-    let Some(JValue::Compound(data)) = level_dat.get_mut("Data") else {
-        error!("Missing Data tag in level.dat");
+    if let Err(err) = std::fs::rename(&path, world.join("level.dat_corrupt")) {
+        if err.kind() != ErrorKind::NotFound {
+            error!("Failed to move corrupt level.dat to level.dat_corrupt: {err}");
+            let _ = std::fs::remove_file(&tmp_path);
+            return false;
+        }
+    }
+    if let Err(err) = std::fs::rename(&tmp_path, &path) {
+        error!("Failed to move recovered level.dat into place: {err}");
+        return false;
+    }
+    true
+}
+
+/// Finds a replacement for an unreadable level.dat, first from level.dat_old and then by
+/// reconstructing one if the user asked for it.
+fn recover_level_dat(
+    world: &Path,
+    reconstruction: Option<&LevelDatReconstruction>,
+) -> Option<JCompound> {
+    warn!("level.dat could not be read, trying level.dat_old");
+    if let Some(level_dat) = read_level_dat(&world.join("level.dat_old")) {
+        warn!(
+            "Recovered level.dat from level.dat_old, changes made since the last save may be lost"
+        );
+        return Some(level_dat);
+    }
+
+    let Some(reconstruction) = reconstruction else {
+        error!("Failed to recover level.dat. Use --reconstruct-level-dat <seed> to generate a minimal one");
         return None;
     };
 
+    Some(reconstruct_level_dat(world, reconstruction))
+}
+
+fn reconstruct_level_dat(world: &Path, reconstruction: &LevelDatReconstruction) -> JCompound {
+    warn!(
+        "Reconstructing level.dat with seed {}. The world spawn, game rules, world border, \
+        single player inventory, data packs and custom dimension settings have been reset to defaults",
+        reconstruction.seed
+    );
+
+    let level_name = world
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "world".to_owned());
+
+    // The settings are written in the pre-20w21a format so that the converters build the
+    // WorldGenSettings from them, the same as they would for a world of that age.
+    let data_version = get_versions()
+        .filter(|version| version.data_version < FIRST_WORLD_GEN_SETTINGS_VERSION)
+        .next_back()
+        .map_or(reconstruction.version, |version| {
+            version.data_version.min(reconstruction.version)
+        });
+
+    jcompound! {
+        "Data" => jcompound! {
+            "DataVersion" => data_version as i32,
+            "version" => 19133,
+            "initialized" => true,
+            "LevelName" => JavaString::from(level_name),
+            "RandomSeed" => reconstruction.seed,
+            "generatorName" => JavaStr::from_str("default"),
+            "MapFeatures" => true,
+            "GameType" => 0,
+        },
+    }
+}
+
+fn upgrade_level_dat_compound(
+    mut level_dat: JCompound,
+    path: &Path,
+    to_version: u32,
+    dry_run: bool,
+) -> Option<JCompound> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+
+    let Some(JValue::Compound(data)) = level_dat.get_mut("Data") else {
+        unreachable!()
+    };
+
     let latest_version = get_versions().next_back().unwrap().data_version;
     let data_version = data
         .remove("DataVersion")
         .and_then(|v| v.as_i32())
         .unwrap_or(99) as u32;
     let Some(data_version) = get_version_by_id(data_version) else {
-        warn!("level.dat had unrecognized data version {data_version}");
+        warn!("{file_name} had unrecognized data version {data_version}");
         return None;
     };
     if data_version.data_version > to_version {
-        warn!("Cannot downgrade level.dat from {}", data_version.name);
+        warn!("Cannot downgrade {file_name} from {}", data_version.name);
 
-        update_data(data, data_version.data_version, latest_version);
+        update_level_data(data, data_version.data_version, latest_version);
 
         let Some(JValue::Compound(data)) = level_dat.remove("Data") else {
             unreachable!()
@@ -98,35 +266,79 @@ pub fn upgrade_level_dat(world: &Path, to_version: u32, dry_run: bool) -> Option
         return Some(data);
     }
 
-    update_data(data, data_version.data_version, to_version);
+    update_level_data(data, data_version.data_version, to_version);
 
-    if !dry_run && !write_compound(file, &level_dat) {
-        error!("Failed to write back to level.dat");
-        return None;
+    if !dry_run {
+        let written = match File::create(path) {
+            Ok(mut file) => write_compound(&mut file, &level_dat),
+            Err(_) => false,
+        };
+        if !written {
+            error!("Failed to write back to {file_name}");
+            return None;
+        }
     }
 
     let Some(JValue::Compound(mut data)) = level_dat.remove("Data") else {
         unreachable!()
     };
 
-    update_data(&mut data, to_version, latest_version);
+    update_level_data(&mut data, to_version, latest_version);
 
     Some(data)
 }
 
-pub fn upgrade_playerdata(world: &Path, to_version: u32, dry_run: bool) {
-    upgrade_dat_dir(world, to_version, dry_run, "playerdata", types::player);
+/// Replaces the `_old` backup of a file with a copy of the freshly written file, if the backup exists.
+fn refresh_old_file(path: &Path, old_path: &Path) {
+    match old_path.try_exists() {
+        Ok(true) => {
+            if let Err(err) = std::fs::copy(path, old_path) {
+                error!("Failed to refresh {}: {}", old_path.to_string_lossy(), err);
+            }
+        }
+        Ok(false) => {}
+        Err(err) => {
+            error!(
+                "Failed to check if {} exists: {}",
+                old_path.to_string_lossy(),
+                err
+            );
+        }
+    }
+}
+
+pub fn upgrade_playerdata(
+    world: &Path,
+    to_version: u32,
+    dry_run: bool,
+    old_files: OldFilesMode,
+    quarantine: Option<&Quarantine>,
+) {
+    upgrade_dat_dir(
+        world,
+        to_version,
+        dry_run,
+        old_files,
+        quarantine,
+        "playerdata",
+        "player",
+        types::player,
+    );
 }
 
 fn upgrade_dat_dir(
     world: &Path,
     to_version: u32,
     dry_run: bool,
+    old_files: OldFilesMode,
+    quarantine: Option<&Quarantine>,
     name: &str,
+    type_name: &str,
     typ: impl Sync + Send + Fn() -> RwLockReadGuard<'static, MapDataType<'static>>,
 ) {
     let _span = info_span!("Upgrading data directory", message = name).entered();
     let dat_dir = world.join(name);
+    let num_up_to_date = AtomicUsize::new(0);
     match std::fs::read_dir(dat_dir) {
         Ok(dir) => {
             let parent_span = Span::current();
@@ -135,36 +347,46 @@ fn upgrade_dat_dir(
                 |_, file| match file {
                     Ok(file) => {
                         let path = file.path();
-                        if path.extension() == Some("dat".as_ref()) {
-                            let mut file = match File::options()
-                                .read(true)
-                                .write(!dry_run)
-                                .open(&path)
-                            {
-                                Ok(file) => file,
-                                Err(err) => {
-                                    error!("Failed to open {}: {}", path.to_string_lossy(), err);
-                                    return;
+                        let outcome = if path.extension() == Some("dat".as_ref()) {
+                            let outcome = upgrade_dat_file(
+                                &path, to_version, dry_run, quarantine, type_name, &typ,
+                            );
+                            if old_files == OldFilesMode::Refresh {
+                                let old_path = path.with_extension("dat_old");
+                                match outcome {
+                                    UpgradeOutcome::Upgraded if !dry_run => {
+                                        refresh_old_file(&path, &old_path);
+                                    }
+                                    // the backup may still be at an old version, but there's no
+                                    // fresh primary file to replace it with
+                                    UpgradeOutcome::UpToDate if old_path.exists() => {
+                                        upgrade_dat_file(
+                                            &old_path, to_version, dry_run, quarantine, type_name,
+                                            &typ,
+                                        );
+                                    }
+                                    _ => {}
                                 }
+                            }
+                            outcome
+                        } else if path.extension() == Some("dat_old".as_ref()) {
+                            let upgrade_old = match old_files {
+                                OldFilesMode::Ignore => false,
+                                OldFilesMode::Upgrade => true,
+                                // an orphaned backup has no primary file to be refreshed from
+                                OldFilesMode::Refresh => !path.with_extension("dat").exists(),
                             };
-                            let Some(mut data) = read_compound(&mut file) else {
-                                error!("Failed to read {}", path.to_string_lossy());
-                                return;
-                            };
-
-                            if !upgrade(
-                                &typ,
-                                &mut data,
-                                || path.to_string_lossy().into_owned(),
-                                to_version,
-                                99,
-                            ) {
+                            if !upgrade_old {
                                 return;
                             }
-
-                            if !dry_run && !write_compound(&mut file, &data) {
-                                error!("Failed to write file {}", path.to_string_lossy());
-                            }
+                            upgrade_dat_file(
+                                &path, to_version, dry_run, quarantine, type_name, &typ,
+                            )
+                        } else {
+                            return;
+                        };
+                        if outcome == UpgradeOutcome::UpToDate {
+                            num_up_to_date.fetch_add(1, Ordering::Relaxed);
                         }
                     }
                     Err(err) => {
@@ -178,33 +400,114 @@ fn upgrade_dat_dir(
             error!("Failed to read {name} dir: {err}");
         }
     }
+    log_up_to_date(num_up_to_date.into_inner());
+}
+
+fn log_up_to_date(num_up_to_date: usize) {
+    if num_up_to_date > 0 {
+        info!("Skipped {num_up_to_date} files already at the target version");
+    }
+}
+
+/// Upgrades a single gzipped NBT file in place.
+fn upgrade_dat_file(
+    path: &Path,
+    to_version: u32,
+    dry_run: bool,
+    quarantine: Option<&Quarantine>,
+    type_name: &str,
+    typ: impl FnOnce() -> RwLockReadGuard<'static, MapDataType<'static>>,
+) -> UpgradeOutcome {
+    let mut file = match File::options().read(true).write(!dry_run).open(path) {
+        Ok(file) => file,
+        Err(err) => {
+            error!("Failed to open {}: {}", path.to_string_lossy(), err);
+            return UpgradeOutcome::NotUpgraded;
+        }
+    };
+    let Some(mut data) = read_compound(&mut file) else {
+        error!("Failed to read {}", path.to_string_lossy());
+        quarantine_file(
+            quarantine,
+            path,
+            type_name,
+            99,
+            FileFormat::Nbt,
+            &"failed to read",
+        );
+        return UpgradeOutcome::NotUpgraded;
+    };
+    if is_up_to_date(&data, to_version) {
+        return UpgradeOutcome::UpToDate;
+    }
+
+    if let Err(failure) = try_upgrade(
+        typ,
+        &mut data,
+        || path.to_string_lossy().into_owned(),
+        to_version,
+        99,
+    ) {
+        if failure.should_quarantine() {
+            quarantine_file(quarantine, path, type_name, 99, FileFormat::Nbt, &failure);
+        }
+        return UpgradeOutcome::NotUpgraded;
+    }
+
+    if !dry_run && !write_compound(&mut file, &data) {
+        error!("Failed to write file {}", path.to_string_lossy());
+        return UpgradeOutcome::NotUpgraded;
+    }
+
+    UpgradeOutcome::Upgraded
 }
 
-pub fn upgrade_advancements(world: &Path, to_version: u32, dry_run: bool) {
+pub fn upgrade_advancements(
+    world: &Path,
+    to_version: u32,
+    dry_run: bool,
+    quarantine: Option<&Quarantine>,
+) {
     upgrade_json_dir(
         world,
         to_version,
         dry_run,
+        quarantine,
         "advancements",
         true,
         types::advancements,
     )
 }
 
-pub fn upgrade_stats(world: &Path, to_version: u32, dry_run: bool) {
-    upgrade_json_dir(world, to_version, dry_run, "stats", false, types::stats);
+pub fn upgrade_stats(
+    world: &Path,
+    to_version: u32,
+    dry_run: bool,
+    quarantine: Option<&Quarantine>,
+) {
+    upgrade_json_dir(
+        world,
+        to_version,
+        dry_run,
+        quarantine,
+        "stats",
+        false,
+        types::stats,
+    );
 }
 
 fn upgrade_json_dir(
     world: &Path,
     to_version: u32,
     dry_run: bool,
+    quarantine: Option<&Quarantine>,
     name: &str,
     pretty_json: bool,
     typ: impl Sync + Send + Fn() -> RwLockReadGuard<'static, MapDataType<'static>>,
 ) {
     let _span = info_span!("Upgrading json directory", message = name).entered();
     let json_dir = world.join(name);
+    let num_up_to_date = AtomicUsize::new(0);
     match std::fs::read_dir(json_dir) {
         Ok(dir) => {
             let parent_span = Span::current();
@@ -214,10 +517,21 @@ fn upgrade_json_dir(
                     Ok(file) => {
                         let path = file.path();
                         if path.extension() == Some("json".as_ref()) {
+                            let format = FileFormat::Json {
+                                pretty: pretty_json,
+                            };
                             let json = match std::fs::read_to_string(&path) {
                                 Ok(json) => json,
                                 Err(err) => {
                                     error!("Failed to read {}: {}", path.to_string_lossy(), err);
+                                    quarantine_file(
+                                        quarantine,
+                                        &path,
+                                        name,
+                                        ADVANCEMENTS_AND_STATS_VERSION,
+                                        format,
+                                        &err,
+                                    );
                                     return;
                                 }
                             };
@@ -226,17 +540,39 @@ fn upgrade_json_dir(
                                 Ok(compound) => compound,
                                 Err(err) => {
                                     error!("Failed to read {}: {}", path.to_string_lossy(), err);
+                                    quarantine_file(
+                                        quarantine,
+                                        &path,
+                                        name,
+                                        ADVANCEMENTS_AND_STATS_VERSION,
+                                        format,
+                                        &err,
+                                    );
                                     return;
                                 }
                             };
 
-                            if !upgrade(
+                            if is_up_to_date(&compound, to_version) {
+                                num_up_to_date.fetch_add(1, Ordering::Relaxed);
+                                return;
+                            }
+                            if let Err(failure) = try_upgrade(
                                 &typ,
                                 &mut compound,
                                 || path.to_string_lossy().into_owned(),
                                 to_version,
                                 ADVANCEMENTS_AND_STATS_VERSION,
                             ) {
+                                if failure.should_quarantine() {
+                                    quarantine_file(
+                                        quarantine,
+                                        &path,
+                                        name,
+                                        ADVANCEMENTS_AND_STATS_VERSION,
+                                        format,
+                                        &failure,
+                                    );
+                                }
                                 return;
                             }
 
@@ -265,6 +601,7 @@ fn upgrade_json_dir(
             error!("Failed to read {name} dir: {err}");
         }
     }
+    log_up_to_date(num_up_to_date.into_inner());
 }
 
 pub fn read_compound<R: Read>(read: R) -> Option<JCompound> {
@@ -277,7 +614,12 @@ pub fn read_compound<R: Read>(read: R) -> Option<JCompound> {
         .map(|(compound, _)| compound)
 }
 
+/// Overwrites the whole contents of a file that has already been read from with the given compound.
 #[must_use]
-fn write_compound<W: Write>(write: W, data: &JCompound) -> bool {
-    to_binary(data, GzEncoder::new(write, Compression::default()), "").is_ok()
+fn write_compound(file: &mut File, data: &JCompound) -> bool {
+    if file.rewind().is_err() || file.set_len(0).is_err() {
+        return false;
+    }
+    let mut encoder = GzEncoder::new(file, Compression::default());
+    to_binary(data, &mut encoder, "").is_ok() && encoder.finish().is_ok()
 }
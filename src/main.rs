@@ -1,15 +1,37 @@
+mod bench;
+mod convert;
 mod data;
 mod dimensions;
+mod formats;
 mod individual_files;
+mod pipe;
+mod quarantine;
 mod region;
+mod schematic;
+mod unknown_ids;
 
+use crate::bench::{run_bench, Fixture};
+use crate::convert::{convert, ConvertOptions};
 use crate::data::{upgrade_data, upgrade_map_data};
 use crate::dimensions::upgrade_dimensions;
+use crate::formats::Format;
 use crate::individual_files::{
     upgrade_advancements, upgrade_level_dat, upgrade_playerdata, upgrade_stats,
+    LevelDatReconstruction, OldFilesMode,
 };
-use clap::{arg, command, value_parser, ArgAction};
-use std::fmt::Write;
+use crate::pipe::pipe;
+use crate::quarantine::{retry_quarantine, Quarantine};
+use crate::region::{
+    check_regions, log_duplicate_uuids, ChunkStatus, DuplicateUuids, RegionOptions, RetrogenMode,
+    RetrogenOverrides, UuidIndex, WrongSlotAction,
+};
+use crate::schematic::{upgrade_schematics, SchematicOptions};
+use crate::unknown_ids::UnknownIds;
+use clap::{arg, command, value_parser, ArgAction, ArgMatches, Command};
+use java_string::{JavaStr, JavaString};
+use rayon::ThreadPoolBuilder;
+use std::fmt::{Display, Formatter, Write};
+use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
 use std::sync::RwLockReadGuard;
 use time::OffsetDateTime;
@@ -71,6 +93,8 @@ fn main() {
 
     let _ = include_str!("../Cargo.toml"); // trick the compiler into recompiling when this changes
     let matches = command!()
+        .args_conflicts_with_subcommands(true)
+        .subcommand_negates_reqs(true)
         .arg(arg!(<world> "The path to the world folder").value_parser(value_parser!(PathBuf)))
         .arg(arg!(<to_version> "The version to update to"))
         .arg(arg!(-s --"allow-snapshots" ... "Allow snapshots").action(ArgAction::SetTrue))
@@ -78,58 +102,630 @@ fn main() {
             arg!(-d --"dry-run" ... "Don't write anything back to files")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            arg!(--"old-files" <mode> "What to do with level.dat_old and playerdata .dat_old backups")
+                .required(false)
+                .value_parser(["upgrade", "refresh", "ignore"])
+                .default_value("upgrade"),
+        )
+        .arg(
+            arg!(--"reconstruct-level-dat" <seed> "Generate a minimal level.dat with this seed if neither level.dat nor level.dat_old can be read")
+                .required(false)
+                .value_parser(value_parser!(i64))
+                .requires("reconstruct-level-dat-version"),
+        )
+        .arg(
+            arg!(--"reconstruct-level-dat-version" <version> "The version the world was last played in, used with --reconstruct-level-dat")
+                .required(false),
+        )
+        .arg(
+            arg!(--salvage "Try to recover the readable parts of corrupt chunks instead of skipping them")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"delete-unrecoverable" "Delete corrupt chunks which couldn't be salvaged, so the game regenerates them")
+                .action(ArgAction::SetTrue)
+                .requires("salvage"),
+        )
+        .arg(
+            arg!(-j --threads <count> "The number of threads converting data, defaults to the number of CPUs")
+                .required(false)
+                .value_parser(value_parser!(usize)),
+        )
+        .arg(
+            arg!(--"io-threads" <count> "The number of threads reading region files")
+                .required(false)
+                .value_parser(value_parser!(usize))
+                .default_value("2"),
+        )
+        .arg(
+            arg!(--"memory-limit" <mebibytes> "Roughly how much region data to hold in memory at once")
+                .required(false)
+                .value_parser(value_parser!(u64)),
+        )
+        .arg(
+            arg!(--quarantine <dir> "Copy files and chunks which fail to upgrade into this folder, along with why they failed")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"unknown-id-report" <file> "Write a JSON report of block, block entity, entity and item IDs found in chunks which aren't in the registries of the target version")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"remap-ids" <file> "A JSON object of IDs to replace in chunks, mapping each old ID to its replacement")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--registries <file> "The reports/registries.json generated by the server of the target version, which the IDs in --unknown-id-report are looked up in. Without it, only IDs outside the minecraft namespace are reported")
+                .required(false)
+                .value_parser(value_parser!(PathBuf)),
+        )
+        .arg(
+            arg!(--"duplicate-uuids" <mode> "Look for entities sharing a UUID across the world, and either report them or give the later ones fresh UUIDs")
+                .required(false)
+                .value_parser(["report", "fix"]),
+        )
+        .arg(
+            arg!(--"wrong-slot-chunks" <action> "What to do with chunks whose position doesn't match the slot they're stored in: move them to their position, rewrite their position to match the slot, or drop them. They're only reported by default")
+                .required(false)
+                .value_parser(["move", "rewrite", "drop"]),
+        )
+        .arg(
+            arg!(--"prune-inhabited-below" <ticks> "Delete chunks which players have spent fewer than this many ticks in, along with their entities and poi, so that they regenerate")
+                .required(false)
+                .value_parser(value_parser!(i64)),
+        )
+        .arg(
+            arg!(--"prune-outside-border" "Delete chunks entirely outside the world border, along with their entities and poi")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            arg!(--"drop-proto-chunks" [status] "Delete partially generated chunks which haven't reached this status, along with their entities and poi, so that they regenerate. Defaults to full")
+                .num_args(0..=1)
+                .default_missing_value("full"),
+        )
+        .arg(
+            arg!(--blending <mode> "Whether pre-1.18 chunks are marked for blending with new terrain: auto, on (overworld only) or off. Can be given per dimension as <dimension>=<mode>")
+                .required(false)
+                .action(ArgAction::Append),
+        )
+        .arg(
+            arg!(--"below-zero-retrogen" <mode> "Whether pre-1.18 chunks have terrain generated below y=0: auto, on (overworld only) or off. Can be given per dimension as <dimension>=<mode>")
+                .required(false)
+                .action(ArgAction::Append),
+        )
+        .subcommand(
+            Command::new("retry-quarantine")
+                .about("Try again to upgrade the files and chunks in a quarantine folder, writing them back into the world")
+                .arg(arg!(<quarantine> "The quarantine folder").value_parser(value_parser!(PathBuf)))
+            .arg(arg!(<world> "The path to the world folder").value_parser(value_parser!(PathBuf)))
+            .arg(arg!(<to_version> "The version to update to"))
+            .arg(arg!(-s --"allow-snapshots" ... "Allow snapshots").action(ArgAction::SetTrue))
+            .arg(
+                arg!(-d --"dry-run" ... "Don't write anything back to files")
+                    .action(ArgAction::SetTrue),
+            )
+        )
+        .subcommand(
+            Command::new("check-regions")
+                .about("Check the region files of a world for damaged headers and chunks")
+                .arg(arg!(<world> "The path to the world folder").value_parser(value_parser!(PathBuf)))
+                .arg(
+                    arg!(--repair "Rebuild damaged region files from the chunks which can be decoded, keeping the originals as .bak files")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("convert")
+                .about("Upgrade a single file or region file outside of a world")
+                .arg(arg!(<input> "The file to upgrade").value_parser(value_parser!(PathBuf)))
+                .arg(arg!(<to_version> "The version to update to"))
+                .arg(
+                    arg!(-t --"type" <name> "The type of data in the file, e.g. player, chunk, entity_chunk, poi_chunk, level, structure or saved_data_raids"),
+                )
+                .arg(
+                    arg!(-o --output <file> "Where to write the upgraded file, which can be the input file")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(--from <version> "The version the data is from, if it doesn't record one itself")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--dimension <id> "The dimension a chunk is from")
+                        .required(false)
+                        .default_value("minecraft:overworld"),
+                )
+                .arg(
+                    arg!(--generator <id> "The type of generator of the dimension a chunk is from")
+                        .required(false)
+                        .default_value("minecraft:noise"),
+                )
+                .arg(
+                    arg!(--world <folder> "The world to read the legacy structure data of pre-1.13 chunks from. Without it, the structures in those chunks are dropped")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-f --format <format> "The format to write the output in, defaults to the format of the input")
+                        .required(false)
+                        .value_parser(Format::NAMES),
+                )
+                .arg(arg!(-s --"allow-snapshots" ... "Allow snapshots").action(ArgAction::SetTrue)),
+        )
+        .subcommand(
+            Command::new("pipe")
+                .about("Upgrade newline-separated SNBT or JSON records from stdin, writing them to stdout")
+                .arg(
+                    arg!(-t --"type" <name> "The type of data in each record, e.g. item_stack, block_state, player or chunk"),
+                )
+                .arg(arg!(--to <version> "The version to update to").id("to_version"))
+                .arg(
+                    arg!(--from <version> "The version the records are from, if they don't record one themselves")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--dimension <id> "The dimension chunks are from")
+                        .required(false)
+                        .default_value("minecraft:overworld"),
+                )
+                .arg(
+                    arg!(--generator <id> "The type of generator of the dimension chunks are from")
+                        .required(false)
+                        .default_value("minecraft:noise"),
+                )
+                .arg(
+                    arg!(--world <folder> "The world to read the legacy structure data of pre-1.13 chunks from. Without it, the structures in those chunks are dropped")
+                        .required(false)
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(
+                    arg!(-f --format <format> "The format to write records in, defaults to the format of each input record")
+                        .required(false)
+                        .value_parser(Format::TEXT_NAMES),
+                )
+                .arg(arg!(-s --"allow-snapshots" ... "Allow snapshots").action(ArgAction::SetTrue)),
+        )
+        .subcommand(
+            Command::new("schematic")
+                .about("Upgrade Sponge (.schem) and Litematica (.litematic) schematics in place")
+                .arg(
+                    arg!(<path> "A schematic, or a folder to search for schematics")
+                        .value_parser(value_parser!(PathBuf)),
+                )
+                .arg(arg!(<to_version> "The version to update to"))
+                .arg(
+                    arg!(--from <version> "The version schematics are from, if they don't record one themselves")
+                        .required(false),
+                )
+                .arg(
+                    arg!(--"sponge-version" <version> "The Sponge schematic format version to write, defaults to the version of each schematic")
+                        .required(false)
+                        .value_parser(value_parser!(i32).range(2..=3)),
+                )
+                .arg(arg!(-s --"allow-snapshots" ... "Allow snapshots").action(ArgAction::SetTrue))
+                .arg(
+                    arg!(-d --"dry-run" ... "Don't write anything back to files")
+                        .action(ArgAction::SetTrue),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Measure how fast chunks are read, converted and written")
+                .arg(arg!(<to_version> "The version to update to"))
+                .arg(arg!(-s --"allow-snapshots" ... "Allow snapshots").action(ArgAction::SetTrue))
+                .arg(
+                    arg!(--from <version> "Generate chunks at this version to benchmark")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .default_values(["1.12.2", "1.16.5", "1.18.2"]),
+                )
+                .arg(
+                    arg!(--chunks <count> "The number of chunks to generate for each version")
+                        .required(false)
+                        .value_parser(value_parser!(usize))
+                        .default_value("1024"),
+                )
+                .arg(
+                    arg!(--regions <dir> "Benchmark an existing region folder instead of generated chunks")
+                        .required(false)
+                        .action(ArgAction::Append)
+                        .value_parser(value_parser!(PathBuf)),
+                ),
+        )
         .get_matches();
 
-    let world = matches.get_one::<PathBuf>("world").unwrap();
+    match matches.subcommand() {
+        Some(("retry-quarantine", sub_matches)) => {
+            let world = sub_matches.get_one::<PathBuf>("world").unwrap();
+            let Some(to_version) = parse_to_version(sub_matches) else {
+                return;
+            };
+            retry_quarantine(
+                sub_matches.get_one::<PathBuf>("quarantine").unwrap(),
+                world,
+                to_version,
+                sub_matches.get_flag("dry-run"),
+            );
+            info!("Done");
+        }
+        Some(("check-regions", sub_matches)) => {
+            if check_regions(
+                sub_matches.get_one::<PathBuf>("world").unwrap(),
+                sub_matches.get_flag("repair"),
+            ) {
+                info!("Done");
+            } else {
+                std::process::exit(1);
+            }
+        }
+        Some(("convert", sub_matches)) => {
+            let Some(to_version) = parse_to_version(sub_matches) else {
+                return;
+            };
+            let Some(options) = parse_convert_options(sub_matches, to_version) else {
+                return;
+            };
+            if convert(
+                sub_matches.get_one::<PathBuf>("input").unwrap(),
+                sub_matches.get_one::<PathBuf>("output").unwrap(),
+                &options,
+            ) {
+                info!("Done");
+            } else {
+                std::process::exit(1);
+            }
+        }
+        Some(("pipe", sub_matches)) => {
+            let Some(to_version) = parse_to_version(sub_matches) else {
+                return;
+            };
+            let Some(options) = parse_convert_options(sub_matches, to_version) else {
+                return;
+            };
+            if !pipe(&options) {
+                std::process::exit(1);
+            }
+        }
+        Some(("schematic", sub_matches)) => {
+            let Some(to_version) = parse_to_version(sub_matches) else {
+                return;
+            };
+            let Ok(from_version) = parse_from_version(sub_matches) else {
+                return;
+            };
+            let options = SchematicOptions {
+                to_version,
+                from_version,
+                sponge_version: sub_matches.get_one::<i32>("sponge-version").copied(),
+                dry_run: sub_matches.get_flag("dry-run"),
+            };
+            if upgrade_schematics(sub_matches.get_one::<PathBuf>("path").unwrap(), &options) {
+                info!("Done");
+            } else {
+                std::process::exit(1);
+            }
+        }
+        Some(("bench", sub_matches)) => {
+            let Some(to_version) = parse_to_version(sub_matches) else {
+                return;
+            };
+            let fixtures = match sub_matches.get_many::<PathBuf>("regions") {
+                Some(folders) => folders.cloned().map(Fixture::Folder).collect(),
+                None => {
+                    let mut fixtures = Vec::new();
+                    for version in sub_matches.get_many::<String>("from").unwrap() {
+                        let Some(version) = get_version_by_name(version) else {
+                            error!("Unknown version {version}");
+                            return;
+                        };
+                        fixtures.push(Fixture::Generated(version.data_version));
+                    }
+                    fixtures
+                }
+            };
+            run_bench(
+                to_version,
+                &fixtures,
+                *sub_matches.get_one::<usize>("chunks").unwrap(),
+            );
+        }
+        _ => upgrade_world(&matches),
+    }
+}
 
+fn parse_to_version(matches: &ArgMatches) -> Option<u32> {
     let to_version = matches.get_one::<String>("to_version").unwrap();
     let Some(to_version) = get_version_by_name(to_version) else {
         error!("Unknown version {to_version}");
-        return;
+        return None;
     };
     if to_version.typ == VersionType::Snapshot && !matches.get_flag("allow-snapshots") {
         error!(
             "{} is a snapshot. Use --allow-snapshots to upgrade the world anyway.",
             to_version.name
         );
-        return;
+        return None;
+    }
+    Some(to_version.data_version)
+}
+
+/// Reads the optional `--from` version, failing if it isn't a known version.
+fn parse_from_version(matches: &ArgMatches) -> Result<Option<u32>, ()> {
+    match matches.get_one::<String>("from") {
+        Some(from_version) => match get_version_by_name(from_version) {
+            Some(from_version) => Ok(Some(from_version.data_version)),
+            None => {
+                error!("Unknown version {from_version}");
+                Err(())
+            }
+        },
+        None => Ok(None),
     }
-    let to_version = to_version.data_version;
+}
+
+/// Reads the options shared by the subcommands which upgrade data outside of a world.
+fn parse_convert_options(matches: &ArgMatches, to_version: u32) -> Option<ConvertOptions> {
+    let from_version = parse_from_version(matches).ok()?;
+    Some(ConvertOptions {
+        type_name: matches.get_one::<String>("type").unwrap(),
+        from_version,
+        to_version,
+        dimension: JavaStr::from_str(matches.get_one::<String>("dimension").unwrap()),
+        generator: JavaStr::from_str(matches.get_one::<String>("generator").unwrap()),
+        world: matches.get_one::<PathBuf>("world").map(PathBuf::as_path),
+        output_format: matches
+            .get_one::<String>("format")
+            .and_then(|name| Format::from_name(name)),
+    })
+}
+
+/// Parses modes given either as `<mode>` for all dimensions or as `<dimension>=<mode>`.
+fn parse_retrogen_modes(
+    matches: &ArgMatches,
+    id: &str,
+) -> Option<Vec<(Option<JavaString>, RetrogenMode)>> {
+    let Some(values) = matches.get_many::<String>(id) else {
+        return Some(Vec::new());
+    };
+    let mut modes = Vec::new();
+    for value in values {
+        let (dimension, mode_name) = match value.rsplit_once('=') {
+            Some((dimension, mode)) if dimension.contains(':') => {
+                (Some(JavaString::from(dimension)), mode)
+            }
+            Some((dimension, mode)) => (
+                Some(JavaString::from(format!("minecraft:{dimension}"))),
+                mode,
+            ),
+            None => (None, value.as_str()),
+        };
+        let Some(mode) = RetrogenMode::from_name(mode_name) else {
+            error!("Unknown --{id} mode {mode_name}, expected auto, on or off");
+            return None;
+        };
+        if let Some(dimension) = &dimension {
+            if mode == RetrogenMode::On && dimension != "minecraft:overworld" {
+                error!("--{id} can only be forced on in the overworld, not in {dimension}");
+                return None;
+            }
+        }
+        modes.push((dimension, mode));
+    }
+    Some(modes)
+}
+
+fn upgrade_world(matches: &ArgMatches) {
+    let world = matches.get_one::<PathBuf>("world").unwrap();
+
+    let Some(to_version) = parse_to_version(matches) else {
+        return;
+    };
 
     let dry_run = matches.get_flag("dry-run");
 
-    let Some(level_dat) = upgrade_level_dat(world, to_version, dry_run) else {
+    if let Some(&threads) = matches.get_one::<usize>("threads") {
+        if let Err(err) = ThreadPoolBuilder::new().num_threads(threads).build_global() {
+            error!("Failed to set the number of threads: {err}");
+        }
+    }
+
+    let quarantine = matches
+        .get_one::<PathBuf>("quarantine")
+        .map(|quarantine_dir| Quarantine::new(world, quarantine_dir));
+
+    let report_path = matches
+        .get_one::<PathBuf>("unknown-id-report")
+        .map(PathBuf::as_path);
+    let remap_path = matches
+        .get_one::<PathBuf>("remap-ids")
+        .map(PathBuf::as_path);
+    let unknown_ids = if report_path.is_some() || remap_path.is_some() {
+        let Some(unknown_ids) = UnknownIds::load(
+            report_path,
+            remap_path,
+            matches
+                .get_one::<PathBuf>("registries")
+                .map(PathBuf::as_path),
+        ) else {
+            return;
+        };
+        Some(unknown_ids)
+    } else {
+        None
+    };
+
+    let uuid_index = match matches
+        .get_one::<String>("duplicate-uuids")
+        .map(String::as_str)
+    {
+        Some("report") => Some(UuidIndex::new(DuplicateUuids::Report)),
+        Some("fix") => Some(UuidIndex::new(DuplicateUuids::Fix)),
+        _ => None,
+    };
+
+    let drop_proto_chunks_below = match matches.get_one::<String>("drop-proto-chunks") {
+        Some(status) => match ChunkStatus::from_name(JavaStr::from_str(status)) {
+            Some(status) => Some(status),
+            None => {
+                error!("Unknown chunk status {status}");
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let Some(blending) = parse_retrogen_modes(matches, "blending") else {
+        return;
+    };
+    let Some(below_zero) = parse_retrogen_modes(matches, "below-zero-retrogen") else {
+        return;
+    };
+
+    let old_files = match matches.get_one::<String>("old-files").unwrap().as_str() {
+        "refresh" => OldFilesMode::Refresh,
+        "ignore" => OldFilesMode::Ignore,
+        _ => OldFilesMode::Upgrade,
+    };
+
+    let reconstruction = match matches.get_one::<i64>("reconstruct-level-dat") {
+        Some(&seed) => {
+            let version = matches
+                .get_one::<String>("reconstruct-level-dat-version")
+                .unwrap();
+            let Some(version) = get_version_by_name(version) else {
+                error!("Unknown version {version}");
+                return;
+            };
+            Some(LevelDatReconstruction {
+                seed,
+                version: version.data_version,
+            })
+        }
+        None => None,
+    };
+
+    let Some(level_dat) = upgrade_level_dat(
+        world,
+        to_version,
+        dry_run,
+        old_files,
+        reconstruction.as_ref(),
+    ) else {
         return;
     };
 
     if to_version >= ADVANCEMENTS_AND_STATS_VERSION {
-        upgrade_advancements(world, to_version, dry_run);
-        upgrade_stats(world, to_version, dry_run);
+        upgrade_advancements(world, to_version, dry_run, quarantine.as_ref());
+        upgrade_stats(world, to_version, dry_run, quarantine.as_ref());
     }
 
-    upgrade_playerdata(world, to_version, dry_run);
+    upgrade_playerdata(world, to_version, dry_run, old_files, quarantine.as_ref());
 
-    upgrade_dimensions(world, to_version, dry_run, &level_dat);
+    let region_options = RegionOptions {
+        salvage: matches.get_flag("salvage"),
+        delete_unrecoverable: matches.get_flag("delete-unrecoverable"),
+        io_threads: *matches.get_one::<usize>("io-threads").unwrap(),
+        memory_limit: matches
+            .get_one::<u64>("memory-limit")
+            .map(|mebibytes| mebibytes * 1024 * 1024),
+        prune_inhabited_below: matches.get_one::<i64>("prune-inhabited-below").copied(),
+        prune_outside_border: matches.get_flag("prune-outside-border"),
+        drop_proto_chunks_below,
+        retrogen: RetrogenOverrides {
+            blending,
+            below_zero,
+        },
+        wrong_slot_chunks: match matches
+            .get_one::<String>("wrong-slot-chunks")
+            .map(String::as_str)
+        {
+            Some("move") => Some(WrongSlotAction::Move),
+            Some("rewrite") => Some(WrongSlotAction::Rewrite),
+            Some("drop") => Some(WrongSlotAction::Drop),
+            _ => None,
+        },
+        quarantine: quarantine.clone(),
+        unknown_ids,
+        uuid_index,
+    };
+
+    upgrade_dimensions(world, to_version, dry_run, &level_dat, &region_options);
+    unknown_ids::write_report(region_options.unknown_ids.as_ref());
+    log_duplicate_uuids(region_options.uuid_index.as_ref());
 
     upgrade_data(
         world,
         "scoreboard",
+        "saved_data_scoreboard",
         types::saved_data_scoreboard,
         to_version,
         dry_run,
+        quarantine.as_ref(),
     );
     upgrade_data(
         world,
         "random_sequences",
+        "saved_data_random_sequences",
         types::saved_data_random_sequences,
         to_version,
         dry_run,
+        quarantine.as_ref(),
     );
-    upgrade_map_data(world, to_version, dry_run);
+    upgrade_map_data(world, to_version, dry_run, quarantine.as_ref());
 
     info!("Done");
 }
 
+/// What happened to a single file when it was upgraded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum UpgradeOutcome {
+    Upgraded,
+    /// The file was already at the target version, so was left untouched.
+    UpToDate,
+    /// The file was missing, or failed to upgrade.
+    NotUpgraded,
+}
+
+/// Whether some data is already at the version being upgraded to, and so doesn't need to be
+/// converted or written back.
+fn is_up_to_date(data: &JCompound, to_version: u32) -> bool {
+    data.get("DataVersion").and_then(|v| v.as_i32()) == Some(to_version as i32)
+}
+
+/// Why some data could not be upgraded.
+#[derive(Debug)]
+enum UpgradeFailure {
+    UnrecognizedVersion(u32),
+    Downgrade(u32),
+    ConversionPanicked,
+}
+
+impl UpgradeFailure {
+    /// Whether the data is worth keeping aside for later investigation, as opposed to having been
+    /// deliberately left alone.
+    fn should_quarantine(&self) -> bool {
+        !matches!(self, UpgradeFailure::Downgrade(_))
+    }
+}
+
+impl Display for UpgradeFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UpgradeFailure::UnrecognizedVersion(version) => {
+                write!(f, "unrecognized data version {version}")
+            }
+            UpgradeFailure::Downgrade(version) => {
+                write!(f, "cannot downgrade from data version {version}")
+            }
+            UpgradeFailure::ConversionPanicked => write!(f, "conversion failed"),
+        }
+    }
+}
+
 #[must_use]
 fn upgrade(
     typ: impl FnOnce() -> RwLockReadGuard<'static, MapDataType<'static>>,
@@ -138,6 +734,16 @@ fn upgrade(
     to_version: u32,
     default_version: u32,
 ) -> bool {
+    try_upgrade(typ, data, name, to_version, default_version).is_ok()
+}
+
+fn try_upgrade(
+    typ: impl FnOnce() -> RwLockReadGuard<'static, MapDataType<'static>>,
+    data: &mut JCompound,
+    name: impl FnOnce() -> String,
+    to_version: u32,
+    default_version: u32,
+) -> Result<(), UpgradeFailure> {
     let from_version = data
         .remove("DataVersion")
         .and_then(|v| v.as_i32())
@@ -145,16 +751,48 @@ fn upgrade(
         .unwrap_or(default_version);
     let Some(from_version) = get_version_by_id(from_version) else {
         warn!("{} had unrecognized data version {}", name(), from_version);
-        return false;
+        return Err(UpgradeFailure::UnrecognizedVersion(from_version));
     };
 
     if from_version.data_version > to_version {
         warn!("Cannot downgrade {} from {}", name(), from_version.name);
-        return false;
+        return Err(UpgradeFailure::Downgrade(from_version.data_version));
     }
 
-    typ().convert(data, from_version.data_version.into(), to_version.into());
+    // a bug in a converter shouldn't take the rest of the world down with it
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        typ().convert(data, from_version.data_version.into(), to_version.into())
+    }));
+    if result.is_err() {
+        error!("Failed to convert {}", name());
+        return Err(UpgradeFailure::ConversionPanicked);
+    }
     data.insert("DataVersion", to_version as i32);
 
-    true
+    Ok(())
+}
+
+type DataTypeFn = fn() -> RwLockReadGuard<'static, MapDataType<'static>>;
+
+/// Looks up a data type by the name used for it on the command line and in quarantine sidecars.
+fn data_type_by_name(name: &str) -> Option<DataTypeFn> {
+    let typ: DataTypeFn = match name {
+        "player" => types::player,
+        "level" => types::level,
+        "chunk" => types::chunk,
+        "entity_chunk" => types::entity_chunk,
+        "poi_chunk" => types::poi_chunk,
+        "advancements" => types::advancements,
+        "stats" => types::stats,
+        "saved_data_map_data" => types::saved_data_map_data,
+        "saved_data_raids" => types::saved_data_raids,
+        "saved_data_scoreboard" => types::saved_data_scoreboard,
+        "saved_data_random_sequences" => types::saved_data_random_sequences,
+        "saved_data_structure_feature_indices" => types::saved_data_structure_feature_indices,
+        "structure" => types::structure,
+        "item_stack" => types::item_stack,
+        "block_state" => types::block_state,
+        _ => return None,
+    };
+    Some(typ)
 }
@@ -0,0 +1,75 @@
+use crate::convert::{convert_compound, ConvertOptions};
+use crate::formats;
+use crate::formats::Format;
+use std::io;
+use std::io::{BufRead, Write};
+use tracing::{error, info, warn};
+
+/// Upgrades a stream of newline-separated SNBT or JSON records from stdin, writing each one to
+/// stdout on its own line. Records which fail to upgrade are written back unchanged, so that the
+/// output lines up with the input. Returns whether every record was upgraded.
+#[must_use]
+pub fn pipe(options: &ConvertOptions) -> bool {
+    let mut stdout = io::stdout().lock();
+    let mut num_records = 0;
+    let mut num_failed = 0;
+
+    for (index, line) in io::stdin().lock().lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                error!("Failed to read from stdin: {err}");
+                return false;
+            }
+        };
+
+        let output = if line.trim().is_empty() {
+            None
+        } else {
+            num_records += 1;
+            let output = upgrade_record(&line, index + 1, options);
+            if output.is_none() {
+                num_failed += 1;
+            }
+            output
+        };
+
+        if let Err(err) = writeln!(stdout, "{}", output.as_deref().unwrap_or(&line)) {
+            error!("Failed to write to stdout: {err}");
+            return false;
+        }
+    }
+
+    if num_failed > 0 {
+        warn!("{num_failed} of {num_records} records failed to upgrade and were written back unchanged");
+    } else {
+        info!("Upgraded {num_records} records");
+    }
+    num_failed == 0
+}
+
+fn upgrade_record(line: &str, line_number: usize, options: &ConvertOptions) -> Option<String> {
+    let name = format!("Record on line {line_number}");
+    let input_format = match Format::detect(line.as_bytes()) {
+        Some(format @ (Format::Snbt | Format::Json)) => format,
+        _ => {
+            error!("{name} is neither SNBT nor JSON");
+            return None;
+        }
+    };
+    let Some(mut data) = formats::read(line.as_bytes(), input_format) else {
+        error!("Failed to parse {name}");
+        return None;
+    };
+
+    // records such as item stacks don't carry a DataVersion, and shouldn't gain one
+    let had_data_version = data.contains_key("DataVersion");
+    if !convert_compound(&mut data, &name, options) {
+        return None;
+    }
+    if !had_data_version {
+        data.remove("DataVersion");
+    }
+
+    formats::write_line(data, options.output_format.unwrap_or(input_format))
+}
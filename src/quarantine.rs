@@ -0,0 +1,398 @@
+use crate::region::retry_quarantined_chunk;
+use crate::{data_type_by_name, try_upgrade};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use java_string::{JavaStr, JavaString};
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use tracing::{error, info, info_span, warn};
+use valence_nbt::{from_binary, jcompound, to_binary};
+use world_transmuter::json::{parse_compound, stringify_compound};
+use world_transmuter_engine::{JCompound, JValue};
+
+const SIDECAR_SUFFIX: &str = ".quarantine.json";
+
+/// A folder which files and chunks that fail to upgrade are copied into, at the same path relative to
+/// it as they had relative to the world.
+#[derive(Clone, Debug)]
+pub struct Quarantine {
+    world: PathBuf,
+    dir: PathBuf,
+}
+
+/// The format a quarantined file was stored in, so it can be written back the same way.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    Nbt,
+    Json { pretty: bool },
+}
+
+impl FileFormat {
+    fn name(self) -> &'static str {
+        match self {
+            FileFormat::Nbt => "nbt",
+            FileFormat::Json { pretty: false } => "json",
+            FileFormat::Json { pretty: true } => "pretty_json",
+        }
+    }
+
+    fn from_name(name: &JavaStr) -> Option<Self> {
+        match name.as_bytes() {
+            b"nbt" => Some(FileFormat::Nbt),
+            b"json" => Some(FileFormat::Json { pretty: false }),
+            b"pretty_json" => Some(FileFormat::Json { pretty: true }),
+            _ => None,
+        }
+    }
+}
+
+/// Copies a file which could not be upgraded into the quarantine folder, if there is one.
+pub fn quarantine_file(
+    quarantine: Option<&Quarantine>,
+    path: &Path,
+    typ: &str,
+    default_version: u32,
+    format: FileFormat,
+    reason: &dyn Display,
+) {
+    let Some(quarantine) = quarantine else {
+        return;
+    };
+    let Some(relative_path) = quarantine.relative_path(path) else {
+        return;
+    };
+
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) => {
+            error!("Failed to quarantine {}: {}", path.to_string_lossy(), err);
+            return;
+        }
+    };
+
+    let sidecar = jcompound! {
+        "kind" => JavaStr::from_str("file"),
+        "path" => JavaString::from(relative_path.to_string_lossy().into_owned()),
+        "type" => JavaStr::from_str(typ),
+        "default_version" => default_version as i32,
+        "format" => JavaStr::from_str(format.name()),
+        "reason" => JavaString::from(reason.to_string()),
+    };
+    quarantine.write(relative_path, &contents, sidecar);
+}
+
+/// Copies the raw sectors of a chunk which could not be upgraded into the quarantine folder. The
+/// sectors must hold the chunk's data inline, rather than pointing to an external `.mcc` file.
+#[allow(clippy::too_many_arguments)]
+pub fn quarantine_chunk(
+    quarantine: &Quarantine,
+    regions_path: &Path,
+    chunk_x: i32,
+    chunk_z: i32,
+    sectors: &[u8],
+    typ: &str,
+    default_version: u32,
+    context: Option<&JCompound>,
+    reason: &dyn Display,
+) {
+    let Some(relative_regions_path) = quarantine.relative_path(regions_path) else {
+        return;
+    };
+    let mut sidecar = jcompound! {
+        "kind" => JavaStr::from_str("chunk"),
+        "path" => JavaString::from(relative_regions_path.to_string_lossy().into_owned()),
+        "chunk_x" => chunk_x,
+        "chunk_z" => chunk_z,
+        "type" => JavaStr::from_str(typ),
+        "default_version" => default_version as i32,
+        "reason" => JavaString::from(reason.to_string()),
+    };
+    if let Some(context) = context {
+        sidecar.insert("context", context.clone());
+    }
+    quarantine.write(
+        &relative_regions_path.join(format!("c.{chunk_x}.{chunk_z}.chunk")),
+        sectors,
+        sidecar,
+    );
+}
+
+impl Quarantine {
+    pub fn new(world: &Path, dir: &Path) -> Self {
+        Self {
+            world: world.to_path_buf(),
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    /// The path of something in the world relative to the world folder, which is where it's put in
+    /// the quarantine and written back to when it's retried. Anything outside the world has no such
+    /// path, so can't be quarantined.
+    fn relative_path<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        match path.strip_prefix(&self.world) {
+            Ok(relative_path) => Some(relative_path),
+            Err(_) => {
+                error!(
+                    "Can't quarantine {}, it isn't inside the world {}",
+                    path.to_string_lossy(),
+                    self.world.to_string_lossy()
+                );
+                None
+            }
+        }
+    }
+
+    fn write(&self, relative_path: &Path, contents: &[u8], sidecar: JCompound) {
+        let path = self.dir.join(relative_path);
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, contents))
+            .and_then(|_| {
+                std::fs::write(
+                    sidecar_path(&path),
+                    stringify_compound(sidecar, true, true).as_bytes(),
+                )
+            });
+        match result {
+            Ok(()) => warn!("Quarantined {}", relative_path.to_string_lossy()),
+            Err(err) => error!(
+                "Failed to quarantine {}: {}",
+                relative_path.to_string_lossy(),
+                err
+            ),
+        }
+    }
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut sidecar_path = path.as_os_str().to_owned();
+    sidecar_path.push(SIDECAR_SUFFIX);
+    PathBuf::from(sidecar_path)
+}
+
+/// Tries to upgrade everything in a quarantine folder again, writing it back into the world and
+/// removing it from the quarantine if it succeeds.
+pub fn retry_quarantine(quarantine_dir: &Path, world: &Path, to_version: u32, dry_run: bool) {
+    let _span = info_span!("Retrying quarantine").entered();
+
+    let mut sidecars = Vec::new();
+    if let Err(err) = find_sidecars(quarantine_dir, &mut sidecars) {
+        error!("Failed to read quarantine dir: {err}");
+        return;
+    }
+
+    let mut num_succeeded = 0;
+    for sidecar_path in &sidecars {
+        let payload_path = PathBuf::from(
+            sidecar_path
+                .to_string_lossy()
+                .strip_suffix(SIDECAR_SUFFIX)
+                .unwrap(),
+        );
+        if retry_entry(sidecar_path, &payload_path, world, to_version, dry_run) {
+            num_succeeded += 1;
+            if !dry_run {
+                for path in [&payload_path, sidecar_path] {
+                    if let Err(err) = std::fs::remove_file(path) {
+                        error!("Failed to remove {}: {}", path.to_string_lossy(), err);
+                    }
+                }
+            }
+        }
+    }
+
+    info!(
+        "Upgraded {num_succeeded} of {} quarantined entries",
+        sidecars.len()
+    );
+}
+
+fn find_sidecars(dir: &Path, sidecars: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_sidecars(&path, sidecars)?;
+        } else if path.to_string_lossy().ends_with(SIDECAR_SUFFIX) {
+            sidecars.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn retry_entry(
+    sidecar_path: &Path,
+    payload_path: &Path,
+    world: &Path,
+    to_version: u32,
+    dry_run: bool,
+) -> bool {
+    let name = payload_path.to_string_lossy();
+
+    let sidecar = match std::fs::read_to_string(sidecar_path) {
+        Ok(sidecar) => sidecar,
+        Err(err) => {
+            error!("Failed to read {}: {}", sidecar_path.to_string_lossy(), err);
+            return false;
+        }
+    };
+    let sidecar = match parse_compound(JavaStr::from_str(&sidecar), true) {
+        Ok(sidecar) => sidecar,
+        Err(err) => {
+            error!(
+                "Failed to parse {}: {}",
+                sidecar_path.to_string_lossy(),
+                err
+            );
+            return false;
+        }
+    };
+    let payload = match std::fs::read(payload_path) {
+        Ok(payload) => payload,
+        Err(err) => {
+            error!("Failed to read {name}: {err}");
+            return false;
+        }
+    };
+
+    let (Some(JValue::String(kind)), Some(JValue::String(path)), Some(JValue::String(typ))) = (
+        sidecar.get("kind"),
+        sidecar.get("path"),
+        sidecar.get("type"),
+    ) else {
+        error!(
+            "Missing kind, path or type in {}",
+            sidecar_path.to_string_lossy()
+        );
+        return false;
+    };
+    let default_version = sidecar
+        .get("default_version")
+        .and_then(|v| v.as_i32())
+        .unwrap_or(99) as u32;
+    // the sidecar may have been edited or copied from elsewhere, so it mustn't point outside the world
+    let path = PathBuf::from(path.as_str_lossy().into_owned());
+    if !path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        error!(
+            "Path {} in {} isn't inside the world",
+            path.to_string_lossy(),
+            sidecar_path.to_string_lossy()
+        );
+        return false;
+    }
+    let path = world.join(path);
+    let typ = typ.as_str_lossy();
+
+    match kind.as_bytes() {
+        b"file" => {
+            let Some(format) = sidecar.get("format").and_then(|format| match format {
+                JValue::String(format) => FileFormat::from_name(format),
+                _ => None,
+            }) else {
+                error!("Unknown format in {}", sidecar_path.to_string_lossy());
+                return false;
+            };
+            let Some(data_type) = data_type_by_name(&typ) else {
+                error!(
+                    "Unknown data type {typ} in {}",
+                    sidecar_path.to_string_lossy()
+                );
+                return false;
+            };
+            let Some(mut data) = decode_file(&payload, format) else {
+                error!("Failed to read {name}");
+                return false;
+            };
+            if try_upgrade(
+                data_type,
+                &mut data,
+                || name.clone().into_owned(),
+                to_version,
+                default_version,
+            )
+            .is_err()
+            {
+                return false;
+            }
+            if !dry_run {
+                if let Err(err) = write_file(&path, data, format) {
+                    error!("Failed to write {}: {}", path.to_string_lossy(), err);
+                    return false;
+                }
+            }
+            true
+        }
+        b"chunk" => {
+            let (Some(chunk_x), Some(chunk_z)) = (
+                sidecar.get("chunk_x").and_then(|v| v.as_i32()),
+                sidecar.get("chunk_z").and_then(|v| v.as_i32()),
+            ) else {
+                error!(
+                    "Missing chunk position in {}",
+                    sidecar_path.to_string_lossy()
+                );
+                return false;
+            };
+            let context = match sidecar.get("context") {
+                Some(JValue::Compound(context)) => Some(context),
+                _ => None,
+            };
+            retry_quarantined_chunk(
+                world,
+                &path,
+                chunk_x,
+                chunk_z,
+                &payload,
+                &typ,
+                default_version,
+                context,
+                to_version,
+                dry_run,
+            )
+        }
+        _ => {
+            error!("Unknown kind {kind} in {}", sidecar_path.to_string_lossy());
+            false
+        }
+    }
+}
+
+fn decode_file(payload: &[u8], format: FileFormat) -> Option<JCompound> {
+    match format {
+        FileFormat::Nbt => {
+            let mut contents = Vec::new();
+            let contents = if payload.starts_with(&[0x1f, 0x8b]) {
+                GzDecoder::new(payload).read_to_end(&mut contents).ok()?;
+                &contents[..]
+            } else {
+                payload
+            };
+            from_binary(&mut &*contents)
+                .ok()
+                .map(|(compound, _)| compound)
+        }
+        FileFormat::Json { .. } => {
+            let json = std::str::from_utf8(payload).ok()?;
+            parse_compound(JavaStr::from_str(json), true).ok()
+        }
+    }
+}
+
+fn write_file(path: &Path, data: JCompound, format: FileFormat) -> io::Result<()> {
+    match format {
+        FileFormat::Nbt => {
+            let mut encoder = GzEncoder::new(File::create(path)?, Compression::default());
+            to_binary(&data, &mut encoder, "").map_err(io::Error::other)?;
+            encoder.finish()?;
+            Ok(())
+        }
+        FileFormat::Json { pretty } => std::fs::write(path, stringify_compound(data, true, pretty)),
+    }
+}
@@ -0,0 +1,465 @@
+use crate::region::raw::{
+    decode_payload, external_chunk_path, parse_region_file_name, ChunkPayload, COMPRESSION_ZLIB,
+    EXTERNAL_CHUNK_FLAG, SECTOR_SIZE,
+};
+use crate::region::salvage::salvage_chunk;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::{error, info, info_span, warn, Span};
+use valence_nbt::to_binary;
+use world_transmuter_engine::{JCompound, JValue};
+
+/// The location table followed by the timestamp table.
+const HEADER_SIZE: usize = 2 * SECTOR_SIZE as usize;
+
+/// The kinds of folder holding region files, which differ in how chunks record their position.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegionFolderKind {
+    Chunks,
+    Entities,
+    Poi,
+}
+
+impl RegionFolderKind {
+    pub fn from_type_name(type_name: &str) -> Self {
+        match type_name {
+            "chunk" => RegionFolderKind::Chunks,
+            "entity_chunk" => RegionFolderKind::Entities,
+            _ => RegionFolderKind::Poi,
+        }
+    }
+
+    fn from_folder_name(name: &str) -> Option<Self> {
+        match name {
+            "region" => Some(RegionFolderKind::Chunks),
+            "entities" => Some(RegionFolderKind::Entities),
+            "poi" => Some(RegionFolderKind::Poi),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Damage {
+    TruncatedHeader,
+    InsideHeader,
+    ZeroLength,
+    PastEndOfFile,
+    Overlapping,
+    BadLength,
+    MissingExternal,
+    Corrupt,
+    WrongSlot,
+}
+
+impl Damage {
+    const ALL: [Damage; 9] = [
+        Damage::TruncatedHeader,
+        Damage::InsideHeader,
+        Damage::ZeroLength,
+        Damage::PastEndOfFile,
+        Damage::Overlapping,
+        Damage::BadLength,
+        Damage::MissingExternal,
+        Damage::Corrupt,
+        Damage::WrongSlot,
+    ];
+
+    fn description(self) -> &'static str {
+        match self {
+            Damage::TruncatedHeader => "header is truncated",
+            Damage::InsideHeader => "entry points inside the header",
+            Damage::ZeroLength => "entry has no sectors",
+            Damage::PastEndOfFile => "entry runs past the end of the file",
+            Damage::Overlapping => "entry overlaps the sectors of another chunk",
+            Damage::BadLength => "length doesn't fit its sectors",
+            Damage::MissingExternal => "external .mcc file is missing",
+            Damage::Corrupt => "data is corrupt",
+            Damage::WrongSlot => "position doesn't match its slot",
+        }
+    }
+}
+
+#[derive(Default)]
+struct CheckStats {
+    damage: [AtomicUsize; Damage::ALL.len()],
+    damaged_files: AtomicUsize,
+    repaired_files: AtomicUsize,
+    salvaged: AtomicUsize,
+    dropped: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+/// Checks the headers and chunks of every region file in a world, reporting each kind of damage
+/// found. With `repair`, damaged region files are rebuilt from the chunks which can be decoded, and
+/// the originals are kept with a `.bak` extension, numbered if an older backup is in the way.
+/// Returns whether the world is free of damage afterwards.
+#[must_use]
+pub fn check_regions(world: &Path, repair: bool) -> bool {
+    let _span = info_span!("Checking region files").entered();
+
+    let mut files = Vec::new();
+    if let Err(err) = find_region_files(world, &mut files) {
+        error!("Error listing region files: {err}");
+        return false;
+    }
+    info!("Found {} region files", files.len());
+
+    let stats = CheckStats::default();
+    let parent_span = Span::current();
+    files.par_iter().for_each_init(
+        move || parent_span.clone().entered(),
+        |_, (path, kind, region_pos)| {
+            if let Err(err) = check_region_file(path, *kind, *region_pos, repair, &stats) {
+                error!("Error checking {}: {}", path.to_string_lossy(), err);
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+            }
+        },
+    );
+
+    for damage in Damage::ALL {
+        let count = stats.damage[damage as usize].load(Ordering::Acquire);
+        if count > 0 {
+            warn!("{count} times: {}", damage.description());
+        }
+    }
+    let num_damaged = stats.damaged_files.load(Ordering::Acquire);
+    let num_repaired = stats.repaired_files.load(Ordering::Acquire);
+    let num_errors = stats.errors.load(Ordering::Acquire);
+    if num_damaged == 0 {
+        info!("Found no damaged region files");
+    } else if repair {
+        info!("Repaired {num_repaired} of {num_damaged} damaged region files");
+        let num_salvaged = stats.salvaged.load(Ordering::Acquire);
+        if num_salvaged > 0 {
+            warn!("Salvaged {num_salvaged} chunks, some of their data may be missing");
+        }
+        let num_dropped = stats.dropped.load(Ordering::Acquire);
+        if num_dropped > 0 {
+            warn!("Dropped {num_dropped} chunks which couldn't be decoded");
+        }
+    } else {
+        warn!("Found {num_damaged} damaged region files, use --repair to rebuild them");
+    }
+
+    num_errors == 0 && (num_damaged == 0 || (repair && num_repaired == num_damaged))
+}
+
+fn find_region_files(
+    dir: &Path,
+    files: &mut Vec<(PathBuf, RegionFolderKind, (i32, i32))>,
+) -> io::Result<()> {
+    let kind = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(RegionFolderKind::from_folder_name);
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_region_files(&path, files)?;
+        } else if let Some(kind) = kind {
+            if let Some(region_pos) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(parse_region_file_name)
+            {
+                files.push((path, kind, region_pos));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A chunk which will be kept when the region file is rebuilt.
+struct KeptChunk {
+    index: usize,
+    chunk_pos: (i32, i32),
+    timestamp: [u8; 4],
+    /// The compression type followed by the compressed data.
+    payload: Vec<u8>,
+}
+
+fn check_region_file(
+    path: &Path,
+    kind: RegionFolderKind,
+    (region_x, region_z): (i32, i32),
+    repair: bool,
+    stats: &CheckStats,
+) -> io::Result<()> {
+    let bytes = std::fs::read(path)?;
+    // an empty region file has no chunks in it
+    if bytes.is_empty() {
+        return Ok(());
+    }
+
+    let mut damage = Vec::new();
+    if bytes.len() < HEADER_SIZE {
+        damage.push((Damage::TruncatedHeader, None));
+    }
+    let mut header = [0; HEADER_SIZE];
+    let header_len = bytes.len().min(HEADER_SIZE);
+    header[..header_len].copy_from_slice(&bytes[..header_len]);
+    let num_sectors = (bytes.len() as u64).div_ceil(SECTOR_SIZE);
+
+    let regions_path = path.parent().unwrap_or(Path::new("."));
+    let mut kept = Vec::new();
+    let mut sector_ranges = Vec::new();
+    for index in 0..1024 {
+        let location = u32::from_be_bytes(header[index * 4..index * 4 + 4].try_into().unwrap());
+        if location == 0 {
+            continue;
+        }
+        let chunk_pos = (
+            region_x * 32 + (index % 32) as i32,
+            region_z * 32 + (index / 32) as i32,
+        );
+        let (sector_offset, sector_count) = ((location >> 8) as u64, (location & 0xff) as u64);
+        if sector_count == 0 {
+            damage.push((Damage::ZeroLength, Some(chunk_pos)));
+            continue;
+        }
+        if sector_offset < (HEADER_SIZE as u64) / SECTOR_SIZE {
+            damage.push((Damage::InsideHeader, Some(chunk_pos)));
+            continue;
+        }
+        if sector_offset >= num_sectors {
+            damage.push((Damage::PastEndOfFile, Some(chunk_pos)));
+            continue;
+        }
+        if sector_offset + sector_count > num_sectors {
+            // the sectors which are there may still hold the whole chunk
+            damage.push((Damage::PastEndOfFile, Some(chunk_pos)));
+        }
+        sector_ranges.push((sector_offset, sector_offset + sector_count, chunk_pos));
+
+        let start = (sector_offset * SECTOR_SIZE) as usize;
+        let end = (((sector_offset + sector_count) * SECTOR_SIZE) as usize).min(bytes.len());
+        let Some((payload, salvaged)) = check_chunk(
+            regions_path,
+            kind,
+            chunk_pos,
+            &bytes[start..end],
+            &mut damage,
+        ) else {
+            continue;
+        };
+        if salvaged {
+            stats.salvaged.fetch_add(1, Ordering::Relaxed);
+        }
+        kept.push(KeptChunk {
+            index,
+            chunk_pos,
+            timestamp: header[SECTOR_SIZE as usize + index * 4..][..4]
+                .try_into()
+                .unwrap(),
+            payload,
+        });
+    }
+
+    sector_ranges.sort_unstable();
+    let mut used_until = 0;
+    for (start, end, chunk_pos) in sector_ranges {
+        if start < used_until {
+            damage.push((Damage::Overlapping, Some(chunk_pos)));
+        }
+        used_until = used_until.max(end);
+    }
+
+    let name = path.to_string_lossy();
+    for &(kind, chunk_pos) in &damage {
+        stats.damage[kind as usize].fetch_add(1, Ordering::Relaxed);
+        match chunk_pos {
+            Some((chunk_x, chunk_z)) => {
+                warn!("{name}: chunk {chunk_x}, {chunk_z}: {}", kind.description())
+            }
+            None => warn!("{name}: {}", kind.description()),
+        }
+    }
+
+    // chunks in the wrong slot are dealt with when upgrading, rebuilding the file doesn't help them
+    if damage.iter().all(|(kind, _)| *kind == Damage::WrongSlot) {
+        return Ok(());
+    }
+    stats.damaged_files.fetch_add(1, Ordering::Relaxed);
+    if repair {
+        let num_entries = (0..1024)
+            .filter(|index| header[index * 4..index * 4 + 4] != [0; 4])
+            .count();
+        stats
+            .dropped
+            .fetch_add(num_entries - kept.len(), Ordering::Relaxed);
+        rebuild_region_file(path, &kept)?;
+        stats.repaired_files.fetch_add(1, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Checks the sectors of a single chunk, returning the payload to keep when the region file is
+/// rebuilt and whether it had to be salvaged, or `None` if nothing could be decoded.
+fn check_chunk(
+    regions_path: &Path,
+    kind: RegionFolderKind,
+    (chunk_x, chunk_z): (i32, i32),
+    sectors: &[u8],
+    damage: &mut Vec<(Damage, Option<(i32, i32)>)>,
+) -> Option<(Vec<u8>, bool)> {
+    let chunk_pos = Some((chunk_x, chunk_z));
+    let Some(payload) = ChunkPayload::parse(sectors) else {
+        damage.push((Damage::BadLength, chunk_pos));
+        return None;
+    };
+    let length = u32::from_be_bytes(sectors[..4].try_into().unwrap()) as usize;
+    if 4 + length > sectors.len() {
+        damage.push((Damage::BadLength, chunk_pos));
+    }
+
+    let (chunk, salvaged) = if payload.external {
+        let external_path = external_chunk_path(regions_path, chunk_x, chunk_z);
+        let data = match std::fs::read(&external_path) {
+            Ok(data) => data,
+            Err(err) => {
+                if err.kind() == ErrorKind::NotFound {
+                    damage.push((Damage::MissingExternal, chunk_pos));
+                } else {
+                    error!("Error reading {}: {}", external_path.to_string_lossy(), err);
+                }
+                return None;
+            }
+        };
+        match decode_payload(payload.compression, &data) {
+            Some(chunk) => (chunk, false),
+            None => {
+                damage.push((Damage::Corrupt, chunk_pos));
+                return None;
+            }
+        }
+    } else {
+        match decode_payload(payload.compression, payload.data) {
+            Some(chunk) => (chunk, false),
+            None => {
+                damage.push((Damage::Corrupt, chunk_pos));
+                (salvage_chunk(sectors, kind)?, true)
+            }
+        }
+    };
+
+    if chunk_position(&chunk, kind).is_some_and(|pos| pos != (chunk_x, chunk_z)) {
+        damage.push((Damage::WrongSlot, chunk_pos));
+    }
+
+    if salvaged {
+        let mut encoder = ZlibEncoder::new(vec![COMPRESSION_ZLIB], Compression::default());
+        to_binary(&chunk, &mut encoder, "").ok()?;
+        Some((encoder.finish().ok()?, true))
+    } else {
+        // keep the original compressed data, and the external flag along with it
+        let mut result = vec![sectors[4]];
+        result.extend_from_slice(payload.data);
+        Some((result, false))
+    }
+}
+
+/// The position a chunk records for itself, if its kind of chunk records one.
+pub fn chunk_position(chunk: &JCompound, kind: RegionFolderKind) -> Option<(i32, i32)> {
+    match kind {
+        RegionFolderKind::Chunks => {
+            let level = match chunk.get("Level") {
+                Some(JValue::Compound(level)) => level,
+                _ => chunk,
+            };
+            Some((level.get("xPos")?.as_i32()?, level.get("zPos")?.as_i32()?))
+        }
+        RegionFolderKind::Entities => match chunk.get("Position") {
+            Some(JValue::IntArray(position)) if position.len() == 2 => {
+                Some((position[0], position[1]))
+            }
+            _ => None,
+        },
+        RegionFolderKind::Poi => None,
+    }
+}
+
+/// Changes the position a chunk records for itself, if its kind of chunk records one.
+pub fn set_chunk_position(
+    chunk: &mut JCompound,
+    kind: RegionFolderKind,
+    (chunk_x, chunk_z): (i32, i32),
+) {
+    match kind {
+        RegionFolderKind::Chunks => {
+            let level = if matches!(chunk.get("Level"), Some(JValue::Compound(_))) {
+                let Some(JValue::Compound(level)) = chunk.get_mut("Level") else {
+                    unreachable!()
+                };
+                level
+            } else {
+                chunk
+            };
+            level.insert("xPos", chunk_x);
+            level.insert("zPos", chunk_z);
+        }
+        RegionFolderKind::Entities => {
+            chunk.insert("Position", JValue::IntArray(vec![chunk_x, chunk_z]));
+        }
+        RegionFolderKind::Poi => {}
+    }
+}
+
+/// Writes a fresh region file containing the given chunks, keeping the original next to it.
+/// Chunks too big for the region file are moved to external `.mcc` files.
+fn rebuild_region_file(path: &Path, chunks: &[KeptChunk]) -> io::Result<()> {
+    let regions_path = path.parent().unwrap_or(Path::new("."));
+    let mut bytes = vec![0; HEADER_SIZE];
+    for chunk in chunks {
+        let sector_offset = bytes.len() as u64 / SECTOR_SIZE;
+        let mut sector_count = (4 + chunk.payload.len() as u64).div_ceil(SECTOR_SIZE);
+        let mut payload = &chunk.payload[..];
+        let external_stub;
+        if sector_count > 0xff {
+            let (chunk_x, chunk_z) = chunk.chunk_pos;
+            std::fs::write(
+                external_chunk_path(regions_path, chunk_x, chunk_z),
+                &chunk.payload[1..],
+            )?;
+            external_stub = [chunk.payload[0] | EXTERNAL_CHUNK_FLAG];
+            payload = &external_stub;
+            sector_count = 1;
+        }
+        let location = ((sector_offset as u32) << 8) | sector_count as u32;
+        bytes[chunk.index * 4..][..4].copy_from_slice(&location.to_be_bytes());
+        bytes[SECTOR_SIZE as usize + chunk.index * 4..][..4].copy_from_slice(&chunk.timestamp);
+        bytes.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(payload);
+        bytes.resize(((sector_offset + sector_count) * SECTOR_SIZE) as usize, 0);
+    }
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    std::fs::write(&temp_path, bytes)?;
+    std::fs::rename(path, backup_path(path))?;
+    std::fs::rename(&temp_path, path)
+}
+
+/// The first of `<file>.bak`, `<file>.bak.1`, `<file>.bak.2`, ... which doesn't exist yet, so
+/// repairing a file again doesn't overwrite the backup of the previous repair.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup_path = path.as_os_str().to_owned();
+    backup_path.push(".bak");
+    let mut number = 0;
+    loop {
+        let mut numbered_path = backup_path.clone();
+        if number > 0 {
+            numbered_path.push(format!(".{number}"));
+        }
+        let numbered_path = PathBuf::from(numbered_path);
+        if !numbered_path.exists() {
+            return numbered_path;
+        }
+        number += 1;
+    }
+}
@@ -1,6 +1,8 @@
 use crate::data::read_data;
-use crate::region::{upgrade_regions, SEPARATE_ENTITIES_VERSION};
-use crate::upgrade;
+use crate::region::uuids::{entity_uuid, index_entities, UuidIndex};
+use crate::region::{ChunkKind, RegionTask, SEPARATE_ENTITIES_VERSION};
+use crate::unknown_ids::UnknownIds;
+use crate::{try_upgrade, unknown_ids, upgrade, UpgradeFailure};
 use ahash::{AHashMap, AHashSet};
 use java_string::{JavaStr, JavaString};
 use std::collections::BTreeMap;
@@ -8,12 +10,13 @@ use std::io::ErrorKind;
 use std::path::Path;
 use std::sync::OnceLock;
 use tracing::{error, info_span};
-use valence_anvil::RegionFolder;
+use valence_anvil::{RawChunk, RegionFolder};
 use valence_nbt::{compound, jcompound};
 use world_transmuter::{static_string_map, static_string_set, types};
 use world_transmuter_engine::{JCompound, JList, JValue};
 
 const LAST_MONOLITH_STRUCTURE_DATA_VERSION: u32 = 1493; // 18w20c
+const CAVES_AND_CLIFFS_CHUNK_VERSION: u32 = 2832; // 1.17.1 -> 21w37a
 
 static_string_map! {
     CURRENT_TO_LEGACY_MAP, current_to_legacy_map, {
@@ -89,6 +92,8 @@ const END_KEYS: [&JavaStr; 1] = [JavaStr::from_str("EndCity")];
 
 struct LegacyStructureDataHandler {
     has_legacy_data: bool,
+    /// The structure starts which still have to be placed into their chunks, by structure ID. Starts
+    /// which have already been handled are dropped as they're read, as they'd never be used.
     data_map: BTreeMap<JavaString, AHashMap<(i32, i32), JCompound>>,
     index_map: BTreeMap<&'static JavaStr, StructureFeatureIndexSavedData>,
     legacy_keys: &'static [&'static JavaStr],
@@ -156,6 +161,8 @@ impl LegacyStructureDataHandler {
                 continue;
             };
 
+            // without an index, every start is treated as unhandled
+            let has_index = !index_saved_data.all.is_empty();
             let mut chunks = Vec::new();
             for (_, feature) in features {
                 let JValue::Compound(mut feature) = feature else {
@@ -175,14 +182,14 @@ impl LegacyStructureDataHandler {
                 }
 
                 if let Some(JValue::String(id)) = feature.get("id") {
-                    self.data_map
-                        .entry(id.clone())
-                        .or_default()
-                        .insert((chunk_x, chunk_z), feature);
+                    let starts = self.data_map.entry(id.clone()).or_default();
+                    if !has_index || index_saved_data.has_unhandled_index(chunk_x, chunk_z) {
+                        starts.insert((chunk_x, chunk_z), feature);
+                    }
                 }
             }
 
-            if !index_saved_data.all.is_empty() {
+            if has_index {
                 self.index_map.insert(*legacy_key, index_saved_data);
             } else {
                 let mut index_saved_data = StructureFeatureIndexSavedData::new();
@@ -405,114 +412,409 @@ fn update_chunk_from_legacy(
     }
 }
 
-pub fn upgrade_chunks(
-    dim_id: &JavaStr,
-    generator_type: &JavaStr,
-    world_folder: &Path,
-    dimension: &Path,
-    to_version: u32,
-    dry_run: bool,
-) {
-    let _span = info_span!("Upgrading regions").entered();
+/// Whether the 1.18 chunk conversion does something it would otherwise decide based on the
+/// dimension and generator.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum RetrogenMode {
+    #[default]
+    Auto,
+    On,
+    Off,
+}
 
-    if !dry_run && to_version >= SEPARATE_ENTITIES_VERSION {
-        if let Err(err) = std::fs::create_dir(dimension.join("entities")) {
-            if err.kind() != ErrorKind::AlreadyExists {
-                error!("Failed to create entity region dir: {err}");
-            }
+impl RetrogenMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(RetrogenMode::Auto),
+            "on" => Some(RetrogenMode::On),
+            "off" => Some(RetrogenMode::Off),
+            _ => None,
         }
     }
+}
 
-    let legacy_structure_handler = OnceLock::new();
-
-    upgrade_regions::<RegionFolder>(
-        &dimension.join("region"),
-        dry_run,
-        |chunk_x, chunk_z, chunk, entity_region_folder| {
-            let version = chunk
-                .get("DataVersion")
-                .and_then(|v| v.as_i32())
-                .map(|v| v as u32)
-                .unwrap_or(99);
-            if version < LAST_MONOLITH_STRUCTURE_DATA_VERSION {
-                if !upgrade(
-                    types::chunk,
-                    chunk,
-                    || format!("chunk at {chunk_x}, {chunk_z}"),
-                    LAST_MONOLITH_STRUCTURE_DATA_VERSION.min(to_version),
-                    99,
-                ) {
-                    return false;
-                }
-                if to_version < LAST_MONOLITH_STRUCTURE_DATA_VERSION {
-                    return true;
+/// Overrides for whether pre-1.18 chunks are marked for blending with new terrain, and for
+/// generating the terrain below y=0.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RetrogenModes {
+    pub blending: RetrogenMode,
+    pub below_zero: RetrogenMode,
+}
+
+/// Retrogen modes for every dimension, or for specific ones.
+#[derive(Clone, Debug, Default)]
+pub struct RetrogenOverrides {
+    /// Modes along with the dimension they're for, or `None` if they're for all dimensions.
+    pub blending: Vec<(Option<JavaString>, RetrogenMode)>,
+    pub below_zero: Vec<(Option<JavaString>, RetrogenMode)>,
+}
+
+impl RetrogenOverrides {
+    /// The modes for a dimension. Only the overworld can be forced to retrogen, as the 1.18
+    /// conversion only knows how to do it for the overworld's layout.
+    pub fn for_dimension(&self, dim_id: &JavaStr) -> RetrogenModes {
+        // a mode given for the dimension takes precedence over one given for all dimensions
+        let resolve = |modes: &[(Option<JavaString>, RetrogenMode)]| {
+            let for_dimension = modes
+                .iter()
+                .rev()
+                .find(|(dim, _)| dim.as_deref() == Some(dim_id));
+            let for_all = modes.iter().rev().find(|(dim, _)| dim.is_none());
+            match for_dimension.or(for_all) {
+                Some((_, RetrogenMode::On)) if dim_id != "minecraft:overworld" => {
+                    RetrogenMode::Auto
                 }
-                update_chunk_from_legacy(dim_id, world_folder, &legacy_structure_handler, chunk);
+                Some((_, mode)) => *mode,
+                None => RetrogenMode::Auto,
             }
-            chunk.insert(
-                "__context",
-                jcompound! {
-                    "dimension" => dim_id,
-                    "generator" => generator_type,
-                },
-            );
-            if !upgrade(
+        };
+        RetrogenModes {
+            blending: resolve(&self.blending),
+            below_zero: resolve(&self.below_zero),
+        }
+    }
+}
+
+/// Upgrades the chunks in the region folder of a dimension, one at a time.
+pub struct ChunkUpgrader<'a> {
+    dim_id: &'a JavaStr,
+    generator_type: &'a JavaStr,
+    world_folder: &'a Path,
+    to_version: u32,
+    dry_run: bool,
+    retrogen: RetrogenModes,
+    unknown_ids: Option<&'a UnknownIds>,
+    uuid_index: Option<&'a UuidIndex>,
+    legacy_structure_handler: OnceLock<Option<LegacyStructureDataHandler>>,
+}
+
+impl<'a> ChunkUpgrader<'a> {
+    pub fn new(
+        dim_id: &'a JavaStr,
+        generator_type: &'a JavaStr,
+        world_folder: &'a Path,
+        to_version: u32,
+        dry_run: bool,
+    ) -> Self {
+        Self {
+            dim_id,
+            generator_type,
+            world_folder,
+            to_version,
+            dry_run,
+            retrogen: RetrogenModes::default(),
+            unknown_ids: None,
+            uuid_index: None,
+            legacy_structure_handler: OnceLock::new(),
+        }
+    }
+
+    /// Drops the structures of pre-1.13 chunks rather than looking up their legacy structure data,
+    /// for chunks which aren't being upgraded as part of a world.
+    pub fn skip_legacy_structures(&mut self) {
+        let _ = self.legacy_structure_handler.set(None);
+    }
+
+    pub fn set_retrogen_modes(&mut self, retrogen: RetrogenModes) {
+        self.retrogen = retrogen;
+    }
+
+    pub fn set_unknown_ids(&mut self, unknown_ids: Option<&'a UnknownIds>) {
+        self.unknown_ids = unknown_ids;
+    }
+
+    pub fn set_uuid_index(&mut self, uuid_index: Option<&'a UuidIndex>) {
+        self.uuid_index = uuid_index;
+    }
+
+    /// The `__context` the chunk converters read the dimension and generator from.
+    pub fn context(&self) -> JCompound {
+        jcompound! {
+            "dimension" => self.dim_id,
+            "generator" => self.generator_type,
+        }
+    }
+
+    /// Whether the overworld is made to look like a noise overworld to the 1.18 conversion, which
+    /// only marks chunks for blending and below-zero retrogen in one. The layout of the chunk only
+    /// depends on the dimension, so the generator is the only thing which is changed.
+    fn forces_retrogen(&self) -> bool {
+        self.dim_id == "minecraft:overworld"
+            && self.generator_type != "minecraft:noise"
+            && (self.retrogen.blending == RetrogenMode::On
+                || self.retrogen.below_zero == RetrogenMode::On)
+    }
+
+    /// The `__context` given to the chunk converters.
+    fn conversion_context(&self) -> JCompound {
+        let mut context = self.context();
+        if self.forces_retrogen() {
+            context.insert("generator", JavaStr::from_str("minecraft:noise"));
+        }
+        context
+    }
+
+    /// Removes the retrogen the 1.18 conversion added which is turned off, or which is on auto but
+    /// was only added because of a forced noise generator.
+    fn remove_disabled_retrogen(&self, chunk: &mut JCompound) {
+        let auto_off = |mode| {
+            mode == RetrogenMode::Off || (mode == RetrogenMode::Auto && self.forces_retrogen())
+        };
+        let chunk = if matches!(chunk.get("Level"), Some(JValue::Compound(_))) {
+            let Some(JValue::Compound(level)) = chunk.get_mut("Level") else {
+                unreachable!()
+            };
+            level
+        } else {
+            chunk
+        };
+        if auto_off(self.retrogen.blending) {
+            chunk.remove("blending_data");
+        }
+        if auto_off(self.retrogen.below_zero) {
+            chunk.remove("below_zero_retrogen");
+        }
+    }
+
+    /// Upgrades a chunk, extracting its entities into `entity_region_folder` if necessary. Returns
+    /// whether the chunk should be written back.
+    pub fn upgrade_chunk(
+        &self,
+        chunk_x: i32,
+        chunk_z: i32,
+        chunk: &mut JCompound,
+        entity_region_folder: &mut RegionFolder,
+    ) -> Result<bool, UpgradeFailure> {
+        let to_version = self.to_version;
+        let version = chunk
+            .get("DataVersion")
+            .and_then(|v| v.as_i32())
+            .map(|v| v as u32)
+            .unwrap_or(99);
+        if version < LAST_MONOLITH_STRUCTURE_DATA_VERSION {
+            try_upgrade(
                 types::chunk,
                 chunk,
                 || format!("chunk at {chunk_x}, {chunk_z}"),
-                to_version,
+                LAST_MONOLITH_STRUCTURE_DATA_VERSION.min(to_version),
                 99,
-            ) {
-                return false;
+            )?;
+            if to_version < LAST_MONOLITH_STRUCTURE_DATA_VERSION {
+                return Ok(true);
             }
-            chunk.remove("__context");
+            update_chunk_from_legacy(
+                self.dim_id,
+                self.world_folder,
+                &self.legacy_structure_handler,
+                chunk,
+            );
+        }
+        chunk.insert("__context", self.conversion_context());
+        try_upgrade(
+            types::chunk,
+            chunk,
+            || format!("chunk at {chunk_x}, {chunk_z}"),
+            to_version,
+            99,
+        )?;
+        chunk.remove("__context");
+        if version < CAVES_AND_CLIFFS_CHUNK_VERSION && to_version >= CAVES_AND_CLIFFS_CHUNK_VERSION
+        {
+            self.remove_disabled_retrogen(chunk);
+        }
+        unknown_ids::process_chunk(self.unknown_ids, self.dim_id, chunk_x, chunk_z, chunk);
+
+        if !self.dry_run
+            && version < SEPARATE_ENTITIES_VERSION
+            && to_version >= SEPARATE_ENTITIES_VERSION
+        {
+            // extract entities into separate region folder
+            let entities = if let Some(JValue::Compound(level)) = chunk.get_mut("Level") {
+                match level.get("Status") {
+                    Some(JValue::String(status)) if is_full(status) => level.remove("Entities"),
+                    _ => None,
+                }
+            } else {
+                match chunk.get("Status") {
+                    Some(JValue::String(status)) if is_full(status) => chunk.remove("entities"),
+                    _ => None,
+                }
+            };
+            if let Some(entities) = entities {
+                if !self.write_entity_chunk(chunk_x, chunk_z, entities, entity_region_folder) {
+                    return Ok(false);
+                }
+            }
+        }
 
-            if !dry_run
-                && version < SEPARATE_ENTITIES_VERSION
-                && to_version >= SEPARATE_ENTITIES_VERSION
-            {
-                // extract entities into separate region folder
-                if let Some(JValue::Compound(level)) = chunk.get_mut("Level") {
-                    if let Some(JValue::String(status)) = level.get("Status") {
-                        if status == "full" || status == "minecraft:full" {
-                            if let Some(entities) = level.remove("Entities") {
-                                if let Err(err) = entity_region_folder.set_chunk(
-                                    chunk_x,
-                                    chunk_z,
-                                    &jcompound! {
-                                        "Entities" => entities,
-                                    },
-                                ) {
-                                    error!(
-                                        "Error writing entity chunk {chunk_x}, {chunk_z}: {err}"
-                                    );
-                                    return false;
-                                }
-                            }
-                        }
-                    }
-                } else if let Some(JValue::String(status)) = chunk.get("Status") {
-                    if status == "full" || status == "minecraft:full" {
-                        if let Some(entities) = chunk.remove("entities") {
-                            if let Err(err) = entity_region_folder.set_chunk(
-                                chunk_x,
-                                chunk_z,
-                                &jcompound! {
-                                    "Entities" => entities,
-                                },
-                            ) {
-                                error!("Error writing entity chunk {chunk_x}, {chunk_z}: {err}");
-                                return false;
-                            }
-                        }
-                    }
+        if let Some(entities) = chunk_entities(chunk) {
+            index_entities(self.uuid_index, self.dim_id, chunk_x, chunk_z, entities);
+        }
+
+        Ok(true)
+    }
+
+    /// Writes entities split out of a chunk as an entity chunk, merging them into any entity chunk
+    /// already there. Returns whether the entity chunk was written.
+    fn write_entity_chunk(
+        &self,
+        chunk_x: i32,
+        chunk_z: i32,
+        entities: JValue,
+        entity_region_folder: &mut RegionFolder,
+    ) -> bool {
+        let mut entities = match entities {
+            JValue::List(JList::Compound(entities)) => entities,
+            _ => Vec::new(),
+        };
+
+        let existing: Option<RawChunk<JavaString>> =
+            match entity_region_folder.get_chunk(chunk_x, chunk_z) {
+                Ok(existing) => existing,
+                Err(err) => {
+                    error!("Error reading entity chunk {chunk_x}, {chunk_z}: {err}");
+                    return false;
                 }
+            };
+        let mut existing_entities = Vec::new();
+        if let Some(mut existing) = existing {
+            // the world has been opened in a newer version, whose copies of the entities win
+            if let Some(JValue::List(JList::Compound(existing))) = existing.data.remove("Entities")
+            {
+                existing_entities = existing;
+                let existing_uuids: AHashSet<_> =
+                    existing_entities.iter().filter_map(entity_uuid).collect();
+                entities.retain(|entity| {
+                    entity_uuid(entity).is_none_or(|uuid| !existing_uuids.contains(&uuid))
+                });
             }
+        }
+        // the existing entities were indexed when the entity chunks were upgraded
+        index_entities(
+            self.uuid_index,
+            self.dim_id,
+            chunk_x,
+            chunk_z,
+            &mut entities,
+        );
+        existing_entities.append(&mut entities);
+        let entities = existing_entities;
+
+        let entity_chunk = jcompound! {
+            "DataVersion" => self.to_version as i32,
+            "Position" => JValue::IntArray(vec![chunk_x, chunk_z]),
+            "Entities" => JList::Compound(entities),
+        };
+        if let Err(err) = entity_region_folder.set_chunk(chunk_x, chunk_z, &entity_chunk) {
+            error!("Error writing entity chunk {chunk_x}, {chunk_z}: {err}");
+            return false;
+        }
+        true
+    }
+}
+
+fn is_full(status: &JavaStr) -> bool {
+    status == "full" || status == "minecraft:full"
+}
+
+/// The generation statuses of chunks in the order they're reached, each along with the names it had
+/// before 1.14.
+const CHUNK_STATUSES: [&[&str]; 14] = [
+    &["empty"],
+    &["structure_starts"],
+    &["structure_references"],
+    &["biomes"],
+    &["noise"],
+    &["surface"],
+    &["carvers", "carved"],
+    &["liquid_carvers", "liquid_carved"],
+    &["features", "decorated"],
+    &["initialize_light"],
+    &["light", "lighted"],
+    &["spawn", "mobs_spawned"],
+    &["heightmaps", "finalized"],
+    &["full", "fullchunk", "postprocessed"],
+];
+
+/// How far a chunk has got through world generation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ChunkStatus(usize);
+
+impl ChunkStatus {
+    pub fn from_name(name: &JavaStr) -> Option<Self> {
+        let name = name.strip_prefix("minecraft:").unwrap_or(name);
+        CHUNK_STATUSES
+            .iter()
+            .position(|names| names.iter().any(|&status| name == status))
+            .map(ChunkStatus)
+    }
+
+    /// The status of a chunk, or `None` if it doesn't record one, as is the case before 1.13.
+    pub fn of_chunk(chunk: &JCompound) -> Option<Self> {
+        let level = match chunk.get("Level") {
+            Some(JValue::Compound(level)) => level,
+            _ => chunk,
+        };
+        match level.get("Status") {
+            Some(JValue::String(status)) => Self::from_name(status),
+            _ => None,
+        }
+    }
+}
+
+/// Upgrades the region folder of a dimension using the given chunk upgrader.
+pub fn chunks_task<'a>(chunk_upgrader: ChunkUpgrader<'a>, dimension: &Path) -> RegionTask<'a> {
+    let to_version = chunk_upgrader.to_version;
+    let entities_path = dimension.join("entities");
+    if !chunk_upgrader.dry_run && to_version >= SEPARATE_ENTITIES_VERSION {
+        if let Err(err) = std::fs::create_dir(&entities_path) {
+            if err.kind() != ErrorKind::AlreadyExists {
+                error!("Failed to create entity region dir: {err}");
+            }
+        }
+    }
 
-            true
+    let dim_id = chunk_upgrader.dim_id;
+    let unknown_ids = chunk_upgrader.unknown_ids;
+    let uuid_index = chunk_upgrader.uuid_index;
+    RegionTask {
+        regions_path: dimension.join("region"),
+        kind: ChunkKind {
+            type_name: "chunk",
+            default_version: 99,
+            context: Some(chunk_upgrader.context()),
         },
-        || RegionFolder::new(dimension.join("entities")),
-    );
+        to_version,
+        span: info_span!("Upgrading regions"),
+        run_after: None,
+        world_border: None,
+        do_update: Box::new(move |chunk_x, chunk_z, chunk, state| {
+            let entity_region_folder = state
+                .entity_region_folder
+                .get_or_insert_with(|| RegionFolder::new(&entities_path));
+            chunk_upgrader.upgrade_chunk(chunk_x, chunk_z, chunk, entity_region_folder)
+        }),
+        scan_up_to_date: Box::new(move |chunk_x, chunk_z, chunk| {
+            let remapped = unknown_ids::process_chunk(unknown_ids, dim_id, chunk_x, chunk_z, chunk);
+            let reassigned = chunk_entities(chunk).is_some_and(|entities| {
+                index_entities(uuid_index, dim_id, chunk_x, chunk_z, entities)
+            });
+            remapped || reassigned
+        }),
+    }
+}
+
+/// The entities still stored in a chunk, from before they were split out in 20w45a.
+fn chunk_entities(chunk: &mut JCompound) -> Option<&mut Vec<JCompound>> {
+    let entities = if let Some(JValue::Compound(level)) = chunk.get_mut("Level") {
+        level.get_mut("Entities")
+    } else {
+        chunk.get_mut("entities")
+    };
+    match entities {
+        Some(JValue::List(JList::Compound(entities))) => Some(entities),
+        _ => None,
+    }
 }
 
 fn delete_legacy_dat_file(world_folder: &Path, key: &JavaStr) {
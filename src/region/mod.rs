@@ -1,169 +1,1155 @@
+mod check;
 mod chunk;
+mod raw;
+mod salvage;
+mod uuids;
 
-use crate::upgrade;
-use java_string::JavaString;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use crate::quarantine::Quarantine;
+use crate::region::check::{chunk_position, set_chunk_position, RegionFolderKind};
+use crate::region::salvage::salvage_chunk;
+use crate::region::uuids::index_entities;
+use crate::unknown_ids::UnknownIds;
+use crate::{
+    data_type_by_name, is_up_to_date, quarantine, try_upgrade, unknown_ids, UpgradeFailure,
+};
+use java_string::{JavaStr, JavaString};
+use rayon::iter::{ParallelBridge, ParallelIterator};
 use std::collections::HashMap;
+use std::fmt::Display;
 use std::io::ErrorKind;
-use std::path::Path;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use tracing::{error, info, info_span, Span};
-use valence_anvil::{RawChunk, RegionError, RegionFolder};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use tracing::{error, info, info_span, warn, Span};
+use valence_anvil::{RawChunk, RegionFolder};
 use world_transmuter::types;
-use world_transmuter_engine::JCompound;
+use world_transmuter_engine::{JCompound, JList, JValue};
 
-pub use chunk::{delete_legacy_dat_files, upgrade_chunks};
+pub use check::check_regions;
+pub use chunk::{
+    chunks_task, delete_legacy_dat_files, ChunkStatus, ChunkUpgrader, RetrogenMode, RetrogenModes,
+    RetrogenOverrides,
+};
+pub use uuids::{log_duplicate_uuids, DuplicateUuids, UuidIndex};
 
 const SEPARATE_ENTITIES_VERSION: u32 = 2681; // 20w45a
 const FIRST_POI_VERSION: u32 = 1937; // 19w11a
 
-pub fn upgrade_entities(dimension: &Path, to_version: u32, dry_run: bool) {
+/// Describes the chunks in a region folder, so that they can be retried from the quarantine.
+struct ChunkKind {
+    type_name: &'static str,
+    default_version: u32,
+    context: Option<JCompound>,
+}
+
+/// Options controlling how the chunks in region files are processed.
+#[derive(Default)]
+pub struct RegionOptions {
+    /// Try to recover what can be read from chunks that fail to load, rather than skipping them.
+    pub salvage: bool,
+    /// Delete chunks which can't be salvaged, so that the game regenerates them.
+    pub delete_unrecoverable: bool,
+    /// The number of threads reading and decompressing region files for the conversion workers.
+    pub io_threads: usize,
+    /// Roughly how many bytes of region data may be held in memory at once.
+    pub memory_limit: Option<u64>,
+    /// Delete chunks which have been inhabited by players for fewer than this many ticks, along
+    /// with their entity and poi chunks, so that they regenerate.
+    pub prune_inhabited_below: Option<i64>,
+    /// Delete chunks which lie entirely outside the world border, along with their entity and poi
+    /// chunks.
+    pub prune_outside_border: bool,
+    /// Delete proto-chunks which haven't reached this status, along with their entity and poi
+    /// chunks, so that they regenerate rather than blending with new world generation.
+    pub drop_proto_chunks_below: Option<ChunkStatus>,
+    /// Overrides for how pre-1.18 chunks are prepared for new terrain, per dimension.
+    pub retrogen: RetrogenOverrides,
+    /// What to do with chunks whose recorded position doesn't match the slot they're stored in,
+    /// which are only reported if this is `None`.
+    pub wrong_slot_chunks: Option<WrongSlotAction>,
+    /// Where to copy chunks which fail to upgrade.
+    pub quarantine: Option<Quarantine>,
+    /// Collects and remaps the IDs in chunks which the target version doesn't know about.
+    pub unknown_ids: Option<UnknownIds>,
+    /// Finds entities which share a UUID with one in another chunk.
+    pub uuid_index: Option<UuidIndex>,
+}
+
+/// What to do with a chunk whose recorded position doesn't match the slot it's stored in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WrongSlotAction {
+    /// Move the chunk to the slot of its recorded position, unless there's a chunk there already.
+    Move,
+    /// Change the recorded position to match the slot.
+    Rewrite,
+    Drop,
+}
+
+/// How much memory a region is assumed to take up once its chunks have been read, relative to the
+/// size of its region file. Chunk NBT usually shrinks four to six times under zlib, and once parsed
+/// each tag is its own allocation, which roughly doubles it again. This is an estimate, so the
+/// memory limit is only a rough ceiling.
+const REGION_MEMORY_FACTOR: u64 = 10;
+
+/// State shared between the chunks of a single region while they are upgraded.
+#[derive(Default)]
+pub struct RegionState {
+    entity_region_folder: Option<RegionFolder>,
+}
+
+type ChunkUpdateFn<'a> = dyn Fn(i32, i32, &mut JCompound, &mut RegionState) -> Result<bool, UpgradeFailure>
+    + Send
+    + Sync
+    + 'a;
+
+/// Checks a chunk which is already at the target version for anything which is done to chunks as
+/// they're upgraded, such as fixing duplicate entity UUIDs. Returns whether it changed the chunk.
+type ChunkScanFn<'a> = dyn Fn(i32, i32, &mut JCompound) -> bool + Send + Sync + 'a;
+
+/// A region folder to upgrade, along with how to upgrade each of its chunks.
+pub struct RegionTask<'a> {
+    regions_path: PathBuf,
+    kind: ChunkKind,
+    to_version: u32,
+    span: Span,
+    run_after: Option<usize>,
+    world_border: Option<WorldBorder>,
+    do_update: Box<ChunkUpdateFn<'a>>,
+    scan_up_to_date: Box<ChunkScanFn<'a>>,
+}
+
+impl<'a> RegionTask<'a> {
+    /// Makes each region of this task wait until the region at the same position in the task at
+    /// `index` has been written.
+    pub fn run_after(&mut self, index: usize) {
+        self.run_after = Some(index);
+    }
+
+    /// Deletes the chunks of this task which lie entirely outside the given world border, along with
+    /// their entity and poi chunks.
+    pub fn prune_outside(&mut self, world_border: WorldBorder) {
+        self.world_border = Some(world_border);
+    }
+}
+
+/// The square area inside a world border, in block coordinates.
+#[derive(Copy, Clone, Debug)]
+pub struct WorldBorder {
+    pub min_x: f64,
+    pub min_z: f64,
+    pub max_x: f64,
+    pub max_z: f64,
+}
+
+impl WorldBorder {
+    fn contains_chunk(&self, chunk_x: i32, chunk_z: i32) -> bool {
+        let (block_x, block_z) = (chunk_x as f64 * 16.0, chunk_z as f64 * 16.0);
+        block_x + 16.0 > self.min_x
+            && block_x < self.max_x
+            && block_z + 16.0 > self.min_z
+            && block_z < self.max_z
+    }
+}
+
+pub fn entities_task<'a>(
+    dim_id: &'a JavaStr,
+    dimension: &Path,
+    to_version: u32,
+    options: &'a RegionOptions,
+) -> Option<RegionTask<'a>> {
     if to_version < SEPARATE_ENTITIES_VERSION {
-        return;
+        return None;
     }
 
-    let _span = info_span!("Upgrading entities").entered();
-    upgrade_regions(
-        &dimension.join("entities"),
-        dry_run,
-        |chunk_x, chunk_z, chunk, _| {
-            upgrade(
+    Some(entity_chunks_task(
+        dim_id,
+        dimension.join("entities"),
+        to_version,
+        options,
+    ))
+}
+
+fn entity_chunks_task<'a>(
+    dim_id: &'a JavaStr,
+    regions_path: PathBuf,
+    to_version: u32,
+    options: &'a RegionOptions,
+) -> RegionTask<'a> {
+    RegionTask {
+        regions_path,
+        kind: ChunkKind {
+            type_name: "entity_chunk",
+            default_version: SEPARATE_ENTITIES_VERSION,
+            context: None,
+        },
+        to_version,
+        span: info_span!("Upgrading entities"),
+        run_after: None,
+        world_border: None,
+        do_update: Box::new(move |chunk_x, chunk_z, chunk, _| {
+            try_upgrade(
                 types::entity_chunk,
                 chunk,
                 || format!("chunk at {chunk_x}, {chunk_z}"),
                 to_version,
                 SEPARATE_ENTITIES_VERSION,
-            )
-        },
-        || (),
-    );
+            )?;
+            unknown_ids::process_entity_chunk(
+                options.unknown_ids.as_ref(),
+                dim_id,
+                chunk_x,
+                chunk_z,
+                chunk,
+            );
+            if let Some(JValue::List(JList::Compound(entities))) = chunk.get_mut("Entities") {
+                index_entities(
+                    options.uuid_index.as_ref(),
+                    dim_id,
+                    chunk_x,
+                    chunk_z,
+                    entities,
+                );
+            }
+            Ok(true)
+        }),
+        scan_up_to_date: Box::new(move |chunk_x, chunk_z, chunk| {
+            let remapped = unknown_ids::process_entity_chunk(
+                options.unknown_ids.as_ref(),
+                dim_id,
+                chunk_x,
+                chunk_z,
+                chunk,
+            );
+            let reassigned = match chunk.get_mut("Entities") {
+                Some(JValue::List(JList::Compound(entities))) => index_entities(
+                    options.uuid_index.as_ref(),
+                    dim_id,
+                    chunk_x,
+                    chunk_z,
+                    entities,
+                ),
+                _ => false,
+            };
+            remapped || reassigned
+        }),
+    }
 }
 
-pub fn upgrade_poi(dimension: &Path, to_version: u32, dry_run: bool) {
+pub fn poi_task(dimension: &Path, to_version: u32) -> Option<RegionTask<'static>> {
     if to_version < FIRST_POI_VERSION {
-        return;
+        return None;
     }
 
-    let _span = info_span!("Upgrading poi").entered();
-
     let poi_path = dimension.join("poi");
     match poi_path.try_exists() {
-        Ok(true) => {
-            upgrade_regions(
-                &poi_path,
-                dry_run,
-                |chunk_x, chunk_z, chunk, _| {
-                    upgrade(
-                        types::poi_chunk,
-                        chunk,
-                        || format!("chunk at {chunk_x}, {chunk_z}"),
-                        to_version,
-                        FIRST_POI_VERSION,
-                    )
-                },
-                || (),
+        Ok(true) => {}
+        Ok(false) => return None,
+        Err(err) => {
+            error!("Error checking if poi exists, skipping: {err}");
+            return None;
+        }
+    }
+
+    Some(poi_chunks_task(poi_path, to_version))
+}
+
+fn poi_chunks_task(regions_path: PathBuf, to_version: u32) -> RegionTask<'static> {
+    RegionTask {
+        regions_path,
+        kind: ChunkKind {
+            type_name: "poi_chunk",
+            default_version: FIRST_POI_VERSION,
+            context: None,
+        },
+        to_version,
+        span: info_span!("Upgrading poi"),
+        run_after: None,
+        world_border: None,
+        do_update: Box::new(move |chunk_x, chunk_z, chunk, _| {
+            try_upgrade(
+                types::poi_chunk,
+                chunk,
+                || format!("chunk at {chunk_x}, {chunk_z}"),
+                to_version,
+                FIRST_POI_VERSION,
             )
+            .map(|_| true)
+        }),
+        scan_up_to_date: Box::new(|_, _, _| false),
+    }
+}
+
+/// Upgrades the chunks of a single region file in place, outside of a world. Entities split out of
+/// chunks are written to an `entities` folder next to the folder the region file is in. The legacy
+/// structure data of pre-1.13 chunks is read from `world`, if there is one.
+pub fn upgrade_region_file(
+    region_file: &Path,
+    type_name: &str,
+    dim_id: &JavaStr,
+    generator_type: &JavaStr,
+    world: Option<&Path>,
+    to_version: u32,
+) -> bool {
+    let Some(region_pos) = region_file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(raw::parse_region_file_name)
+    else {
+        error!(
+            "{} isn't named like a region file (r.<x>.<z>.mca)",
+            region_file.to_string_lossy()
+        );
+        return false;
+    };
+    let regions_path = match region_file.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let dimension = regions_path.parent().unwrap_or(regions_path);
+
+    let options = RegionOptions::default();
+    let mut task = match type_name {
+        "chunk" => {
+            let mut chunk_upgrader = ChunkUpgrader::new(
+                dim_id,
+                generator_type,
+                world.unwrap_or(dimension),
+                to_version,
+                false,
+            );
+            if world.is_none() {
+                chunk_upgrader.skip_legacy_structures();
+            }
+            chunks_task(chunk_upgrader, dimension)
+        }
+        "entity_chunk" => entity_chunks_task(dim_id, PathBuf::new(), to_version, &options),
+        "poi_chunk" => poi_chunks_task(PathBuf::new(), to_version),
+        _ => {
+            error!("Region files can't contain {type_name} data");
+            return false;
         }
-        Ok(false) => {}
+    };
+    task.regions_path = regions_path.to_path_buf();
+
+    let stats = RegionStats::default();
+    let _span = task.span.enter();
+    let region = read_region(
+        &task.regions_path,
+        region_pos,
+        false,
+        &options,
+        &task.kind,
+        &stats,
+    );
+    upgrade_region(&task, region, false, &options, &stats);
+    stats.log(&task.regions_path, &options);
+    stats.errors.load(Ordering::Acquire) == 0
+}
+
+/// A single region of a task, waiting to be upgraded.
+struct RegionJob {
+    task: usize,
+    region_pos: (i32, i32),
+    file_size: u64,
+    run_after: Option<usize>,
+    done: Latch,
+}
+
+#[derive(Default)]
+struct Latch {
+    done: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Latch {
+    fn set(&self) {
+        *self.done.lock().unwrap() = true;
+        self.condvar.notify_all();
+    }
+
+    fn wait(&self) {
+        let mut done = self.done.lock().unwrap();
+        while !*done {
+            done = self.condvar.wait(done).unwrap();
+        }
+    }
+}
+
+/// Limits how much region data is held in memory at once, between being read by an I/O thread and
+/// written back by a conversion worker.
+struct MemoryBudget {
+    limit: u64,
+    used: Mutex<u64>,
+    condvar: Condvar,
+    disabled: AtomicBool,
+}
+
+impl MemoryBudget {
+    fn new(limit: Option<u64>) -> Self {
+        Self {
+            limit: limit.unwrap_or(u64::MAX),
+            used: Mutex::new(0),
+            condvar: Condvar::new(),
+            disabled: AtomicBool::new(false),
+        }
+    }
+
+    /// Waits until the given amount of memory is available, returning how much was reserved. A
+    /// region bigger than the whole budget is allowed through on its own.
+    fn reserve(&self, amount: u64) -> u64 {
+        let amount = amount.min(self.limit);
+        let mut used = self.used.lock().unwrap();
+        while *used + amount > self.limit && !self.disabled.load(Ordering::Relaxed) {
+            used = self.condvar.wait(used).unwrap();
+        }
+        *used += amount;
+        amount
+    }
+
+    fn release(&self, amount: u64) {
+        let mut used = self.used.lock().unwrap();
+        *used = used.saturating_sub(amount);
+        self.condvar.notify_all();
+    }
+
+    /// Lets everything through from now on.
+    fn disable(&self) {
+        let _used = self.used.lock().unwrap();
+        self.disabled.store(true, Ordering::Relaxed);
+        self.condvar.notify_all();
+    }
+}
+
+/// Releases every job and the memory budget when dropped, so that I/O threads waiting on a job
+/// which will never be processed (because a worker panicked) don't hang forever.
+struct ReleaseJobs<'a>(&'a [RegionJob], &'a MemoryBudget);
+
+impl Drop for ReleaseJobs<'_> {
+    fn drop(&mut self) {
+        for job in self.0 {
+            job.done.set();
+        }
+        self.1.disable();
+    }
+}
+
+/// Upgrades the regions of all the given tasks through a single work queue, so that threads don't
+/// sit idle at the end of a small region folder.
+pub fn upgrade_region_tasks(tasks: &[RegionTask], dry_run: bool, options: &RegionOptions) {
+    let mut jobs = Vec::new();
+    for (task_index, task) in tasks.iter().enumerate() {
+        let _span = task.span.enter();
+        for (region_pos, file_size) in list_regions(&task.regions_path) {
+            jobs.push(RegionJob {
+                task: task_index,
+                region_pos,
+                file_size,
+                run_after: None,
+                done: Latch::default(),
+            });
+        }
+    }
+
+    // Jobs are handed out in order, so putting the jobs other jobs wait on first guarantees that
+    // anything being waited on has already been picked up by an I/O thread.
+    jobs.sort_by_key(|job| tasks[job.task].run_after.is_some());
+    let job_indices: HashMap<_, _> = jobs
+        .iter()
+        .enumerate()
+        .map(|(index, job)| ((job.task, job.region_pos), index))
+        .collect();
+    for job in &mut jobs {
+        job.run_after = tasks[job.task]
+            .run_after
+            .and_then(|task| job_indices.get(&(task, job.region_pos)).copied());
+    }
+
+    // A few I/O threads read and decompress whole regions and hand them to the conversion workers
+    // through a bounded channel, so memory use doesn't grow with the world size. Waiting for other
+    // jobs and for memory happens on the I/O threads, so that the conversion workers never block.
+    let next_job = AtomicUsize::new(0);
+    let stats: Vec<_> = tasks.iter().map(|_| RegionStats::default()).collect();
+    let memory_budget = MemoryBudget::new(options.memory_limit);
+    let io_threads = options.io_threads.max(1);
+    let (sender, receiver) = mpsc::sync_channel(io_threads);
+    std::thread::scope(|scope| {
+        let _release_jobs = ReleaseJobs(&jobs, &memory_budget);
+        for _ in 0..io_threads {
+            let sender = sender.clone();
+            let (jobs, next_job, stats, memory_budget) = (&jobs, &next_job, &stats, &memory_budget);
+            scope.spawn(move || loop {
+                let job_index = next_job.fetch_add(1, Ordering::Relaxed);
+                let Some(job) = jobs.get(job_index) else {
+                    break;
+                };
+                if let Some(run_after) = job.run_after {
+                    jobs[run_after].done.wait();
+                }
+                let reserved = memory_budget.reserve(job.file_size * REGION_MEMORY_FACTOR);
+                let task = &tasks[job.task];
+                let region = task.span.in_scope(|| {
+                    read_region(
+                        &task.regions_path,
+                        job.region_pos,
+                        dry_run,
+                        options,
+                        &task.kind,
+                        &stats[job.task],
+                    )
+                });
+                if sender.send((job_index, region, reserved)).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        receiver
+            .into_iter()
+            .par_bridge()
+            .for_each(|(job_index, region, reserved)| {
+                let job = &jobs[job_index];
+                let task = &tasks[job.task];
+                task.span
+                    .in_scope(|| upgrade_region(task, region, dry_run, options, &stats[job.task]));
+                memory_budget.release(reserved);
+                job.done.set();
+            });
+    });
+
+    for (task, stats) in tasks.iter().zip(&stats) {
+        let _span = task.span.enter();
+        // chunks are only moved once nothing else can be writing to the regions they move into
+        if !dry_run {
+            let folder_kind = RegionFolderKind::from_type_name(task.kind.type_name);
+            stats.move_chunks(&task.regions_path, folder_kind);
+            stats.prune_counterparts(&task.regions_path);
+        }
+        stats.log(&task.regions_path, options);
+    }
+}
+
+/// Lists the region files in a region folder along with their sizes. The chunks in each region are
+/// only listed once it's read, so that nothing about individual chunks is held in memory up front.
+fn list_regions(regions_path: &Path) -> Vec<((i32, i32), u64)> {
+    let dir = match std::fs::read_dir(regions_path) {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Vec::new(),
         Err(err) => {
-            error!("Error checking if poi exists, skipping: {err}");
+            error!("Error listing region files: {err}");
+            return Vec::new();
         }
     };
+
+    let mut regions = Vec::new();
+    for entry in dir {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                error!("Error listing region files: {err}");
+                continue;
+            }
+        };
+        let Some(region_pos) = entry
+            .file_name()
+            .to_str()
+            .and_then(raw::parse_region_file_name)
+        else {
+            continue;
+        };
+        match entry.metadata() {
+            // an empty region file has no chunks in it
+            Ok(metadata) if metadata.len() == 0 => {}
+            Ok(metadata) => regions.push((region_pos, metadata.len())),
+            Err(err) => error!(
+                "Error reading region file {}: {}",
+                entry.path().to_string_lossy(),
+                err
+            ),
+        }
+    }
+    info!("Found {} region files", regions.len());
+    regions
 }
 
-fn upgrade_regions<S>(
-    regions_path: &Path,
+fn upgrade_region(
+    task: &RegionTask,
+    region: Vec<(i32, i32, JCompound, bool)>,
     dry_run: bool,
-    do_update: impl Send + Sync + Fn(i32, i32, &mut JCompound, &mut S) -> bool,
-    thread_local_state_init: impl Send + Sync + Fn() -> S,
+    options: &RegionOptions,
+    stats: &RegionStats,
 ) {
-    // figure out which chunks exist
-    info!("Counting chunks");
-    let mut region_folder = RegionFolder::new(regions_path);
-    let mut num_errors: usize = 0;
-    let chunk_positions: Vec<_> = match region_folder.all_chunk_positions() {
-        Ok(chunk_positions_iter) => chunk_positions_iter
-            .filter_map(|pos| match pos {
-                Ok(pos) => Some(pos),
+    let mut region_folder = RegionFolder::new(&task.regions_path);
+    let mut state = RegionState::default();
+    let folder_kind = RegionFolderKind::from_type_name(task.kind.type_name);
+    for (chunk_x, chunk_z, mut chunk, salvaged) in region {
+        let mut position_rewritten = false;
+        if let Some(position) =
+            chunk_position(&chunk, folder_kind).filter(|&position| position != (chunk_x, chunk_z))
+        {
+            stats.wrong_slot.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                "Chunk in the slot for {chunk_x}, {chunk_z} says it's at {}, {}",
+                position.0, position.1
+            );
+            match options.wrong_slot_chunks {
+                None => {}
+                Some(WrongSlotAction::Move) => {
+                    stats
+                        .pending_moves
+                        .lock()
+                        .unwrap()
+                        .push(((chunk_x, chunk_z), position));
+                }
+                Some(WrongSlotAction::Rewrite) => {
+                    set_chunk_position(&mut chunk, folder_kind, (chunk_x, chunk_z));
+                    position_rewritten = true;
+                    stats.rewritten.fetch_add(1, Ordering::Relaxed);
+                }
+                Some(WrongSlotAction::Drop) => {
+                    if !dry_run {
+                        if let Err(err) = region_folder.delete_chunk(chunk_x, chunk_z) {
+                            error!("Error deleting chunk at {chunk_x}, {chunk_z}: {err}");
+                            stats.errors.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        }
+                    }
+                    stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    if folder_kind == RegionFolderKind::Chunks {
+                        stats
+                            .dropped_positions
+                            .lock()
+                            .unwrap()
+                            .push((chunk_x, chunk_z));
+                    }
+                    continue;
+                }
+            }
+        }
+
+        if folder_kind == RegionFolderKind::Chunks
+            && should_prune(task, options, chunk_x, chunk_z, &chunk)
+        {
+            let size = match raw::chunk_sectors_size(&task.regions_path, chunk_x, chunk_z) {
+                Ok(size) => size,
                 Err(err) => {
-                    error!("Error listing chunks: {err}");
-                    num_errors += 1;
-                    None
+                    error!("Error reading header of chunk at {chunk_x}, {chunk_z}: {err}");
+                    0
                 }
-            })
-            .collect(),
-        Err(RegionError::Io(err)) if err.kind() == ErrorKind::NotFound => Vec::new(),
-        Err(err) => {
-            error!("Error listing chunks: {err}");
-            return;
+            };
+            if !dry_run {
+                if let Err(err) = region_folder.delete_chunk(chunk_x, chunk_z) {
+                    error!("Error pruning chunk at {chunk_x}, {chunk_z}: {err}");
+                    continue;
+                }
+            }
+            stats.pruned_size.fetch_add(size, Ordering::Relaxed);
+            stats.pruned.lock().unwrap().push((chunk_x, chunk_z));
+            continue;
+        }
+
+        if is_up_to_date(&chunk, task.to_version) {
+            stats.up_to_date.fetch_add(1, Ordering::Relaxed);
+            let scan_changed = (task.scan_up_to_date)(chunk_x, chunk_z, &mut chunk);
+            // a salvaged chunk is written back even if it's up to date, replacing the corrupt one
+            if (position_rewritten || salvaged || scan_changed) && !dry_run {
+                if let Err(err) = region_folder.set_chunk(chunk_x, chunk_z, &chunk) {
+                    error!("Error writing chunk at {chunk_x}, {chunk_z}: {err}");
+                }
+            }
+            continue;
+        }
+        match (task.do_update)(chunk_x, chunk_z, &mut chunk, &mut state) {
+            Ok(true) => {
+                if !dry_run {
+                    if let Err(err) = region_folder.set_chunk(chunk_x, chunk_z, &chunk) {
+                        error!("Error writing chunk at {chunk_x}, {chunk_z}: {err}");
+                    }
+                }
+            }
+            Ok(false) => {}
+            Err(failure) => {
+                if failure.should_quarantine() {
+                    quarantine_chunk(
+                        options,
+                        &task.regions_path,
+                        chunk_x,
+                        chunk_z,
+                        &task.kind,
+                        &failure,
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct RegionStats {
+    up_to_date: AtomicUsize,
+    errors: AtomicUsize,
+    salvaged: AtomicUsize,
+    unrecoverable: AtomicUsize,
+    wrong_slot: AtomicUsize,
+    rewritten: AtomicUsize,
+    dropped: AtomicUsize,
+    moved: AtomicUsize,
+    /// Chunks to move from their slot to the one for their recorded position, once every region has
+    /// been upgraded.
+    pending_moves: Mutex<Vec<((i32, i32), (i32, i32))>>,
+    /// Chunks which were pruned, whose entity and poi chunks are deleted once every region has been
+    /// upgraded.
+    pruned: Mutex<Vec<(i32, i32)>>,
+    /// Chunks in the wrong slot which were dropped, whose entity and poi chunks are deleted along
+    /// with those of the pruned chunks.
+    dropped_positions: Mutex<Vec<(i32, i32)>>,
+    /// The bytes of region file sectors freed by pruning.
+    pruned_size: AtomicU64,
+}
+
+fn should_prune(
+    task: &RegionTask,
+    options: &RegionOptions,
+    chunk_x: i32,
+    chunk_z: i32,
+    chunk: &JCompound,
+) -> bool {
+    if task
+        .world_border
+        .is_some_and(|border| !border.contains_chunk(chunk_x, chunk_z))
+    {
+        return true;
+    }
+    if let Some(min_status) = options.drop_proto_chunks_below {
+        if ChunkStatus::of_chunk(chunk).is_some_and(|status| status < min_status) {
+            return true;
         }
+    }
+    let Some(min_inhabited_time) = options.prune_inhabited_below else {
+        return false;
+    };
+    let level = match chunk.get("Level") {
+        Some(JValue::Compound(level)) => level,
+        _ => chunk,
     };
-    drop(region_folder);
-    if num_errors > 0 {
-        error!("Found {num_errors} errors listing chunks");
-    }
-
-    let _span = info_span!(
-        "Upgrading chunks",
-        message = format!("count = {}", chunk_positions.len())
-    )
-    .entered();
-
-    // partition the chunks into regions to make sure that region files are not overwritten concurrently
-    let mut partitioned_chunks = HashMap::<(i32, i32), Vec<(i32, i32)>>::new();
-    for chunk_pos @ (chunk_x, chunk_z) in chunk_positions {
-        partitioned_chunks
-            .entry((chunk_x >> 5, chunk_z >> 5))
-            .or_default()
-            .push(chunk_pos);
-    }
-
-    // upgrade the chunks
-    let num_errors = AtomicUsize::new(0);
-    let parent_span = Span::current();
-    partitioned_chunks
-        .into_values()
-        .collect::<Vec<_>>()
-        .into_par_iter()
-        .for_each_init(
-            move || {
-                (
-                    RegionFolder::new(regions_path),
-                    thread_local_state_init(),
-                    parent_span.clone().entered(),
-                )
-            },
-            |(region_folder, thread_local_state, _), chunks| {
-                for (chunk_x, chunk_z) in chunks {
-                    let mut chunk_nbt: RawChunk<JavaString> =
-                        match region_folder.get_chunk(chunk_x, chunk_z) {
-                            Ok(Some(chunk_nbt)) => chunk_nbt,
-                            Ok(None) => {
-                                // all chunk positions listed the chunk, but it wasn't found when we tried to get it
-                                num_errors.fetch_add(1, Ordering::Relaxed);
-                                continue;
-                            }
-                            Err(err) => {
-                                error!("Error reading chunk at {chunk_x}, {chunk_z}: {err}");
-                                num_errors.fetch_add(1, Ordering::Relaxed);
-                                continue;
-                            }
-                        };
-
-                    if do_update(chunk_x, chunk_z, &mut chunk_nbt.data, thread_local_state)
-                        && !dry_run
-                    {
-                        if let Err(err) = region_folder.set_chunk(chunk_x, chunk_z, &chunk_nbt.data)
-                        {
-                            error!("Error writing chunk at {chunk_x}, {chunk_z}: {err}");
+    let inhabited_time = level
+        .get("InhabitedTime")
+        .and_then(|time| time.as_i64())
+        .unwrap_or(0);
+    inhabited_time < min_inhabited_time
+}
+
+impl RegionStats {
+    fn log(&self, regions_path: &Path, options: &RegionOptions) {
+        let num_up_to_date = self.up_to_date.load(Ordering::Acquire);
+        if num_up_to_date > 0 {
+            info!("Skipped {num_up_to_date} chunks already at the target version");
+        }
+        let num_errors = self.errors.load(Ordering::Acquire);
+        if num_errors > 0 {
+            error!("Encountered {num_errors} errors while upgrading chunks");
+        }
+        let num_salvaged = self.salvaged.load(Ordering::Acquire);
+        if num_salvaged > 0 {
+            if options.quarantine.is_some() {
+                warn!(
+                    "Salvaged {num_salvaged} chunks, some of their data may be missing. Their original data was quarantined"
+                );
+            } else {
+                warn!(
+                    "Salvaged {num_salvaged} chunks, some of their data may be missing. Their original data was saved to {}",
+                    salvaged_chunks_path(regions_path).to_string_lossy()
+                );
+            }
+        }
+        let num_wrong_slot = self.wrong_slot.load(Ordering::Acquire);
+        if num_wrong_slot > 0 {
+            warn!("Found {num_wrong_slot} chunks whose position doesn't match their slot");
+        }
+        let num_moved = self.moved.load(Ordering::Acquire);
+        if num_moved > 0 {
+            info!("Moved {num_moved} chunks to the slot for their position");
+        }
+        let num_rewritten = self.rewritten.load(Ordering::Acquire);
+        if num_rewritten > 0 {
+            info!("Changed the position of {num_rewritten} chunks to match their slot");
+        }
+        let num_dropped = self.dropped.load(Ordering::Acquire);
+        if num_dropped > 0 {
+            info!("Dropped {num_dropped} chunks in the wrong slot");
+        }
+        let num_pruned = self.pruned.lock().unwrap().len();
+        if num_pruned > 0 {
+            info!(
+                "Pruned {num_pruned} chunks, freeing {:.1} MiB of region file sectors",
+                self.pruned_size.load(Ordering::Acquire) as f64 / (1024.0 * 1024.0)
+            );
+        }
+        let num_unrecoverable = self.unrecoverable.load(Ordering::Acquire);
+        if num_unrecoverable > 0 {
+            if options.quarantine.is_some() {
+                error!(
+                    "{num_unrecoverable} chunks could not be salvaged, their raw data was quarantined"
+                );
+            } else {
+                error!(
+                    "{num_unrecoverable} chunks could not be salvaged, their raw data was saved to {}",
+                    unrecoverable_chunks_path(regions_path).to_string_lossy()
+                );
+            }
+        }
+    }
+
+    /// Deletes the entity and poi chunks of the chunks which were pruned or dropped.
+    fn prune_counterparts(&self, regions_path: &Path) {
+        let pruned = self.pruned.lock().unwrap();
+        let dropped = self.dropped_positions.lock().unwrap();
+        if pruned.is_empty() && dropped.is_empty() {
+            return;
+        }
+        for folder_name in ["entities", "poi"] {
+            let counterparts_path = regions_path.with_file_name(folder_name);
+            if !counterparts_path.is_dir() {
+                continue;
+            }
+            let mut region_folder = RegionFolder::new(&counterparts_path);
+            let positions = pruned
+                .iter()
+                .map(|&position| (position, true))
+                .chain(dropped.iter().map(|&position| (position, false)));
+            for ((chunk_x, chunk_z), is_pruned) in positions {
+                match raw::chunk_sectors_size(&counterparts_path, chunk_x, chunk_z) {
+                    Ok(0) => continue,
+                    Ok(size) => {
+                        if is_pruned {
+                            self.pruned_size.fetch_add(size, Ordering::Relaxed);
                         }
                     }
+                    Err(err) => {
+                        error!("Error reading header of {folder_name} chunk at {chunk_x}, {chunk_z}: {err}");
+                        continue;
+                    }
+                }
+                if let Err(err) = region_folder.delete_chunk(chunk_x, chunk_z) {
+                    error!("Error deleting {folder_name} chunk at {chunk_x}, {chunk_z}: {err}");
                 }
-            },
+            }
+        }
+    }
+
+    /// Moves chunks into the slots for their recorded positions, leaving them where they are if the
+    /// slot is already taken. The entity and poi chunks of moved terrain chunks are moved with them.
+    fn move_chunks(&self, regions_path: &Path, folder_kind: RegionFolderKind) {
+        let pending_moves = std::mem::take(&mut *self.pending_moves.lock().unwrap());
+        let mut moved = Vec::new();
+        let mut region_folder = RegionFolder::new(regions_path);
+        for (from, to) in pending_moves {
+            if move_chunk(&mut region_folder, folder_kind, "chunk", from, to) {
+                self.moved.fetch_add(1, Ordering::Relaxed);
+                moved.push((from, to));
+            }
+        }
+
+        if folder_kind != RegionFolderKind::Chunks || moved.is_empty() {
+            return;
+        }
+        for (folder_name, counterpart_kind) in [
+            ("entities", RegionFolderKind::Entities),
+            ("poi", RegionFolderKind::Poi),
+        ] {
+            let counterparts_path = regions_path.with_file_name(folder_name);
+            if !counterparts_path.is_dir() {
+                continue;
+            }
+            let mut region_folder = RegionFolder::new(&counterparts_path);
+            let what = format!("{folder_name} chunk");
+            for &(from, to) in &moved {
+                move_chunk(&mut region_folder, counterpart_kind, &what, from, to);
+            }
+        }
+    }
+}
+
+/// Moves a chunk to another slot, updating the position it records, unless there's a chunk in that
+/// slot already. Returns whether the chunk was moved.
+fn move_chunk(
+    region_folder: &mut RegionFolder,
+    folder_kind: RegionFolderKind,
+    what: &str,
+    (from_x, from_z): (i32, i32),
+    (to_x, to_z): (i32, i32),
+) -> bool {
+    let existing: Option<RawChunk<JavaString>> = match region_folder.get_chunk(to_x, to_z) {
+        Ok(existing) => existing,
+        Err(err) => {
+            error!("Error reading {what} at {to_x}, {to_z}: {err}");
+            return false;
+        }
+    };
+    if existing.is_some() {
+        warn!(
+            "Can't move {what} at {from_x}, {from_z} to {to_x}, {to_z}, there's already a chunk there"
         );
+        return false;
+    }
+    let mut chunk: RawChunk<JavaString> = match region_folder.get_chunk(from_x, from_z) {
+        Ok(Some(chunk)) => chunk,
+        Ok(None) => return false,
+        Err(err) => {
+            error!("Error reading {what} at {from_x}, {from_z}: {err}");
+            return false;
+        }
+    };
+    set_chunk_position(&mut chunk.data, folder_kind, (to_x, to_z));
+    if let Err(err) = region_folder.set_chunk(to_x, to_z, &chunk.data) {
+        error!("Error moving {what} at {from_x}, {from_z} to {to_x}, {to_z}: {err}");
+        return false;
+    }
+    if let Err(err) = region_folder.delete_chunk(from_x, from_z) {
+        error!("Error deleting {what} at {from_x}, {from_z} after moving it: {err}");
+    }
+    true
+}
+
+/// Reads and decompresses the chunks of a single region, along with whether each one had to be
+/// salvaged.
+fn read_region(
+    regions_path: &Path,
+    (region_x, region_z): (i32, i32),
+    dry_run: bool,
+    options: &RegionOptions,
+    kind: &ChunkKind,
+    stats: &RegionStats,
+) -> Vec<(i32, i32, JCompound, bool)> {
+    let chunks = match raw::region_chunk_positions(regions_path, region_x, region_z) {
+        Ok(chunks) => chunks,
+        Err(err) => {
+            error!("Error reading header of region {region_x}, {region_z}: {err}");
+            stats.errors.fetch_add(1, Ordering::Relaxed);
+            return Vec::new();
+        }
+    };
+
+    let mut region_folder = RegionFolder::new(regions_path);
+    let mut result = Vec::with_capacity(chunks.len());
+    for (chunk_x, chunk_z) in chunks {
+        let chunk_nbt: Result<Option<RawChunk<JavaString>>, _> =
+            region_folder.get_chunk(chunk_x, chunk_z);
+        let (data, salvaged) = match chunk_nbt {
+            Ok(Some(chunk_nbt)) => (chunk_nbt.data, false),
+            Ok(None) => {
+                // the region header listed the chunk, but it wasn't found when we tried to get it
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            Err(err) => {
+                error!("Error reading chunk at {chunk_x}, {chunk_z}: {err}");
+                stats.errors.fetch_add(1, Ordering::Relaxed);
+                quarantine_chunk(options, regions_path, chunk_x, chunk_z, kind, &err);
+                if !options.salvage {
+                    continue;
+                }
+                match try_salvage_chunk(
+                    regions_path,
+                    &mut region_folder,
+                    chunk_x,
+                    chunk_z,
+                    RegionFolderKind::from_type_name(kind.type_name),
+                    dry_run,
+                    options,
+                ) {
+                    Some(data) => {
+                        stats.salvaged.fetch_add(1, Ordering::Relaxed);
+                        (data, true)
+                    }
+                    None => {
+                        stats.unrecoverable.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                }
+            }
+        };
+        result.push((chunk_x, chunk_z, data, salvaged));
+    }
+    result
+}
+
+/// Attempts to recover a chunk that valence_anvil failed to read. The raw sectors of the chunk are
+/// copied aside either way, so that a bad salvage can be undone. If nothing can be recovered, the
+/// chunk is optionally deleted.
+fn try_salvage_chunk(
+    regions_path: &Path,
+    region_folder: &mut RegionFolder,
+    chunk_x: i32,
+    chunk_z: i32,
+    folder_kind: RegionFolderKind,
+    dry_run: bool,
+    options: &RegionOptions,
+) -> Option<JCompound> {
+    let sectors = match raw::read_chunk_sectors(regions_path, chunk_x, chunk_z) {
+        Ok(Some(sectors)) => sectors,
+        Ok(None) => return None,
+        Err(err) => {
+            error!("Error reading raw chunk at {chunk_x}, {chunk_z}: {err}");
+            return None;
+        }
+    };
+
+    let salvaged = salvage_chunk(&sectors, folder_kind);
+    let saved_path = if salvaged.is_some() {
+        salvaged_chunks_path(regions_path)
+    } else {
+        unrecoverable_chunks_path(regions_path)
+    };
+    // if there's a quarantine, the chunk has already been copied there
+    if !dry_run && options.quarantine.is_none() {
+        if let Err(err) = std::fs::create_dir_all(&saved_path).and_then(|_| {
+            std::fs::write(
+                saved_path.join(format!("c.{chunk_x}.{chunk_z}.bin")),
+                &sectors,
+            )
+        }) {
+            error!("Error saving the original data of chunk at {chunk_x}, {chunk_z}: {err}");
+            return None;
+        }
+    }
+
+    if let Some(data) = salvaged {
+        warn!("Salvaged chunk at {chunk_x}, {chunk_z}");
+        return Some(data);
+    }
+
+    if !dry_run && options.delete_unrecoverable {
+        if let Err(err) = region_folder.delete_chunk(chunk_x, chunk_z) {
+            error!("Error deleting unrecoverable chunk at {chunk_x}, {chunk_z}: {err}");
+        }
+    }
+
+    None
+}
 
-    let num_errors = num_errors.load(Ordering::Acquire);
-    if num_errors > 0 {
-        error!("Encountered {num_errors} errors while upgrading chunks");
+fn unrecoverable_chunks_path(regions_path: &Path) -> PathBuf {
+    let mut folder_name = regions_path.file_name().unwrap_or_default().to_owned();
+    folder_name.push("_unrecoverable");
+    regions_path.with_file_name(folder_name)
+}
+
+fn salvaged_chunks_path(regions_path: &Path) -> PathBuf {
+    let mut folder_name = regions_path.file_name().unwrap_or_default().to_owned();
+    folder_name.push("_salvaged");
+    regions_path.with_file_name(folder_name)
+}
+
+/// Copies a chunk into the quarantine, if there is one. The data of a chunk stored in an external
+/// `.mcc` file is copied along with it, so that it can be retried on its own.
+fn quarantine_chunk(
+    options: &RegionOptions,
+    regions_path: &Path,
+    chunk_x: i32,
+    chunk_z: i32,
+    kind: &ChunkKind,
+    reason: &dyn Display,
+) {
+    let Some(quarantine) = &options.quarantine else {
+        return;
+    };
+    let sectors = match raw::read_chunk_sectors(regions_path, chunk_x, chunk_z) {
+        Ok(Some(sectors)) => sectors,
+        Ok(None) => return,
+        Err(err) => {
+            error!("Error reading raw chunk at {chunk_x}, {chunk_z}: {err}");
+            return;
+        }
+    };
+    let sectors = match raw::inline_external_chunk(regions_path, chunk_x, chunk_z, sectors) {
+        Ok(sectors) => sectors,
+        Err(err) => {
+            error!("Error reading external data of chunk at {chunk_x}, {chunk_z}, not quarantining it: {err}");
+            return;
+        }
+    };
+    quarantine::quarantine_chunk(
+        quarantine,
+        regions_path,
+        chunk_x,
+        chunk_z,
+        &sectors,
+        kind.type_name,
+        kind.default_version,
+        kind.context.as_ref(),
+        reason,
+    );
+}
+
+/// Upgrades a chunk from the quarantine and writes it back into its region file.
+#[allow(clippy::too_many_arguments)]
+pub fn retry_quarantined_chunk(
+    world: &Path,
+    regions_path: &Path,
+    chunk_x: i32,
+    chunk_z: i32,
+    sectors: &[u8],
+    typ: &str,
+    default_version: u32,
+    context: Option<&JCompound>,
+    to_version: u32,
+    dry_run: bool,
+) -> bool {
+    let Some(mut chunk) = raw::decode_chunk(sectors) else {
+        if raw::ChunkPayload::parse(sectors).is_some_and(|payload| payload.external) {
+            error!("Quarantined chunk at {chunk_x}, {chunk_z} is missing its external data");
+        } else {
+            error!("Failed to read quarantined chunk at {chunk_x}, {chunk_z}");
+        }
+        return false;
+    };
+
+    let result = if typ == "chunk" {
+        let get_context = |key: &str| match context.and_then(|context| context.get(key)) {
+            Some(JValue::String(value)) => &value[..],
+            _ => JavaStr::from_str(""),
+        };
+        let dimension = regions_path.parent().unwrap_or(world);
+        ChunkUpgrader::new(
+            get_context("dimension"),
+            get_context("generator"),
+            world,
+            to_version,
+            dry_run,
+        )
+        .upgrade_chunk(
+            chunk_x,
+            chunk_z,
+            &mut chunk,
+            &mut RegionFolder::new(dimension.join("entities")),
+        )
+    } else {
+        let Some(data_type) = data_type_by_name(typ) else {
+            error!("Unknown data type {typ} for quarantined chunk at {chunk_x}, {chunk_z}");
+            return false;
+        };
+        try_upgrade(
+            data_type,
+            &mut chunk,
+            || format!("chunk at {chunk_x}, {chunk_z}"),
+            to_version,
+            default_version,
+        )
+        .map(|_| true)
+    };
+
+    match result {
+        Ok(true) => {
+            if !dry_run {
+                if let Err(err) =
+                    RegionFolder::new(regions_path).set_chunk(chunk_x, chunk_z, &chunk)
+                {
+                    error!("Error writing chunk at {chunk_x}, {chunk_z}: {err}");
+                    return false;
+                }
+            }
+            true
+        }
+        Ok(false) | Err(_) => false,
     }
 }
@@ -0,0 +1,197 @@
+use flate2::read::{GzDecoder, ZlibDecoder};
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use valence_nbt::from_binary;
+use world_transmuter_engine::JCompound;
+
+pub const SECTOR_SIZE: u64 = 4096;
+
+pub const COMPRESSION_GZIP: u8 = 1;
+pub const COMPRESSION_ZLIB: u8 = 2;
+pub const COMPRESSION_NONE: u8 = 3;
+pub const EXTERNAL_CHUNK_FLAG: u8 = 0x80;
+
+pub fn region_file_path(regions_path: &Path, region_x: i32, region_z: i32) -> PathBuf {
+    regions_path.join(format!("r.{region_x}.{region_z}.mca"))
+}
+
+/// The file the data of a chunk too big for its region file is stored in.
+pub fn external_chunk_path(regions_path: &Path, chunk_x: i32, chunk_z: i32) -> PathBuf {
+    regions_path.join(format!("c.{chunk_x}.{chunk_z}.mcc"))
+}
+
+/// Parses the region position out of a region file name of the form `r.<x>.<z>.mca`.
+pub fn parse_region_file_name(name: &str) -> Option<(i32, i32)> {
+    let mut parts = name.strip_prefix("r.")?.strip_suffix(".mca")?.split('.');
+    let region_x = parts.next()?.parse().ok()?;
+    let region_z = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some((region_x, region_z))
+}
+
+/// The location table at the start of a region file.
+pub struct RegionHeader {
+    locations: [u32; 1024],
+}
+
+impl RegionHeader {
+    pub fn read(file: &mut File) -> io::Result<Self> {
+        let mut bytes = [0; SECTOR_SIZE as usize];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_exact(&mut bytes)?;
+        let mut locations = [0; 1024];
+        for (location, bytes) in locations.iter_mut().zip(bytes.chunks_exact(4)) {
+            *location = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        Ok(Self { locations })
+    }
+
+    /// Returns the sector offset and sector count of the chunk at the given index within the region.
+    pub fn location(&self, index: usize) -> (u64, u64) {
+        let location = self.locations[index];
+        ((location >> 8) as u64, (location & 0xff) as u64)
+    }
+}
+
+pub fn chunk_index(chunk_x: i32, chunk_z: i32) -> usize {
+    (chunk_x.rem_euclid(32) + chunk_z.rem_euclid(32) * 32) as usize
+}
+
+/// Lists the chunks which have an entry in the header of a region file.
+pub fn region_chunk_positions(
+    regions_path: &Path,
+    region_x: i32,
+    region_z: i32,
+) -> io::Result<Vec<(i32, i32)>> {
+    let mut file = File::open(region_file_path(regions_path, region_x, region_z))?;
+    let header = RegionHeader::read(&mut file)?;
+    Ok((0..1024)
+        .filter(|&index| header.location(index) != (0, 0))
+        .map(|index| {
+            (
+                region_x * 32 + (index % 32) as i32,
+                region_z * 32 + (index / 32) as i32,
+            )
+        })
+        .collect())
+}
+
+/// Returns how many bytes of sectors a chunk takes up in its region file, or 0 if it isn't there.
+pub fn chunk_sectors_size(regions_path: &Path, chunk_x: i32, chunk_z: i32) -> io::Result<u64> {
+    let path = region_file_path(regions_path, chunk_x >> 5, chunk_z >> 5);
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let header = RegionHeader::read(&mut file)?;
+    let (_, sector_count) = header.location(chunk_index(chunk_x, chunk_z));
+    Ok(sector_count * SECTOR_SIZE)
+}
+
+/// Reads the raw sectors of a chunk straight out of its region file, without trusting any of the
+/// length fields beyond the end of the file. Returns `None` if the header has no entry for the chunk.
+pub fn read_chunk_sectors(
+    regions_path: &Path,
+    chunk_x: i32,
+    chunk_z: i32,
+) -> io::Result<Option<Vec<u8>>> {
+    let path = region_file_path(regions_path, chunk_x >> 5, chunk_z >> 5);
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let header = RegionHeader::read(&mut file)?;
+    let (sector_offset, sector_count) = header.location(chunk_index(chunk_x, chunk_z));
+    if sector_offset == 0 && sector_count == 0 {
+        return Ok(None);
+    }
+
+    let file_len = file.metadata()?.len();
+    let start = (sector_offset * SECTOR_SIZE).min(file_len);
+    let end = ((sector_offset + sector_count) * SECTOR_SIZE).min(file_len);
+    file.seek(SeekFrom::Start(start))?;
+    let mut bytes = vec![0; (end - start) as usize];
+    file.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+/// The payload of a chunk entry, split out of its sectors.
+pub struct ChunkPayload<'a> {
+    pub compression: u8,
+    pub external: bool,
+    pub data: &'a [u8],
+}
+
+impl<'a> ChunkPayload<'a> {
+    /// Splits the length and compression prefix off the raw sectors of a chunk. A length which runs
+    /// past the end of the sectors is clamped rather than rejected.
+    pub fn parse(sectors: &'a [u8]) -> Option<Self> {
+        if sectors.len() < 5 {
+            return None;
+        }
+        let length = u32::from_be_bytes(sectors[..4].try_into().unwrap()) as usize;
+        if length == 0 {
+            return None;
+        }
+        let compression = sectors[4];
+        let end = (4 + length).min(sectors.len());
+        Some(Self {
+            compression: compression & !EXTERNAL_CHUNK_FLAG,
+            external: compression & EXTERNAL_CHUNK_FLAG != 0,
+            data: &sectors[5..end],
+        })
+    }
+}
+
+/// Makes the raw sectors of a chunk self-contained by replacing a pointer to an external `.mcc` file
+/// with the data in that file. Sectors holding their data inline are returned as they are.
+pub fn inline_external_chunk(
+    regions_path: &Path,
+    chunk_x: i32,
+    chunk_z: i32,
+    sectors: Vec<u8>,
+) -> io::Result<Vec<u8>> {
+    let compression = match ChunkPayload::parse(&sectors) {
+        Some(payload) if payload.external => payload.compression,
+        _ => return Ok(sectors),
+    };
+    let data = std::fs::read(external_chunk_path(regions_path, chunk_x, chunk_z))?;
+    let mut inlined = Vec::with_capacity(5 + data.len());
+    inlined.extend_from_slice(&(data.len() as u32 + 1).to_be_bytes());
+    inlined.push(compression);
+    inlined.extend_from_slice(&data);
+    Ok(inlined)
+}
+
+/// Strictly decodes the raw sectors of a chunk, failing on any corruption.
+pub fn decode_chunk(sectors: &[u8]) -> Option<JCompound> {
+    let payload = ChunkPayload::parse(sectors)?;
+    if payload.external {
+        return None;
+    }
+    decode_payload(payload.compression, payload.data)
+}
+
+/// Strictly decompresses and decodes the data of a chunk, failing on any corruption.
+pub fn decode_payload(compression: u8, payload: &[u8]) -> Option<JCompound> {
+    let mut data = Vec::new();
+    let data = match compression {
+        COMPRESSION_GZIP => {
+            GzDecoder::new(payload).read_to_end(&mut data).ok()?;
+            &data[..]
+        }
+        COMPRESSION_ZLIB => {
+            ZlibDecoder::new(payload).read_to_end(&mut data).ok()?;
+            &data[..]
+        }
+        COMPRESSION_NONE => payload,
+        _ => return None,
+    };
+    from_binary(&mut &*data).ok().map(|(compound, _)| compound)
+}
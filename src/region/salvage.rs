@@ -0,0 +1,245 @@
+use crate::region::check::{chunk_position, RegionFolderKind};
+use crate::region::raw::{ChunkPayload, COMPRESSION_GZIP, COMPRESSION_NONE, COMPRESSION_ZLIB};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use java_string::{JavaStr, JavaString};
+use std::io::{ErrorKind, Read};
+use world_transmuter_engine::{JCompound, JList, JValue};
+
+const MAX_DEPTH: usize = 512;
+
+/// Tries to recover as much of a chunk as possible from its raw sectors, decompressing up to the
+/// first error and then keeping every tag that was read completely before the data ran out. What's
+/// recovered is only kept if it's still recognizably a chunk of the given kind.
+pub fn salvage_chunk(sectors: &[u8], kind: RegionFolderKind) -> Option<JCompound> {
+    let payload = ChunkPayload::parse(sectors)?;
+    if payload.external {
+        // the data is in a separate .mcc file, which valence_anvil would already have tried
+        return None;
+    }
+    let data = match payload.compression {
+        COMPRESSION_GZIP => read_partial(GzDecoder::new(payload.data)),
+        COMPRESSION_ZLIB => read_partial(ZlibDecoder::new(payload.data)),
+        COMPRESSION_NONE => payload.data.to_vec(),
+        _ => return None,
+    };
+
+    let mut reader = LenientReader { data: &data };
+    if reader.u8()? != 10 {
+        return None;
+    }
+    reader.string()?;
+    let (compound, _) = reader.compound(0);
+    is_usable_chunk(&compound, kind).then_some(compound)
+}
+
+/// Whether a salvaged chunk still has the tags the game needs to place and load it.
+fn is_usable_chunk(chunk: &JCompound, kind: RegionFolderKind) -> bool {
+    match kind {
+        RegionFolderKind::Chunks => {
+            if chunk_position(chunk, kind).is_none() {
+                return false;
+            }
+            match chunk.get("Level") {
+                Some(JValue::Compound(level)) => {
+                    matches!(level.get("Sections"), Some(JValue::List(_)))
+                }
+                _ => matches!(chunk.get("sections"), Some(JValue::List(_))),
+            }
+        }
+        RegionFolderKind::Entities => {
+            chunk_position(chunk, kind).is_some()
+                && matches!(chunk.get("Entities"), Some(JValue::List(_)))
+        }
+        RegionFolderKind::Poi => matches!(chunk.get("Sections"), Some(JValue::Compound(_))),
+    }
+}
+
+fn read_partial(mut reader: impl Read) -> Vec<u8> {
+    let mut result = Vec::new();
+    let mut buf = [0; 8192];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => result.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    result
+}
+
+/// An NBT reader which, instead of failing, stops at the first truncated or malformed tag.
+struct LenientReader<'a> {
+    data: &'a [u8],
+}
+
+macro_rules! read_primitive {
+    ($name:ident, $typ:ty) => {
+        fn $name(&mut self) -> Option<$typ> {
+            let bytes = self.bytes(std::mem::size_of::<$typ>())?;
+            Some(<$typ>::from_be_bytes(bytes.try_into().unwrap()))
+        }
+    };
+}
+
+macro_rules! read_list {
+    ($len:ident, $read:expr, $variant:ident) => {{
+        let mut elements = Vec::new();
+        for _ in 0..$len {
+            match $read {
+                Some(element) => elements.push(element),
+                None => return (Some(JList::$variant(elements)), false),
+            }
+        }
+        (Some(JList::$variant(elements)), true)
+    }};
+}
+
+impl<'a> LenientReader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.data.len() < len {
+            self.data = &[];
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    read_primitive!(u8, u8);
+    read_primitive!(i8, i8);
+    read_primitive!(u16, u16);
+    read_primitive!(i16, i16);
+    read_primitive!(i32, i32);
+    read_primitive!(i64, i64);
+    read_primitive!(f32, f32);
+    read_primitive!(f64, f64);
+
+    fn len(&mut self) -> Option<usize> {
+        usize::try_from(self.i32()?).ok()
+    }
+
+    fn string(&mut self) -> Option<JavaString> {
+        let len = self.u16()? as usize;
+        let bytes = self.bytes(len)?;
+        JavaStr::from_modified_utf8(bytes)
+            .ok()
+            .map(|str| str.into_owned())
+    }
+
+    fn byte_array(&mut self) -> Option<Vec<i8>> {
+        let len = self.len()?;
+        Some(self.bytes(len)?.iter().map(|b| *b as i8).collect())
+    }
+
+    fn int_array(&mut self) -> Option<Vec<i32>> {
+        let len = self.len()?;
+        (0..len).map(|_| self.i32()).collect()
+    }
+
+    fn long_array(&mut self) -> Option<Vec<i64>> {
+        let len = self.len()?;
+        (0..len).map(|_| self.i64()).collect()
+    }
+
+    /// Reads a compound, returning what was read and whether the compound was read completely.
+    fn compound(&mut self, depth: usize) -> (JCompound, bool) {
+        let mut compound = JCompound::new();
+        if depth > MAX_DEPTH {
+            return (compound, false);
+        }
+        loop {
+            let Some(tag) = self.u8() else {
+                return (compound, false);
+            };
+            if tag == 0 {
+                return (compound, true);
+            }
+            let Some(name) = self.string() else {
+                return (compound, false);
+            };
+            let (value, complete) = self.value(tag, depth + 1);
+            if let Some(value) = value {
+                compound.insert(name, value);
+            }
+            if !complete {
+                return (compound, false);
+            }
+        }
+    }
+
+    fn value(&mut self, tag: u8, depth: usize) -> (Option<JValue>, bool) {
+        let value = match tag {
+            1 => self.i8().map(JValue::Byte),
+            2 => self.i16().map(JValue::Short),
+            3 => self.i32().map(JValue::Int),
+            4 => self.i64().map(JValue::Long),
+            5 => self.f32().map(JValue::Float),
+            6 => self.f64().map(JValue::Double),
+            7 => self.byte_array().map(JValue::ByteArray),
+            8 => self.string().map(JValue::String),
+            9 => {
+                let (list, complete) = self.list(depth);
+                return (list.map(JValue::List), complete);
+            }
+            10 => {
+                let (compound, complete) = self.compound(depth);
+                return (Some(JValue::Compound(compound)), complete);
+            }
+            11 => self.int_array().map(JValue::IntArray),
+            12 => self.long_array().map(JValue::LongArray),
+            _ => None,
+        };
+        let complete = value.is_some();
+        (value, complete)
+    }
+
+    fn list(&mut self, depth: usize) -> (Option<JList>, bool) {
+        if depth > MAX_DEPTH {
+            return (None, false);
+        }
+        let Some(tag) = self.u8() else {
+            return (None, false);
+        };
+        let Some(len) = self.len() else {
+            return (None, false);
+        };
+        match tag {
+            0 => (Some(JList::End), true),
+            1 => read_list!(len, self.i8(), Byte),
+            2 => read_list!(len, self.i16(), Short),
+            3 => read_list!(len, self.i32(), Int),
+            4 => read_list!(len, self.i64(), Long),
+            5 => read_list!(len, self.f32(), Float),
+            6 => read_list!(len, self.f64(), Double),
+            7 => read_list!(len, self.byte_array(), ByteArray),
+            8 => read_list!(len, self.string(), String),
+            9 => read_list!(
+                len,
+                match self.list(depth + 1) {
+                    (Some(list), true) => Some(list),
+                    _ => None,
+                },
+                List
+            ),
+            10 => {
+                let mut compounds = Vec::new();
+                for _ in 0..len {
+                    let (compound, complete) = self.compound(depth + 1);
+                    if !complete {
+                        // keep the partially read element, it's better than nothing
+                        if !compound.is_empty() {
+                            compounds.push(compound);
+                        }
+                        return (Some(JList::Compound(compounds)), false);
+                    }
+                    compounds.push(compound);
+                }
+                (Some(JList::Compound(compounds)), true)
+            }
+            11 => read_list!(len, self.int_array(), IntArray),
+            12 => read_list!(len, self.long_array(), LongArray),
+            _ => (None, false),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use ahash::{AHashMap, RandomState};
+use java_string::{JavaStr, JavaString};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tracing::{info, warn};
+use world_transmuter_engine::{JCompound, JList, JValue};
+
+/// What to do with entities whose UUID is already used by another entity in the world.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DuplicateUuids {
+    Report,
+    /// Give the later entity a fresh UUID, updating references to it within its chunk.
+    Fix,
+}
+
+/// The UUIDs of the entities upgraded in region files, across all dimensions.
+pub struct UuidIndex {
+    mode: DuplicateUuids,
+    /// Where each UUID was first seen.
+    seen: Mutex<AHashMap<[i32; 4], (JavaString, i32, i32)>>,
+    num_duplicates: AtomicUsize,
+    random_state: RandomState,
+    counter: AtomicU64,
+}
+
+pub fn log_duplicate_uuids(index: Option<&UuidIndex>) {
+    let Some(index) = index else {
+        return;
+    };
+    let num_duplicates = index.num_duplicates.load(Ordering::Relaxed);
+    match (num_duplicates, index.mode) {
+        (0, _) => info!("Found no duplicate entity UUIDs"),
+        (_, DuplicateUuids::Report) => warn!("Found {num_duplicates} duplicate entity UUIDs"),
+        (_, DuplicateUuids::Fix) => info!("Gave {num_duplicates} entities fresh UUIDs"),
+    }
+}
+
+/// Adds the entities of a chunk to the UUID index, reporting or fixing any whose UUID has already
+/// been seen. Returns whether any entity was given a fresh UUID.
+pub fn index_entities(
+    index: Option<&UuidIndex>,
+    dim_id: &JavaStr,
+    chunk_x: i32,
+    chunk_z: i32,
+    entities: &mut [JCompound],
+) -> bool {
+    let Some(index) = index else {
+        return false;
+    };
+
+    let mut reassigned = AHashMap::new();
+    {
+        let mut seen = index.seen.lock().unwrap();
+        for entity in entities.iter_mut() {
+            index.index_entity(&mut seen, dim_id, chunk_x, chunk_z, entity, &mut reassigned);
+        }
+    }
+
+    // leashes, owners and the like in the same chunk most likely point at the copy in this chunk
+    if reassigned.is_empty() {
+        return false;
+    }
+    for entity in entities {
+        replace_entity_references(entity, &reassigned);
+    }
+    true
+}
+
+impl UuidIndex {
+    pub fn new(mode: DuplicateUuids) -> Self {
+        Self {
+            mode,
+            seen: Mutex::new(AHashMap::new()),
+            num_duplicates: AtomicUsize::new(0),
+            random_state: RandomState::new(),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    fn index_entity(
+        &self,
+        seen: &mut AHashMap<[i32; 4], (JavaString, i32, i32)>,
+        dim_id: &JavaStr,
+        chunk_x: i32,
+        chunk_z: i32,
+        entity: &mut JCompound,
+        reassigned: &mut AHashMap<[i32; 4], [i32; 4]>,
+    ) {
+        if let Some(JValue::List(JList::Compound(passengers))) = entity.get_mut("Passengers") {
+            for passenger in passengers {
+                self.index_entity(seen, dim_id, chunk_x, chunk_z, passenger, reassigned);
+            }
+        }
+
+        let Some(uuid) = entity_uuid(entity) else {
+            return;
+        };
+        let Some((first_dim_id, first_x, first_z)) = seen.get(&uuid) else {
+            seen.insert(uuid, (dim_id.to_owned(), chunk_x, chunk_z));
+            return;
+        };
+
+        self.num_duplicates.fetch_add(1, Ordering::Relaxed);
+        let id = match entity.get("id") {
+            Some(JValue::String(id)) => id.as_str_lossy(),
+            _ => "entity".into(),
+        };
+        warn!(
+            "{id} in chunk {chunk_x}, {chunk_z} in {dim_id} has the same UUID as an entity in chunk {first_x}, {first_z} in {first_dim_id}"
+        );
+        if self.mode == DuplicateUuids::Fix {
+            let new_uuid = loop {
+                let new_uuid = self.random_uuid();
+                if !seen.contains_key(&new_uuid) {
+                    break new_uuid;
+                }
+            };
+            seen.insert(new_uuid, (dim_id.to_owned(), chunk_x, chunk_z));
+            entity.insert("UUID", JValue::IntArray(new_uuid.to_vec()));
+            reassigned.insert(uuid, new_uuid);
+        }
+    }
+
+    /// A version 4 UUID, from the randomly seeded hasher.
+    fn random_uuid(&self) -> [i32; 4] {
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+        let most = self.random_state.hash_one(counter * 2);
+        let least = self.random_state.hash_one(counter * 2 + 1);
+        let most = (most & !0xf000) | 0x4000;
+        let least = (least & !(0b11 << 62)) | (0b10 << 62);
+        [
+            (most >> 32) as i32,
+            most as i32,
+            (least >> 32) as i32,
+            least as i32,
+        ]
+    }
+}
+
+pub(super) fn entity_uuid(entity: &JCompound) -> Option<[i32; 4]> {
+    match entity.get("UUID") {
+        Some(JValue::IntArray(uuid)) => uuid.as_slice().try_into().ok(),
+        _ => None,
+    }
+}
+
+/// Replaces UUIDs anywhere inside an entity other than its own UUID.
+fn replace_entity_references(entity: &mut JCompound, reassigned: &AHashMap<[i32; 4], [i32; 4]>) {
+    for (key, value) in entity.iter_mut() {
+        match value {
+            JValue::IntArray(_) if key == "UUID" => {}
+            JValue::List(JList::Compound(passengers)) if key == "Passengers" => {
+                for passenger in passengers {
+                    replace_entity_references(passenger, reassigned);
+                }
+            }
+            _ => replace_references(value, reassigned),
+        }
+    }
+}
+
+fn replace_references(value: &mut JValue, reassigned: &AHashMap<[i32; 4], [i32; 4]>) {
+    match value {
+        JValue::IntArray(uuid) => replace_uuid(uuid, reassigned),
+        JValue::Compound(compound) => {
+            for (_, value) in compound.iter_mut() {
+                replace_references(value, reassigned);
+            }
+        }
+        JValue::List(JList::Compound(compounds)) => {
+            for compound in compounds {
+                for (_, value) in compound.iter_mut() {
+                    replace_references(value, reassigned);
+                }
+            }
+        }
+        JValue::List(JList::IntArray(uuids)) => {
+            for uuid in uuids {
+                replace_uuid(uuid, reassigned);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn replace_uuid(uuid: &mut Vec<i32>, reassigned: &AHashMap<[i32; 4], [i32; 4]>) {
+    let new_uuid = <[i32; 4]>::try_from(uuid.as_slice())
+        .ok()
+        .and_then(|uuid| reassigned.get(&uuid));
+    if let Some(new_uuid) = new_uuid {
+        *uuid = new_uuid.to_vec();
+    }
+}
@@ -0,0 +1,669 @@
+use crate::{try_upgrade, UpgradeOutcome};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use java_string::{JavaStr, JavaString};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tracing::{error, info, info_span, warn, Span};
+use valence_nbt::{from_binary, jcompound, to_binary};
+use world_transmuter::types;
+use world_transmuter_engine::{JCompound, JList, JValue};
+
+/// How to upgrade schematics.
+pub struct SchematicOptions {
+    pub to_version: u32,
+    /// The version to assume for schematics which don't record their own.
+    pub from_version: Option<u32>,
+    /// The Sponge schematic format version to write, defaulting to the version each schematic is
+    /// already in.
+    pub sponge_version: Option<i32>,
+    pub dry_run: bool,
+}
+
+/// Upgrades a Sponge (`.schem`) or Litematica (`.litematic`) schematic in place, or every
+/// schematic in a directory. Returns whether every schematic was upgraded. The biomes of Sponge
+/// schematics are carried over without upgrading their IDs.
+#[must_use]
+pub fn upgrade_schematics(path: &Path, options: &SchematicOptions) -> bool {
+    let _span = info_span!(
+        "Upgrading schematics",
+        message = path.to_string_lossy().as_ref()
+    )
+    .entered();
+
+    let mut files = Vec::new();
+    if path.is_dir() {
+        if let Err(err) = find_schematics(path, &mut files) {
+            error!("Failed to list schematics: {err}");
+            return false;
+        }
+    } else {
+        files.push(path.to_path_buf());
+    }
+
+    let num_up_to_date = AtomicUsize::new(0);
+    let num_failed = AtomicUsize::new(0);
+    let parent_span = Span::current();
+    files.par_iter().for_each_init(
+        move || parent_span.clone().entered(),
+        |_, file| match upgrade_schematic(file, options) {
+            UpgradeOutcome::Upgraded => {}
+            UpgradeOutcome::UpToDate => {
+                num_up_to_date.fetch_add(1, Ordering::Relaxed);
+            }
+            UpgradeOutcome::NotUpgraded => {
+                num_failed.fetch_add(1, Ordering::Relaxed);
+            }
+        },
+    );
+
+    let num_up_to_date = num_up_to_date.into_inner();
+    let num_failed = num_failed.into_inner();
+    info!(
+        "Upgraded {} of {} schematics",
+        files.len() - num_up_to_date - num_failed,
+        files.len()
+    );
+    if num_up_to_date > 0 {
+        info!("Skipped {num_up_to_date} schematics already at the target version");
+    }
+    num_failed == 0
+}
+
+fn find_schematics(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_schematics(&path, files)?;
+        } else if matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("schem" | "litematic")
+        ) {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn upgrade_schematic(path: &Path, options: &SchematicOptions) -> UpgradeOutcome {
+    let name = path.to_string_lossy();
+    let is_litematic = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("schem") => false,
+        Some("litematic") => true,
+        _ => {
+            error!("{name} is neither a .schem nor a .litematic file");
+            return UpgradeOutcome::NotUpgraded;
+        }
+    };
+
+    let (mut root, root_name) = match read_schematic(path) {
+        Ok(Some(schematic)) => schematic,
+        Ok(None) => {
+            error!("Failed to parse {name}");
+            return UpgradeOutcome::NotUpgraded;
+        }
+        Err(err) => {
+            error!("Failed to read {name}: {err}");
+            return UpgradeOutcome::NotUpgraded;
+        }
+    };
+
+    let (outcome, data, root_name) = if is_litematic {
+        let outcome = upgrade_litematic(&mut root, &name, options);
+        (outcome, root, root_name)
+    } else {
+        // version 3 schematics are wrapped in an unnamed root compound
+        let mut schematic = match root.remove("Schematic") {
+            Some(JValue::Compound(schematic)) => schematic,
+            _ => root,
+        };
+        let outcome = upgrade_sponge(&mut schematic, &name, options);
+        if schematic.get("Version").and_then(|v| v.as_i32()) == Some(3) {
+            (
+                outcome,
+                jcompound! { "Schematic" => schematic },
+                JavaString::new(),
+            )
+        } else {
+            (outcome, schematic, JavaString::from("Schematic"))
+        }
+    };
+
+    if outcome != UpgradeOutcome::Upgraded || options.dry_run {
+        return outcome;
+    }
+    if let Err(err) = write_schematic(path, &data, &root_name.as_str_lossy()) {
+        error!("Failed to write {name}: {err}");
+        return UpgradeOutcome::NotUpgraded;
+    }
+    UpgradeOutcome::Upgraded
+}
+
+fn read_schematic(path: &Path) -> io::Result<Option<(JCompound, JavaString)>> {
+    let mut contents = Vec::new();
+    GzDecoder::new(File::open(path)?).read_to_end(&mut contents)?;
+    Ok(from_binary(&mut &*contents).ok())
+}
+
+/// Writes a schematic to a temporary file and then moves it into place, so that a failed write
+/// doesn't destroy the original.
+fn write_schematic(path: &Path, data: &JCompound, root_name: &str) -> io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let result = File::create(&temp_path).and_then(|file| {
+        let mut encoder = GzEncoder::new(file, Compression::default());
+        to_binary(data, &mut encoder, root_name).map_err(io::Error::other)?;
+        encoder.finish()?.sync_all()
+    });
+    if let Err(err) = result {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(err);
+    }
+    std::fs::rename(&temp_path, path)
+}
+
+fn schematic_data_version(
+    data: &JCompound,
+    key: &str,
+    name: &str,
+    options: &SchematicOptions,
+) -> Option<u32> {
+    match data.get(key).and_then(|v| v.as_i32()) {
+        Some(data_version) => Some(data_version as u32),
+        None if options.from_version.is_some() => options.from_version,
+        None => {
+            error!("{name} has no {key}, use --from to say which version it's from");
+            None
+        }
+    }
+}
+
+/// The parts of a schematic which the game's structure format also has, so that they can be
+/// upgraded by the structure data type.
+struct StructureParts {
+    palette: Vec<JCompound>,
+    block_entities: Vec<JCompound>,
+    entities: Vec<JCompound>,
+}
+
+fn upgrade_structure_parts(
+    parts: StructureParts,
+    name: &str,
+    data_version: u32,
+    to_version: u32,
+) -> Option<StructureParts> {
+    let num_block_entities = parts.block_entities.len();
+    let num_entities = parts.entities.len();
+    let blocks = parts
+        .block_entities
+        .into_iter()
+        .map(|nbt| {
+            jcompound! {
+                "pos" => JList::Int(vec![0, 0, 0]),
+                "state" => 0,
+                "nbt" => nbt,
+            }
+        })
+        .collect();
+    let entities = parts
+        .entities
+        .into_iter()
+        .map(|nbt| {
+            jcompound! {
+                "pos" => JList::Double(vec![0.0, 0.0, 0.0]),
+                "blockPos" => JList::Int(vec![0, 0, 0]),
+                "nbt" => nbt,
+            }
+        })
+        .collect();
+    let mut structure = jcompound! {
+        "DataVersion" => data_version as i32,
+        "palette" => JList::Compound(parts.palette),
+        "blocks" => JList::Compound(blocks),
+        "entities" => JList::Compound(entities),
+    };
+
+    try_upgrade(
+        types::structure,
+        &mut structure,
+        || name.to_owned(),
+        to_version,
+        data_version,
+    )
+    .ok()?;
+
+    let palette = take_compound_list(&mut structure, "palette").unwrap_or_default();
+    let block_entities: Vec<_> = take_compound_list(&mut structure, "blocks")
+        .unwrap_or_default()
+        .into_iter()
+        .map(|mut block| take_compound(&mut block, "nbt").unwrap_or_default())
+        .collect();
+    let entities: Vec<_> = take_compound_list(&mut structure, "entities")
+        .unwrap_or_default()
+        .into_iter()
+        .map(|mut entity| take_compound(&mut entity, "nbt").unwrap_or_default())
+        .collect();
+    if block_entities.len() != num_block_entities || entities.len() != num_entities {
+        error!("Upgrading {name} lost track of its block entities or entities");
+        return None;
+    }
+
+    Some(StructureParts {
+        palette,
+        block_entities,
+        entities,
+    })
+}
+
+fn take_compound(compound: &mut JCompound, key: &str) -> Option<JCompound> {
+    match compound.remove(key) {
+        Some(JValue::Compound(value)) => Some(value),
+        Some(value) => {
+            compound.insert(key, value);
+            None
+        }
+        None => None,
+    }
+}
+
+fn take_compound_list(compound: &mut JCompound, key: &str) -> Option<Vec<JCompound>> {
+    match compound.remove(key) {
+        Some(JValue::List(JList::Compound(values))) => Some(values),
+        Some(JValue::List(JList::End)) => Some(Vec::new()),
+        Some(value) => {
+            compound.insert(key, value);
+            None
+        }
+        None => None,
+    }
+}
+
+fn upgrade_litematic(
+    root: &mut JCompound,
+    name: &str,
+    options: &SchematicOptions,
+) -> UpgradeOutcome {
+    const DATA_VERSION_KEY: &str = "MinecraftDataVersion";
+    if root.get(DATA_VERSION_KEY).and_then(|v| v.as_i32()) == Some(options.to_version as i32) {
+        return UpgradeOutcome::UpToDate;
+    }
+    let Some(data_version) = schematic_data_version(root, DATA_VERSION_KEY, name, options) else {
+        return UpgradeOutcome::NotUpgraded;
+    };
+
+    let Some(JValue::Compound(regions)) = root.get_mut("Regions") else {
+        error!("{name} has no regions");
+        return UpgradeOutcome::NotUpgraded;
+    };
+    for (region_name, region) in regions.iter_mut() {
+        let JValue::Compound(region) = region else {
+            continue;
+        };
+        let parts = StructureParts {
+            palette: take_compound_list(region, "BlockStatePalette").unwrap_or_default(),
+            // tile entities keep their position in x, y and z, just like in chunks
+            block_entities: take_compound_list(region, "TileEntities").unwrap_or_default(),
+            entities: take_compound_list(region, "Entities").unwrap_or_default(),
+        };
+        let region_name = format!("{name} region {}", region_name.as_str_lossy());
+        let Some(parts) =
+            upgrade_structure_parts(parts, &region_name, data_version, options.to_version)
+        else {
+            return UpgradeOutcome::NotUpgraded;
+        };
+        region.insert("BlockStatePalette", JList::Compound(parts.palette));
+        region.insert("TileEntities", JList::Compound(parts.block_entities));
+        region.insert("Entities", JList::Compound(parts.entities));
+    }
+
+    root.insert(DATA_VERSION_KEY, options.to_version as i32);
+    UpgradeOutcome::Upgraded
+}
+
+fn upgrade_sponge(
+    schematic: &mut JCompound,
+    name: &str,
+    options: &SchematicOptions,
+) -> UpgradeOutcome {
+    let version = schematic
+        .get("Version")
+        .and_then(|v| v.as_i32())
+        .unwrap_or(1);
+    if !matches!(version, 2 | 3) {
+        error!(
+            "{name} is a version {version} Sponge schematic, only versions 2 and 3 are supported"
+        );
+        return UpgradeOutcome::NotUpgraded;
+    }
+    let out_version = options.sponge_version.unwrap_or(version);
+    if out_version < version {
+        error!("Cannot downgrade {name} from Sponge schematic version {version}");
+        return UpgradeOutcome::NotUpgraded;
+    }
+    if schematic.get("DataVersion").and_then(|v| v.as_i32()) == Some(options.to_version as i32)
+        && out_version == version
+    {
+        return UpgradeOutcome::UpToDate;
+    }
+    let Some(data_version) = schematic_data_version(schematic, "DataVersion", name, options) else {
+        return UpgradeOutcome::NotUpgraded;
+    };
+    // world-transmuter has no data type for a bare biome palette, and the biome IDs renamed in 1.18
+    // only get fixed as part of a chunk
+    let has_biomes = schematic.contains_key("BiomePalette") || schematic.contains_key("Biomes");
+    if has_biomes && data_version < options.to_version {
+        warn!("{name} has biomes, whose IDs are left as they are and may be unknown to the target version");
+    }
+
+    // version 3 moved the blocks into their own compound, and the extra data of block entities
+    // and entities into a Data compound
+    let nested = version == 3;
+    let data_key = if nested { "Data" } else { "BlockData" };
+    let (palette, mut block_entities) = match sponge_blocks(schematic, nested) {
+        Some(blocks) => (
+            take_compound(blocks, "Palette"),
+            take_compound_list(blocks, "BlockEntities"),
+        ),
+        None => (None, None),
+    };
+    let mut entities = take_compound_list(schematic, "Entities");
+
+    let mut palette_states = Vec::new();
+    if let Some(palette) = &palette {
+        palette_states.resize_with(palette.len(), || {
+            jcompound! { "Name" => JavaStr::from_str("minecraft:air") }
+        });
+        for (state, index) in palette.iter() {
+            match index.as_i32() {
+                Some(index) if (0..palette.len() as i32).contains(&index) => {
+                    palette_states[index as usize] = parse_block_state(&state.as_str_lossy());
+                }
+                _ => {
+                    error!(
+                        "{name} has an invalid palette index for {}",
+                        state.as_str_lossy()
+                    );
+                    return UpgradeOutcome::NotUpgraded;
+                }
+            }
+        }
+    }
+
+    let block_entity_ids: Vec<_> = block_entities
+        .iter_mut()
+        .flatten()
+        .map(|entry| to_structure_nbt(entry, nested))
+        .collect();
+    let entity_ids: Vec<_> = entities
+        .iter_mut()
+        .flatten()
+        .map(|entry| to_structure_nbt(entry, nested))
+        .collect();
+    let (block_entity_nbt, block_entity_had_ids): (Vec<_>, Vec<_>) =
+        block_entity_ids.into_iter().unzip();
+    let (entity_nbt, entity_had_ids): (Vec<_>, Vec<_>) = entity_ids.into_iter().unzip();
+
+    let parts = StructureParts {
+        palette: palette_states,
+        block_entities: block_entity_nbt,
+        entities: entity_nbt,
+    };
+    let Some(parts) = upgrade_structure_parts(parts, name, data_version, options.to_version) else {
+        return UpgradeOutcome::NotUpgraded;
+    };
+
+    let nested_out = out_version == 3;
+    for ((entry, nbt), had_id) in block_entities
+        .iter_mut()
+        .flatten()
+        .zip(parts.block_entities)
+        .zip(block_entity_had_ids)
+    {
+        from_structure_nbt(entry, nbt, nested_out, had_id);
+    }
+    for ((entry, nbt), had_id) in entities
+        .iter_mut()
+        .flatten()
+        .zip(parts.entities)
+        .zip(entity_had_ids)
+    {
+        from_structure_nbt(entry, nbt, nested_out, had_id);
+    }
+
+    if let Some(blocks) = sponge_blocks(schematic, nested) {
+        if palette.is_some() {
+            // states which became the same after upgrading have to share a palette entry
+            let mut new_palette = JCompound::new();
+            let mut remapped_indices = Vec::with_capacity(parts.palette.len());
+            for state in &parts.palette {
+                let Some(state) = stringify_block_state(state) else {
+                    error!("{name} has a block state without a name after upgrading");
+                    return UpgradeOutcome::NotUpgraded;
+                };
+                let index = match new_palette.get(state.as_str()) {
+                    Some(index) => index.as_i32().unwrap_or(0),
+                    None => {
+                        let index = new_palette.len() as i32;
+                        new_palette.insert(state, index);
+                        index
+                    }
+                };
+                remapped_indices.push(index as u32);
+            }
+
+            let is_remapped = remapped_indices
+                .iter()
+                .enumerate()
+                .any(|(old_index, new_index)| old_index as u32 != *new_index);
+            if is_remapped {
+                let Some(JValue::ByteArray(block_data)) = blocks.get_mut(data_key) else {
+                    error!("{name} has a palette but no block data");
+                    return UpgradeOutcome::NotUpgraded;
+                };
+                let Some(indices) = read_varints(block_data) else {
+                    error!("{name} has invalid block data");
+                    return UpgradeOutcome::NotUpgraded;
+                };
+                *block_data = write_varints(indices.into_iter().map(|index| {
+                    remapped_indices
+                        .get(index as usize)
+                        .copied()
+                        .unwrap_or(index)
+                }));
+            }
+
+            if !nested {
+                blocks.insert("PaletteMax", new_palette.len() as i32);
+            }
+            blocks.insert("Palette", new_palette);
+        }
+        if let Some(block_entities) = block_entities {
+            blocks.insert("BlockEntities", JList::Compound(block_entities));
+        }
+    }
+    if let Some(entities) = entities {
+        schematic.insert("Entities", JList::Compound(entities));
+    }
+
+    if version == 2 && out_version == 3 {
+        sponge_v2_to_v3(schematic);
+    }
+    schematic.insert("DataVersion", options.to_version as i32);
+    UpgradeOutcome::Upgraded
+}
+
+/// The compound holding the blocks of a Sponge schematic.
+fn sponge_blocks(schematic: &mut JCompound, nested: bool) -> Option<&mut JCompound> {
+    if !nested {
+        return Some(schematic);
+    }
+    match schematic.get_mut("Blocks") {
+        Some(JValue::Compound(blocks)) => Some(blocks),
+        _ => None,
+    }
+}
+
+/// Turns a Sponge block entity or entity into the form it has in the world, leaving behind
+/// whatever isn't part of that. Returns whether the data already had an ID of its own.
+fn to_structure_nbt(entry: &mut JCompound, nested: bool) -> (JCompound, bool) {
+    if nested {
+        let mut nbt = take_compound(entry, "Data").unwrap_or_default();
+        let had_id = nbt.contains_key("id");
+        if let Some(id) = entry.get("Id") {
+            nbt.insert("id", id.clone());
+        }
+        (nbt, had_id)
+    } else {
+        let mut nbt = std::mem::take(entry);
+        if let Some(id) = nbt.remove("Id") {
+            nbt.insert("id", id);
+        }
+        (nbt, false)
+    }
+}
+
+fn from_structure_nbt(entry: &mut JCompound, mut nbt: JCompound, nested: bool, had_id: bool) {
+    if nested {
+        if let Some(id) = nbt.get("id") {
+            entry.insert("Id", id.clone());
+        }
+        if !had_id {
+            nbt.remove("id");
+        }
+        // coming from version 2, where the position was mixed in with the rest of the data
+        if !entry.contains_key("Pos") {
+            if let Some(pos) = nbt.remove("Pos") {
+                entry.insert("Pos", pos);
+            }
+        }
+        if !nbt.is_empty() {
+            entry.insert("Data", nbt);
+        }
+    } else {
+        if let Some(id) = nbt.remove("id") {
+            nbt.insert("Id", id);
+        }
+        *entry = nbt;
+    }
+}
+
+fn sponge_v2_to_v3(schematic: &mut JCompound) {
+    let mut blocks = JCompound::new();
+    for (old_key, new_key) in [
+        ("Palette", "Palette"),
+        ("BlockData", "Data"),
+        ("BlockEntities", "BlockEntities"),
+    ] {
+        if let Some(value) = schematic.remove(old_key) {
+            blocks.insert(new_key, value);
+        }
+    }
+    schematic.remove("PaletteMax");
+    if !blocks.is_empty() {
+        schematic.insert("Blocks", blocks);
+    }
+
+    // biomes were stored per column, and are now stored per block
+    schematic.remove("BiomePaletteMax");
+    let biome_palette = schematic.remove("BiomePalette");
+    let biome_data = schematic.remove("BiomeData");
+    if let (Some(palette), Some(JValue::ByteArray(data))) = (biome_palette, biome_data) {
+        let height = schematic
+            .get("Height")
+            .and_then(|v| v.as_i32())
+            .map_or(0, |height| height as u16 as usize);
+        if let Some(columns) = read_varints(&data) {
+            let data = write_varints((0..height).flat_map(|_| columns.iter().copied()));
+            schematic.insert(
+                "Biomes",
+                jcompound! {
+                    "Palette" => palette,
+                    "Data" => JValue::ByteArray(data),
+                },
+            );
+        }
+    }
+
+    schematic.insert("Version", 3);
+}
+
+/// Parses a block state such as `minecraft:oak_stairs[facing=east,half=top]`.
+fn parse_block_state(state: &str) -> JCompound {
+    let (name, properties) = match state.split_once('[') {
+        Some((name, properties)) => (name, properties.strip_suffix(']').unwrap_or(properties)),
+        None => (state, ""),
+    };
+    let mut result = jcompound! { "Name" => JavaStr::from_str(name) };
+    if !properties.is_empty() {
+        let mut property_compound = JCompound::new();
+        for property in properties.split(',') {
+            if let Some((key, value)) = property.split_once('=') {
+                property_compound.insert(key, JavaStr::from_str(value));
+            }
+        }
+        result.insert("Properties", property_compound);
+    }
+    result
+}
+
+fn stringify_block_state(state: &JCompound) -> Option<String> {
+    let Some(JValue::String(name)) = state.get("Name") else {
+        return None;
+    };
+    let mut result = name.as_str_lossy().into_owned();
+    if let Some(JValue::Compound(properties)) = state.get("Properties") {
+        let properties: Vec<_> = properties
+            .iter()
+            .filter_map(|(key, value)| match value {
+                JValue::String(value) => {
+                    Some(format!("{}={}", key.as_str_lossy(), value.as_str_lossy()))
+                }
+                _ => None,
+            })
+            .collect();
+        if !properties.is_empty() {
+            result.push('[');
+            result.push_str(&properties.join(","));
+            result.push(']');
+        }
+    }
+    Some(result)
+}
+
+fn read_varints(bytes: &[i8]) -> Option<Vec<u32>> {
+    let mut result = Vec::new();
+    let mut value = 0;
+    let mut shift = 0;
+    for &byte in bytes {
+        value |= ((byte & 0x7f) as u32) << shift;
+        if byte >= 0 {
+            result.push(value);
+            value = 0;
+            shift = 0;
+        } else {
+            shift += 7;
+            if shift >= 32 {
+                return None;
+            }
+        }
+    }
+    (shift == 0).then_some(result)
+}
+
+fn write_varints(values: impl Iterator<Item = u32>) -> Vec<i8> {
+    let mut result = Vec::new();
+    for mut value in values {
+        while value >= 0x80 {
+            result.push(((value & 0x7f) | 0x80) as u8 as i8);
+            value >>= 7;
+        }
+        result.push(value as i8);
+    }
+    result
+}
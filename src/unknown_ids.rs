@@ -0,0 +1,440 @@
+use ahash::{AHashMap, AHashSet};
+use java_string::{JavaStr, JavaString};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tracing::{error, info, warn};
+use valence_nbt::jcompound;
+use world_transmuter::json::{parse_compound, stringify_compound};
+use world_transmuter_engine::{JCompound, JList, JValue};
+
+/// How many chunks to list in the report for each unknown ID.
+const MAX_SAMPLES: usize = 5;
+
+/// Collects IDs which the target version doesn't know about, and replaces IDs using a remap table.
+pub struct UnknownIds {
+    /// Where to write the report of unknown IDs, or `None` if they aren't being collected.
+    report_path: Option<PathBuf>,
+    /// IDs to replace after chunks have been upgraded.
+    remap: AHashMap<JavaString, JavaString>,
+    /// The IDs in the registries of the target version, or `None` if only IDs outside the
+    /// `minecraft` namespace are treated as unknown.
+    registries: Option<AHashMap<IdKind, AHashSet<JavaString>>>,
+    found: Mutex<BTreeMap<(IdKind, JavaString), Occurrences>>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum IdKind {
+    Block,
+    BlockEntity,
+    Entity,
+    Item,
+}
+
+impl IdKind {
+    const ALL: [IdKind; 4] = [
+        IdKind::Block,
+        IdKind::BlockEntity,
+        IdKind::Entity,
+        IdKind::Item,
+    ];
+
+    /// The registry in the `registries.json` report listing the IDs of this kind.
+    fn registry(self) -> &'static str {
+        match self {
+            IdKind::Block => "minecraft:block",
+            IdKind::BlockEntity => "minecraft:block_entity_type",
+            IdKind::Entity => "minecraft:entity_type",
+            IdKind::Item => "minecraft:item",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            IdKind::Block => "blocks",
+            IdKind::BlockEntity => "block_entities",
+            IdKind::Entity => "entities",
+            IdKind::Item => "items",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Occurrences {
+    count: u64,
+    samples: Vec<(JavaString, i32, i32)>,
+}
+
+/// Reads the IDs of each kind out of a `registries.json` report, as generated by running the server
+/// with `--reports`.
+fn read_registries(path: &Path) -> Option<AHashMap<IdKind, AHashSet<JavaString>>> {
+    let json = match std::fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(err) => {
+            error!(
+                "Failed to read registries {}: {}",
+                path.to_string_lossy(),
+                err
+            );
+            return None;
+        }
+    };
+    let Ok(mut report) = parse_compound(JavaStr::from_str(&json), false) else {
+        error!("Failed to parse registries {}", path.to_string_lossy());
+        return None;
+    };
+
+    let mut registries = AHashMap::new();
+    for kind in IdKind::ALL {
+        let entries = match report.remove(kind.registry()) {
+            Some(JValue::Compound(mut registry)) => match registry.remove("entries") {
+                Some(JValue::Compound(entries)) => entries,
+                _ => JCompound::new(),
+            },
+            _ => JCompound::new(),
+        };
+        if entries.is_empty() {
+            error!(
+                "Registries {} have no entries for {}",
+                path.to_string_lossy(),
+                kind.registry()
+            );
+            return None;
+        }
+        registries.insert(kind, entries.into_iter().map(|(id, _)| id).collect());
+    }
+    Some(registries)
+}
+
+/// Applies the remap table to a chunk, and collects the IDs in it which are unknown. Returns whether
+/// any ID was replaced.
+pub fn process_chunk(
+    unknown_ids: Option<&UnknownIds>,
+    dim_id: &JavaStr,
+    chunk_x: i32,
+    chunk_z: i32,
+    chunk: &mut JCompound,
+) -> bool {
+    let Some(unknown_ids) = unknown_ids else {
+        return false;
+    };
+
+    let mut found = Vec::new();
+    let mut visitor = IdVisitor {
+        unknown_ids,
+        found: &mut found,
+        remapped: false,
+    };
+    // chunks from before 21w43a keep everything in a Level tag
+    let chunk = if matches!(chunk.get("Level"), Some(JValue::Compound(_))) {
+        let Some(JValue::Compound(level)) = chunk.get_mut("Level") else {
+            unreachable!()
+        };
+        level
+    } else {
+        chunk
+    };
+    for (key, value) in chunk.iter_mut() {
+        match (key.as_bytes(), value) {
+            (b"Sections" | b"sections", JValue::List(JList::Compound(sections))) => {
+                for section in sections {
+                    visitor.visit_section(section);
+                }
+            }
+            (
+                b"TileEntities" | b"block_entities",
+                JValue::List(JList::Compound(block_entities)),
+            ) => {
+                for block_entity in block_entities {
+                    visitor.visit_block_entity(block_entity);
+                }
+            }
+            (b"Entities" | b"entities", JValue::List(JList::Compound(entities))) => {
+                for entity in entities {
+                    visitor.visit_entity(entity);
+                }
+            }
+            _ => {}
+        }
+    }
+    let remapped = visitor.remapped;
+    unknown_ids.record(dim_id, chunk_x, chunk_z, found);
+    remapped
+}
+
+/// Applies the remap table to an entity chunk, and collects the IDs in it which are unknown. Returns
+/// whether any ID was replaced.
+pub fn process_entity_chunk(
+    unknown_ids: Option<&UnknownIds>,
+    dim_id: &JavaStr,
+    chunk_x: i32,
+    chunk_z: i32,
+    chunk: &mut JCompound,
+) -> bool {
+    let Some(unknown_ids) = unknown_ids else {
+        return false;
+    };
+
+    let mut found = Vec::new();
+    let mut visitor = IdVisitor {
+        unknown_ids,
+        found: &mut found,
+        remapped: false,
+    };
+    if let Some(JValue::List(JList::Compound(entities))) = chunk.get_mut("Entities") {
+        for entity in entities {
+            visitor.visit_entity(entity);
+        }
+    }
+    let remapped = visitor.remapped;
+    unknown_ids.record(dim_id, chunk_x, chunk_z, found);
+    remapped
+}
+
+/// Writes the report of unknown IDs, if one was asked for.
+pub fn write_report(unknown_ids: Option<&UnknownIds>) {
+    let Some(unknown_ids) = unknown_ids else {
+        return;
+    };
+    let Some(report_path) = &unknown_ids.report_path else {
+        return;
+    };
+
+    let found = unknown_ids.found.lock().unwrap();
+    let mut report = JCompound::new();
+    for kind in IdKind::ALL {
+        let mut ids = JCompound::new();
+        for ((_, id), occurrences) in found.iter().filter(|((k, _), _)| *k == kind) {
+            let samples = occurrences
+                .samples
+                .iter()
+                .map(|(dim_id, chunk_x, chunk_z)| {
+                    jcompound! {
+                        "dimension" => dim_id.clone(),
+                        "x" => *chunk_x,
+                        "z" => *chunk_z,
+                    }
+                })
+                .collect();
+            ids.insert(
+                id.clone(),
+                jcompound! {
+                    "count" => occurrences.count as i64,
+                    "samples" => JList::Compound(samples),
+                },
+            );
+        }
+        report.insert(kind.name(), ids);
+    }
+
+    if found.is_empty() {
+        info!("Found no unknown IDs");
+    } else {
+        warn!(
+            "Found {} unknown IDs, see {}",
+            found.len(),
+            report_path.to_string_lossy()
+        );
+    }
+    if let Err(err) = std::fs::write(report_path, stringify_compound(report, false, true)) {
+        error!(
+            "Failed to write unknown ID report {}: {}",
+            report_path.to_string_lossy(),
+            err
+        );
+    }
+}
+
+impl UnknownIds {
+    /// Sets up collecting unknown IDs into a report, and replacing IDs using a JSON object mapping
+    /// old IDs to new ones. IDs are looked up in the `registries.json` report generated by the
+    /// server of the target version, if one is given. Returns `None` if the remap table or
+    /// registries couldn't be loaded.
+    pub fn load(
+        report_path: Option<&Path>,
+        remap_path: Option<&Path>,
+        registries_path: Option<&Path>,
+    ) -> Option<Self> {
+        let registries = match registries_path {
+            Some(registries_path) => match read_registries(registries_path) {
+                Some(registries) => Some(registries),
+                None => return None,
+            },
+            None => {
+                if report_path.is_some() {
+                    warn!("No --registries given, only IDs outside the minecraft namespace will be reported");
+                }
+                None
+            }
+        };
+
+        let mut remap = AHashMap::new();
+        if let Some(remap_path) = remap_path {
+            let json = match std::fs::read_to_string(remap_path) {
+                Ok(json) => json,
+                Err(err) => {
+                    error!(
+                        "Failed to read remap table {}: {}",
+                        remap_path.to_string_lossy(),
+                        err
+                    );
+                    return None;
+                }
+            };
+            let Ok(table) = parse_compound(JavaStr::from_str(&json), false) else {
+                error!(
+                    "Failed to parse remap table {}",
+                    remap_path.to_string_lossy()
+                );
+                return None;
+            };
+            for (from, to) in table {
+                let JValue::String(to) = to else {
+                    error!("Remap table entry for {from} isn't a string");
+                    return None;
+                };
+                remap.insert(from, to);
+            }
+        }
+
+        Some(Self {
+            report_path: report_path.map(Path::to_path_buf),
+            remap,
+            registries,
+            found: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Whether an ID is in the registries of the target version. Without the registries, anything
+    /// in the `minecraft` namespace is assumed to be known.
+    fn is_known(&self, kind: IdKind, id: &JavaStr) -> bool {
+        let Some(registries) = &self.registries else {
+            return match id.find(':') {
+                Some(colon_index) => &id[..colon_index] == "minecraft",
+                None => true,
+            };
+        };
+        let registry = &registries[&kind];
+        if id.contains(':') {
+            registry.contains(id)
+        } else {
+            registry.contains(&JavaString::from(format!("minecraft:{id}")))
+        }
+    }
+
+    fn record(
+        &self,
+        dim_id: &JavaStr,
+        chunk_x: i32,
+        chunk_z: i32,
+        found: Vec<(IdKind, JavaString)>,
+    ) {
+        if self.report_path.is_none() || found.is_empty() {
+            return;
+        }
+        let mut all_found = self.found.lock().unwrap();
+        for key in found {
+            let occurrences = all_found.entry(key).or_default();
+            occurrences.count += 1;
+            let sample = (dim_id.to_owned(), chunk_x, chunk_z);
+            if occurrences.samples.len() < MAX_SAMPLES && !occurrences.samples.contains(&sample) {
+                occurrences.samples.push(sample);
+            }
+        }
+    }
+}
+
+struct IdVisitor<'a> {
+    unknown_ids: &'a UnknownIds,
+    found: &'a mut Vec<(IdKind, JavaString)>,
+    remapped: bool,
+}
+
+impl IdVisitor<'_> {
+    fn visit_id(&mut self, kind: IdKind, id: &mut JavaString) {
+        if let Some(new_id) = self.unknown_ids.remap.get(&id[..]) {
+            *id = new_id.clone();
+            self.remapped = true;
+        }
+        if !self.unknown_ids.is_known(kind, id) {
+            self.found.push((kind, id.clone()));
+        }
+    }
+
+    fn visit_section(&mut self, section: &mut JCompound) {
+        // the palette moved into block_states in 21w37a
+        let palette = if matches!(section.get("block_states"), Some(JValue::Compound(_))) {
+            let Some(JValue::Compound(block_states)) = section.get_mut("block_states") else {
+                unreachable!()
+            };
+            block_states.get_mut("palette")
+        } else {
+            section.get_mut("Palette")
+        };
+        if let Some(JValue::List(JList::Compound(palette))) = palette {
+            for state in palette {
+                if let Some(JValue::String(name)) = state.get_mut("Name") {
+                    self.visit_id(IdKind::Block, name);
+                }
+            }
+        }
+    }
+
+    fn visit_block_entity(&mut self, block_entity: &mut JCompound) {
+        for (key, value) in block_entity.iter_mut() {
+            match value {
+                JValue::String(id) if key == "id" => self.visit_id(IdKind::BlockEntity, id),
+                _ => self.visit_items(value),
+            }
+        }
+    }
+
+    fn visit_entity(&mut self, entity: &mut JCompound) {
+        for (key, value) in entity.iter_mut() {
+            match value {
+                JValue::String(id) if key == "id" => self.visit_id(IdKind::Entity, id),
+                JValue::List(JList::Compound(passengers)) if key == "Passengers" => {
+                    for passenger in passengers {
+                        self.visit_entity(passenger);
+                    }
+                }
+                _ => self.visit_items(value),
+            }
+        }
+    }
+
+    /// Looks for item stacks anywhere inside a value, which are compounds with an ID and a count.
+    fn visit_items(&mut self, value: &mut JValue) {
+        match value {
+            JValue::Compound(compound) => self.visit_items_in_compound(compound),
+            JValue::List(list) => self.visit_items_in_list(list),
+            _ => {}
+        }
+    }
+
+    fn visit_items_in_compound(&mut self, compound: &mut JCompound) {
+        let is_item = compound.contains_key("Count") || compound.contains_key("count");
+        for (key, value) in compound.iter_mut() {
+            match value {
+                JValue::String(id) if is_item && key == "id" => self.visit_id(IdKind::Item, id),
+                _ => self.visit_items(value),
+            }
+        }
+    }
+
+    fn visit_items_in_list(&mut self, list: &mut JList) {
+        match list {
+            JList::Compound(compounds) => {
+                for compound in compounds {
+                    self.visit_items_in_compound(compound);
+                }
+            }
+            JList::List(lists) => {
+                for list in lists {
+                    self.visit_items_in_list(list);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::*;
+use std::ffi::OsStr;
+
+fn check_regions(world: &TestWorld, args: &[&str]) -> bool {
+    let mut all_args = vec![OsStr::new("check-regions"), world.path.as_os_str()];
+    all_args.extend(args.iter().map(OsStr::new));
+    run(&all_args)
+}
+
+#[test]
+fn repairs_damaged_region_header() {
+    let world = TestWorld::new("check_regions");
+    world.write_chunk("region", 0, 0, &new_format_chunk(0, 0, V1_20_4));
+    world.write_chunk("region", 1, 0, &new_format_chunk(1, 0, V1_20_4));
+    assert!(check_regions(&world, &[]));
+
+    // point chunk 2, 0 past the end of the file, and give chunk 3, 0 no sectors
+    let region_path = world.join("region/r.0.0.mca");
+    let mut region = std::fs::read(&region_path).unwrap();
+    region[8..12].copy_from_slice(&((1000u32 << 8) | 1).to_be_bytes());
+    region[12..16].copy_from_slice(&(5u32 << 8).to_be_bytes());
+    std::fs::write(&region_path, &region).unwrap();
+
+    assert!(!check_regions(&world, &[]));
+    assert_eq!(std::fs::read(&region_path).unwrap(), region);
+
+    assert!(check_regions(&world, &["--repair"]));
+    assert_eq!(
+        std::fs::read(world.join("region/r.0.0.mca.bak")).unwrap(),
+        region
+    );
+    assert!(world.read_chunk("region", 0, 0).is_some());
+    assert!(world.read_chunk("region", 1, 0).is_some());
+    assert!(world.read_chunk("region", 2, 0).is_none());
+    assert!(world.read_chunk("region", 3, 0).is_none());
+    assert!(check_regions(&world, &[]));
+}
+
+#[test]
+fn keeps_earlier_backups_when_repairing_again() {
+    let world = TestWorld::new("check_regions");
+    world.write_chunk("region", 0, 0, &new_format_chunk(0, 0, V1_20_4));
+    let region_path = world.join("region/r.0.0.mca");
+
+    let mut damaged_regions = Vec::new();
+    for _ in 0..2 {
+        // give chunk 1, 0 no sectors
+        let mut region = std::fs::read(&region_path).unwrap();
+        region[4..8].copy_from_slice(&(5u32 << 8).to_be_bytes());
+        std::fs::write(&region_path, &region).unwrap();
+        assert!(check_regions(&world, &["--repair"]));
+        damaged_regions.push(region);
+    }
+
+    assert_eq!(
+        std::fs::read(world.join("region/r.0.0.mca.bak")).unwrap(),
+        damaged_regions[0]
+    );
+    assert_eq!(
+        std::fs::read(world.join("region/r.0.0.mca.bak.1")).unwrap(),
+        damaged_regions[1]
+    );
+    assert!(world.read_chunk("region", 0, 0).is_some());
+    assert!(check_regions(&world, &[]));
+}
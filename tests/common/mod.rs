@@ -0,0 +1,358 @@
+#![allow(dead_code)]
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use java_string::{JavaStr, JavaString};
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use valence_anvil::{RawChunk, RegionFolder};
+use valence_nbt::{from_binary, jcompound, to_binary};
+use world_transmuter::json::{parse_compound, stringify_compound};
+use world_transmuter_engine::{JCompound, JList, JValue};
+
+pub const V1_12_2: i32 = 1343;
+pub const V1_13: i32 = 1519;
+pub const V1_17_1: i32 = 2730;
+pub const V1_18_2: i32 = 2975;
+pub const V1_20_4: i32 = 3700;
+
+pub const PLAYER_UUID: &str = "01234567-89ab-cdef-0123-456789abcdef";
+
+/// Runs the CLI with the given arguments, returning whether it exited successfully.
+pub fn run(args: &[&OsStr]) -> bool {
+    Command::new(env!("CARGO_BIN_EXE_world-transmuter-cli"))
+        .args(args)
+        .status()
+        .unwrap()
+        .success()
+}
+
+/// Runs the CLI with the given arguments and stdin, returning whether it exited successfully and
+/// what it wrote to stdout.
+pub fn run_with_stdin(args: &[&OsStr], stdin: &str) -> (bool, String) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_world-transmuter-cli"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    (
+        output.status.success(),
+        String::from_utf8(output.stdout).unwrap(),
+    )
+}
+
+/// A world folder in the temp directory, deleted when dropped.
+pub struct TestWorld {
+    pub path: PathBuf,
+}
+
+impl TestWorld {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "world-transmuter-cli-test-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    pub fn join(&self, path: impl AsRef<Path>) -> PathBuf {
+        self.path.join(path)
+    }
+
+    /// Runs the upgrader on this world, panicking if it doesn't exit successfully.
+    pub fn upgrade(&self, to_version: &str, args: &[&str]) {
+        let status = Command::new(env!("CARGO_BIN_EXE_world-transmuter-cli"))
+            .arg(&self.path)
+            .arg(to_version)
+            .args(args)
+            .status()
+            .unwrap();
+        assert!(status.success(), "upgrade exited with {status}");
+    }
+
+    pub fn write_level_dat(&self, data_version: i32) {
+        let mut data = jcompound! {
+            "DataVersion" => data_version,
+            "version" => 19133,
+            "initialized" => true,
+            "LevelName" => JavaStr::from_str("test"),
+            "GameType" => 0,
+        };
+        if data_version < 2554 {
+            data.insert("RandomSeed", 1i64);
+            data.insert("generatorName", JavaStr::from_str("default"));
+            data.insert("MapFeatures", true);
+        } else {
+            data.insert(
+                "WorldGenSettings",
+                jcompound! {
+                    "seed" => 1i64,
+                    "generate_features" => true,
+                    "dimensions" => JCompound::new(),
+                },
+            );
+        }
+        write_dat(&self.join("level.dat"), &jcompound! { "Data" => data });
+    }
+
+    pub fn read_level_dat(&self) -> JCompound {
+        match read_dat(&self.join("level.dat")).remove("Data") {
+            Some(JValue::Compound(data)) => data,
+            _ => panic!("level.dat has no Data"),
+        }
+    }
+
+    pub fn write_chunk(
+        &self,
+        regions: impl AsRef<Path>,
+        chunk_x: i32,
+        chunk_z: i32,
+        chunk: &JCompound,
+    ) {
+        let regions = self.join(regions);
+        std::fs::create_dir_all(&regions).unwrap();
+        RegionFolder::new(regions)
+            .set_chunk(chunk_x, chunk_z, chunk)
+            .unwrap();
+    }
+
+    pub fn read_chunk(
+        &self,
+        regions: impl AsRef<Path>,
+        chunk_x: i32,
+        chunk_z: i32,
+    ) -> Option<JCompound> {
+        let chunk: Option<RawChunk<JavaString>> = RegionFolder::new(self.join(regions))
+            .get_chunk(chunk_x, chunk_z)
+            .unwrap();
+        chunk.map(|chunk| chunk.data)
+    }
+}
+
+impl Drop for TestWorld {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+pub fn write_dat(path: &Path, data: &JCompound) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let mut encoder = GzEncoder::new(std::fs::File::create(path).unwrap(), Compression::default());
+    to_binary(data, &mut encoder, "").unwrap();
+    encoder.finish().unwrap();
+}
+
+pub fn read_dat(path: &Path) -> JCompound {
+    let mut contents = Vec::new();
+    GzDecoder::new(std::fs::File::open(path).unwrap())
+        .read_to_end(&mut contents)
+        .unwrap();
+    from_binary(&mut &contents[..]).unwrap().0
+}
+
+pub fn write_json(path: &Path, data: JCompound) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, stringify_compound(data, true, true)).unwrap();
+}
+
+pub fn read_json(path: &Path) -> JCompound {
+    let json = std::fs::read_to_string(path).unwrap();
+    parse_compound(JavaStr::from_str(&json), true).unwrap()
+}
+
+pub fn data_version(data: &JCompound) -> Option<i32> {
+    data.get("DataVersion").and_then(|v| v.as_i32())
+}
+
+pub fn pig(x: f64, z: f64) -> JCompound {
+    jcompound! {
+        "id" => JavaStr::from_str("minecraft:pig"),
+        "Pos" => JList::Double(vec![x, 64.0, z]),
+        "Motion" => JList::Double(vec![0.0, 0.0, 0.0]),
+        "Rotation" => JList::Float(vec![0.0, 0.0]),
+        "UUIDMost" => 0x0123_4567_89ab_4defi64,
+        "UUIDLeast" => -0x7edc_ba98_7654_3210i64,
+        "Health" => 10.0f32,
+    }
+}
+
+/// A fully generated chunk in the pre-1.13 format, with a pig and a chest in it.
+pub fn legacy_chunk(chunk_x: i32, chunk_z: i32) -> JCompound {
+    jcompound! {
+        "DataVersion" => V1_12_2,
+        "Level" => jcompound! {
+            "xPos" => chunk_x,
+            "zPos" => chunk_z,
+            "LastUpdate" => 0i64,
+            "InhabitedTime" => 0i64,
+            "TerrainPopulated" => true,
+            "LightPopulated" => true,
+            "V" => 1i8,
+            "Biomes" => JValue::ByteArray(vec![1; 256]),
+            "HeightMap" => JValue::IntArray(vec![1; 256]),
+            "Sections" => JList::Compound(vec![jcompound! {
+                "Y" => 0i8,
+                "Blocks" => JValue::ByteArray(vec![1; 4096]),
+                "Data" => JValue::ByteArray(vec![0; 2048]),
+                "BlockLight" => JValue::ByteArray(vec![0; 2048]),
+                "SkyLight" => JValue::ByteArray(vec![0; 2048]),
+            }]),
+            "Entities" => JList::Compound(vec![pig(
+                (chunk_x * 16 + 8) as f64,
+                (chunk_z * 16 + 8) as f64,
+            )]),
+            "TileEntities" => JList::Compound(vec![jcompound! {
+                "id" => JavaStr::from_str("minecraft:chest"),
+                "x" => chunk_x * 16,
+                "y" => 20,
+                "z" => chunk_z * 16,
+                "Items" => JList::Compound(vec![jcompound! {
+                    "Slot" => 0i8,
+                    "id" => JavaStr::from_str("minecraft:stone"),
+                    "Count" => 1i8,
+                    "Damage" => 0i16,
+                }]),
+            }]),
+        },
+    }
+}
+
+/// A fully generated chunk in the 1.13 to 1.17 format.
+pub fn paletted_chunk(chunk_x: i32, chunk_z: i32, data_version: i32, status: &str) -> JCompound {
+    // biomes were stored per column until 19w36a
+    let num_biomes = if data_version < 2203 { 256 } else { 1024 };
+    jcompound! {
+        "DataVersion" => data_version,
+        "Level" => jcompound! {
+            "xPos" => chunk_x,
+            "zPos" => chunk_z,
+            "Status" => JavaStr::from_str(status),
+            "LastUpdate" => 0i64,
+            "InhabitedTime" => 0i64,
+            "Biomes" => JValue::IntArray(vec![1; num_biomes]),
+            "Sections" => JList::Compound(vec![jcompound! {
+                "Y" => 0i8,
+                "Palette" => JList::Compound(vec![jcompound! {
+                    "Name" => JavaStr::from_str("minecraft:stone"),
+                }]),
+                "BlockStates" => JValue::LongArray(vec![0; 256]),
+            }]),
+            "Entities" => JList::Compound(vec![pig(
+                (chunk_x * 16 + 8) as f64,
+                (chunk_z * 16 + 8) as f64,
+            )]),
+            "TileEntities" => JList::Compound(Vec::new()),
+        },
+    }
+}
+
+/// A chunk in the format used since 1.18.
+pub fn new_format_chunk(chunk_x: i32, chunk_z: i32, data_version: i32) -> JCompound {
+    jcompound! {
+        "DataVersion" => data_version,
+        "xPos" => chunk_x,
+        "yPos" => -4,
+        "zPos" => chunk_z,
+        "Status" => JavaStr::from_str("minecraft:full"),
+        "LastUpdate" => 0i64,
+        "InhabitedTime" => 0i64,
+        "sections" => JList::Compound(vec![jcompound! {
+            "Y" => 0i8,
+            "block_states" => jcompound! {
+                "palette" => JList::Compound(vec![jcompound! {
+                    "Name" => JavaStr::from_str("minecraft:stone"),
+                }]),
+            },
+            "biomes" => jcompound! {
+                "palette" => JList::String(vec![JavaString::from("minecraft:plains")]),
+            },
+        }]),
+        "block_entities" => JList::Compound(Vec::new()),
+    }
+}
+
+pub fn entity_chunk(chunk_x: i32, chunk_z: i32, data_version: i32) -> JCompound {
+    jcompound! {
+        "DataVersion" => data_version,
+        "Position" => JValue::IntArray(vec![chunk_x, chunk_z]),
+        "Entities" => JList::Compound(vec![pig(
+            (chunk_x * 16 + 8) as f64,
+            (chunk_z * 16 + 8) as f64,
+        )]),
+    }
+}
+
+pub fn poi_chunk(data_version: i32) -> JCompound {
+    jcompound! {
+        "DataVersion" => data_version,
+        "Sections" => JCompound::new(),
+    }
+}
+
+pub fn player(data_version: i32) -> JCompound {
+    jcompound! {
+        "DataVersion" => data_version,
+        "Pos" => JList::Double(vec![0.0, 64.0, 0.0]),
+        "Dimension" => 0,
+        "Inventory" => JList::Compound(vec![jcompound! {
+            "Slot" => 0i8,
+            "id" => JavaStr::from_str("minecraft:stone"),
+            "Count" => 1i8,
+            "Damage" => 0i16,
+        }]),
+    }
+}
+
+pub fn map_data(data_version: i32) -> JCompound {
+    jcompound! {
+        "DataVersion" => data_version,
+        "data" => jcompound! {
+            "dimension" => 0i8,
+            "scale" => 0i8,
+            "xCenter" => 0,
+            "zCenter" => 0,
+            "colors" => JValue::ByteArray(vec![0; 16384]),
+        },
+    }
+}
+
+/// Legacy structure data as saved before 1.13, with a single structure starting in chunk 0, 0.
+pub fn legacy_structure_data(id: &str) -> JCompound {
+    jcompound! {
+        "data" => jcompound! {
+            "Features" => jcompound! {
+                "[0,0]" => jcompound! {
+                    "id" => JavaStr::from_str(id),
+                    "ChunkX" => 0,
+                    "ChunkZ" => 0,
+                    "BB" => JValue::IntArray(vec![0, 0, 0, 15, 80, 15]),
+                    "Children" => JList::Compound(Vec::new()),
+                },
+            },
+        },
+    }
+}
+
+pub fn raids(data_version: i32) -> JCompound {
+    jcompound! {
+        "DataVersion" => data_version,
+        "data" => jcompound! {
+            "NextAvailableID" => 1,
+            "Tick" => 0,
+            "Raids" => JList::Compound(Vec::new()),
+        },
+    }
+}
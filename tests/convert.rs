@@ -0,0 +1,133 @@
+mod common;
+
+use common::*;
+use std::ffi::OsStr;
+use world_transmuter_engine::JValue;
+
+#[test]
+fn converts_player_file() {
+    let dir = TestWorld::new("convert_player");
+    let input = dir.join("player.dat");
+    let output = dir.join("upgraded.dat");
+    write_dat(&input, &player(V1_12_2));
+
+    assert!(run(&[
+        OsStr::new("convert"),
+        input.as_os_str(),
+        OsStr::new("1.20.4"),
+        OsStr::new("--type"),
+        OsStr::new("player"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+
+    assert_eq!(data_version(&read_dat(&output)), Some(V1_20_4));
+    assert_eq!(data_version(&read_dat(&input)), Some(V1_12_2));
+}
+
+#[test]
+fn requires_from_version_without_data_version() {
+    let dir = TestWorld::new("convert_no_version");
+    let input = dir.join("player.dat");
+    let mut data = player(V1_12_2);
+    data.remove("DataVersion");
+    write_dat(&input, &data);
+
+    let args = [
+        OsStr::new("convert"),
+        input.as_os_str(),
+        OsStr::new("1.20.4"),
+        OsStr::new("--type"),
+        OsStr::new("player"),
+        OsStr::new("-o"),
+        input.as_os_str(),
+    ];
+    assert!(!run(&args));
+    assert!(run(&[
+        &args[..],
+        &[OsStr::new("--from"), OsStr::new("1.12.2")]
+    ]
+    .concat()));
+    assert_eq!(data_version(&read_dat(&input)), Some(V1_20_4));
+}
+
+#[test]
+fn converts_region_file() {
+    let world = TestWorld::new("convert_region");
+    world.write_chunk("region", 0, 0, &legacy_chunk(0, 0));
+    let output = world.join("out/region/r.0.0.mca");
+    std::fs::create_dir_all(output.parent().unwrap()).unwrap();
+
+    assert!(run(&[
+        OsStr::new("convert"),
+        world.join("region/r.0.0.mca").as_os_str(),
+        OsStr::new("1.20.4"),
+        OsStr::new("--type"),
+        OsStr::new("chunk"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+
+    let chunk = world.read_chunk("out/region", 0, 0).unwrap();
+    assert_eq!(data_version(&chunk), Some(V1_20_4));
+    assert!(world.read_chunk("out/entities", 0, 0).is_some());
+    let original = world.read_chunk("region", 0, 0).unwrap();
+    assert_eq!(data_version(&original), Some(V1_12_2));
+}
+
+#[test]
+fn converts_snbt_to_json() {
+    let dir = TestWorld::new("convert_snbt");
+    let input = dir.join("player.snbt");
+    let output = dir.join("player.json");
+    std::fs::write(
+        &input,
+        r#"{DataVersion: 1343, Dimension: 0, Pos: [0.5d, 64.0d, 0.5d], Inventory: [{Slot: 0b, id: "minecraft:stone", Count: 1b, Damage: 0s}]}"#,
+    )
+    .unwrap();
+
+    assert!(run(&[
+        OsStr::new("convert"),
+        input.as_os_str(),
+        OsStr::new("1.20.4"),
+        OsStr::new("--type"),
+        OsStr::new("player"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+        OsStr::new("--format"),
+        OsStr::new("json"),
+    ]));
+
+    let data = read_json(&output);
+    assert_eq!(data_version(&data), Some(V1_20_4));
+    assert!(
+        matches!(data.get("Dimension"), Some(JValue::String(dimension)) if dimension == "minecraft:overworld")
+    );
+}
+
+#[test]
+fn converts_level_dat_settings_like_a_world_upgrade() {
+    let dir = TestWorld::new("convert_level_dat");
+    dir.write_level_dat(V1_12_2);
+    let output = dir.join("upgraded.dat");
+
+    assert!(run(&[
+        OsStr::new("convert"),
+        dir.join("level.dat").as_os_str(),
+        OsStr::new("1.20.4"),
+        OsStr::new("--type"),
+        OsStr::new("level"),
+        OsStr::new("-o"),
+        output.as_os_str(),
+    ]));
+
+    let Some(JValue::Compound(data)) = read_dat(&output).remove("Data") else {
+        panic!("level.dat has no Data");
+    };
+    assert_eq!(data_version(&data), Some(V1_20_4));
+    assert!(!data.contains_key("RandomSeed"));
+    let Some(JValue::Compound(world_gen_settings)) = data.get("WorldGenSettings") else {
+        panic!("level.dat has no WorldGenSettings");
+    };
+    assert_eq!(world_gen_settings.get("seed"), Some(&JValue::Long(1)));
+}
@@ -0,0 +1,31 @@
+mod common;
+
+use common::*;
+use std::ffi::OsStr;
+
+#[test]
+fn upgrades_each_record() {
+    let (success, output) = run_with_stdin(
+        &[
+            OsStr::new("pipe"),
+            OsStr::new("--type"),
+            OsStr::new("item_stack"),
+            OsStr::new("--from"),
+            OsStr::new("1.12.2"),
+            OsStr::new("--to"),
+            OsStr::new("1.20.4"),
+        ],
+        "{id: \"minecraft:stone\", Count: 1b, Damage: 1s}\n\nnot a record\n{\"id\": \"minecraft:stone\", \"Count\": 2, \"Damage\": 3}\n",
+    );
+
+    // the record that couldn't be parsed fails the run, but doesn't stop the stream
+    assert!(!success);
+    let lines: Vec<_> = output.lines().collect();
+    assert_eq!(lines.len(), 4);
+    assert!(lines[0].contains("minecraft:granite"));
+    assert!(!lines[0].contains("DataVersion"));
+    assert_eq!(lines[1], "");
+    assert_eq!(lines[2], "not a record");
+    assert!(lines[3].starts_with('{'));
+    assert!(lines[3].contains("\"minecraft:diorite\""));
+}
@@ -0,0 +1,163 @@
+mod common;
+
+use common::*;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use java_string::JavaStr;
+use std::ffi::OsStr;
+use std::path::Path;
+use valence_nbt::{jcompound, to_binary};
+use world_transmuter_engine::{JCompound, JList, JValue};
+
+fn write_named_dat(path: &Path, data: &JCompound, root_name: &str) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let mut encoder = GzEncoder::new(std::fs::File::create(path).unwrap(), Compression::default());
+    to_binary(data, &mut encoder, root_name).unwrap();
+    encoder.finish().unwrap();
+}
+
+fn zombie_pigman() -> JCompound {
+    jcompound! {
+        "id" => JavaStr::from_str("minecraft:zombie_pigman"),
+        "Pos" => JList::Double(vec![0.5, 0.0, 0.5]),
+    }
+}
+
+fn compound<'a>(data: &'a JCompound, key: &str) -> &'a JCompound {
+    match data.get(key) {
+        Some(JValue::Compound(compound)) => compound,
+        _ => panic!("missing {key}"),
+    }
+}
+
+fn compound_list<'a>(data: &'a JCompound, key: &str) -> &'a [JCompound] {
+    match data.get(key) {
+        Some(JValue::List(JList::Compound(list))) => list,
+        _ => panic!("missing {key}"),
+    }
+}
+
+fn is_string(value: Option<&JValue>, expected: &str) -> bool {
+    matches!(value, Some(JValue::String(value)) if value == expected)
+}
+
+#[test]
+fn upgrades_sponge_v2_to_v3() {
+    let dir = TestWorld::new("schematic_sponge");
+    let path = dir.join("house.schem");
+    let mut entity = zombie_pigman();
+    let id = entity.remove("id").unwrap();
+    entity.insert("Id", id);
+    write_named_dat(
+        &path,
+        &jcompound! {
+            "Version" => 2,
+            "DataVersion" => V1_13,
+            "Width" => 2i16,
+            "Height" => 1i16,
+            "Length" => 1i16,
+            "Offset" => JValue::IntArray(vec![0, 0, 0]),
+            "PaletteMax" => 2,
+            "Palette" => jcompound! {
+                "minecraft:air" => 0,
+                "minecraft:grass_path" => 1,
+            },
+            "BlockData" => JValue::ByteArray(vec![0, 1]),
+            "BlockEntities" => JList::Compound(Vec::new()),
+            "Entities" => JList::Compound(vec![entity]),
+        },
+        "Schematic",
+    );
+
+    assert!(run(&[
+        OsStr::new("schematic"),
+        path.as_os_str(),
+        OsStr::new("1.20.4"),
+        OsStr::new("--sponge-version"),
+        OsStr::new("3"),
+    ]));
+
+    let root = read_dat(&path);
+    let schematic = compound(&root, "Schematic");
+    assert_eq!(schematic.get("Version").and_then(|v| v.as_i32()), Some(3));
+    assert_eq!(data_version(schematic), Some(V1_20_4));
+    let blocks = compound(schematic, "Blocks");
+    assert!(compound(blocks, "Palette").contains_key("minecraft:dirt_path"));
+    assert!(matches!(blocks.get("Data"), Some(JValue::ByteArray(data)) if data == &[0, 1]));
+    let entity = &compound_list(schematic, "Entities")[0];
+    assert!(is_string(entity.get("Id"), "minecraft:zombified_piglin"));
+    assert!(entity.contains_key("Pos"));
+}
+
+#[test]
+fn keeps_schematic_when_writing_fails() {
+    let dir = TestWorld::new("schematic_write_fails");
+    let path = dir.join("house.schem");
+    let schematic = jcompound! {
+        "Version" => 2,
+        "DataVersion" => V1_13,
+        "Width" => 1i16,
+        "Height" => 1i16,
+        "Length" => 1i16,
+        "PaletteMax" => 1,
+        "Palette" => jcompound! { "minecraft:grass_path" => 0 },
+        "BlockData" => JValue::ByteArray(vec![0]),
+    };
+    write_named_dat(&path, &schematic, "Schematic");
+    let original = std::fs::read(&path).unwrap();
+    // a folder in the way of the temporary file makes the write fail
+    std::fs::create_dir(dir.join("house.schem.tmp")).unwrap();
+
+    assert!(!run(&[
+        OsStr::new("schematic"),
+        path.as_os_str(),
+        OsStr::new("1.20.4"),
+    ]));
+
+    assert_eq!(std::fs::read(&path).unwrap(), original);
+}
+
+#[test]
+fn upgrades_litematica_folder() {
+    let dir = TestWorld::new("schematic_litematica");
+    let path = dir.join("builds/tower.litematic");
+    write_named_dat(
+        &path,
+        &jcompound! {
+            "Version" => 5,
+            "MinecraftDataVersion" => V1_13,
+            "Metadata" => jcompound! { "Name" => JavaStr::from_str("tower") },
+            "Regions" => jcompound! {
+                "tower" => jcompound! {
+                    "Position" => jcompound! { "x" => 0, "y" => 0, "z" => 0 },
+                    "Size" => jcompound! { "x" => 1, "y" => 1, "z" => 1 },
+                    "BlockStatePalette" => JList::Compound(vec![
+                        jcompound! { "Name" => JavaStr::from_str("minecraft:air") },
+                        jcompound! { "Name" => JavaStr::from_str("minecraft:grass_path") },
+                    ]),
+                    "BlockStates" => JValue::LongArray(vec![1]),
+                    "TileEntities" => JList::Compound(Vec::new()),
+                    "Entities" => JList::Compound(vec![zombie_pigman()]),
+                },
+            },
+        },
+        "",
+    );
+
+    assert!(run(&[
+        OsStr::new("schematic"),
+        dir.path.as_os_str(),
+        OsStr::new("1.20.4"),
+    ]));
+
+    let root = read_dat(&path);
+    assert_eq!(
+        root.get("MinecraftDataVersion").and_then(|v| v.as_i32()),
+        Some(V1_20_4)
+    );
+    let region = compound(compound(&root, "Regions"), "tower");
+    let palette = compound_list(region, "BlockStatePalette");
+    assert!(is_string(palette[1].get("Name"), "minecraft:dirt_path"));
+    let entity = &compound_list(region, "Entities")[0];
+    assert!(is_string(entity.get("id"), "minecraft:zombified_piglin"));
+}
@@ -0,0 +1,732 @@
+mod common;
+
+use common::*;
+use java_string::JavaStr;
+use std::ffi::OsStr;
+use valence_nbt::jcompound;
+use world_transmuter_engine::{JList, JValue};
+
+#[test]
+fn upgrades_1_12_2_world() {
+    let world = TestWorld::new("1_12_2");
+    world.write_level_dat(V1_12_2);
+    world.write_chunk("region", 0, 0, &legacy_chunk(0, 0));
+    world.write_chunk("region", 1, 0, &legacy_chunk(1, 0));
+    world.write_chunk("DIM-1/region", 0, 0, &legacy_chunk(0, 0));
+    write_dat(
+        &world.join(format!("playerdata/{PLAYER_UUID}.dat")),
+        &player(V1_12_2),
+    );
+    write_json(
+        &world.join(format!("stats/{PLAYER_UUID}.json")),
+        jcompound! { "stat.walkOneCm" => 100 },
+    );
+    write_json(
+        &world.join(format!("advancements/{PLAYER_UUID}.json")),
+        jcompound! {
+            "minecraft:story/root" => jcompound! {
+                "criteria" => jcompound! {
+                    "crafting_table" => JavaStr::from_str("2017-09-18 12:00:00 +0000"),
+                },
+                "done" => true,
+            },
+        },
+    );
+    write_dat(&world.join("data/idcounts.dat"), &jcompound! { "map" => 0 });
+    write_dat(&world.join("data/map_0.dat"), &map_data(V1_12_2));
+    write_dat(
+        &world.join("data/Village.dat"),
+        &legacy_structure_data("Village"),
+    );
+    write_dat(
+        &world.join("data/Fortress.dat"),
+        &legacy_structure_data("Fortress"),
+    );
+
+    world.upgrade("1.20.4", &[]);
+
+    assert_eq!(data_version(&world.read_level_dat()), Some(V1_20_4));
+
+    for (regions, chunk_x) in [("region", 0), ("region", 1), ("DIM-1/region", 0)] {
+        let chunk = world.read_chunk(regions, chunk_x, 0).unwrap();
+        assert_eq!(data_version(&chunk), Some(V1_20_4), "{regions} {chunk_x}");
+        assert!(!chunk.contains_key("Level"), "chunk wasn't flattened");
+        assert!(!chunk.contains_key("entities"), "entities weren't split");
+    }
+
+    // entities are moved out of the chunks into their own region files
+    for chunk_x in [0, 1] {
+        let entity_chunk = world.read_chunk("entities", chunk_x, 0).unwrap();
+        assert_eq!(data_version(&entity_chunk), Some(V1_20_4));
+        assert!(
+            matches!(entity_chunk.get("Position"), Some(JValue::IntArray(pos)) if pos == &[chunk_x, 0])
+        );
+        let Some(JValue::List(JList::Compound(entities))) = entity_chunk.get("Entities") else {
+            panic!("entity chunk has no entities");
+        };
+        assert_eq!(entities.len(), 1);
+    }
+    assert!(world.read_chunk("DIM-1/entities", 0, 0).is_some());
+
+    let player = read_dat(&world.join(format!("playerdata/{PLAYER_UUID}.dat")));
+    assert_eq!(data_version(&player), Some(V1_20_4));
+
+    let stats = read_json(&world.join(format!("stats/{PLAYER_UUID}.json")));
+    assert_eq!(data_version(&stats), Some(V1_20_4));
+    let advancements = read_json(&world.join(format!("advancements/{PLAYER_UUID}.json")));
+    assert_eq!(data_version(&advancements), Some(V1_20_4));
+
+    let map = read_dat(&world.join("data/map_0.dat"));
+    assert_eq!(data_version(&map), Some(V1_20_4));
+
+    assert!(!world.join("data/Village.dat").exists());
+    assert!(!world.join("data/Fortress.dat").exists());
+}
+
+#[test]
+fn upgrades_1_13_world() {
+    let world = TestWorld::new("1_13");
+    world.write_level_dat(V1_13);
+    world.write_chunk(
+        "region",
+        0,
+        0,
+        &paletted_chunk(0, 0, V1_13, "postprocessed"),
+    );
+    write_dat(
+        &world.join(format!("playerdata/{PLAYER_UUID}.dat")),
+        &player(V1_13),
+    );
+
+    world.upgrade("1.18.2", &[]);
+
+    assert_eq!(data_version(&world.read_level_dat()), Some(V1_18_2));
+    let chunk = world.read_chunk("region", 0, 0).unwrap();
+    assert_eq!(data_version(&chunk), Some(V1_18_2));
+    assert!(chunk.contains_key("sections"));
+    let player = read_dat(&world.join(format!("playerdata/{PLAYER_UUID}.dat")));
+    assert_eq!(data_version(&player), Some(V1_18_2));
+}
+
+#[test]
+fn upgrades_1_17_world() {
+    let world = TestWorld::new("1_17");
+    world.write_level_dat(V1_17_1);
+    world.write_chunk("region", 0, 0, &paletted_chunk(0, 0, V1_17_1, "full"));
+    world.write_chunk("entities", 0, 0, &entity_chunk(0, 0, V1_17_1));
+    world.write_chunk("poi", 0, 0, &poi_chunk(V1_17_1));
+    write_dat(&world.join("DIM-1/data/raids_nether.dat"), &raids(V1_17_1));
+    write_dat(&world.join("DIM1/data/raids_end.dat"), &raids(V1_17_1));
+
+    world.upgrade("1.18.2", &[]);
+
+    let chunk = world.read_chunk("region", 0, 0).unwrap();
+    assert_eq!(data_version(&chunk), Some(V1_18_2));
+    let entity_chunk = world.read_chunk("entities", 0, 0).unwrap();
+    assert_eq!(data_version(&entity_chunk), Some(V1_18_2));
+    let poi_chunk = world.read_chunk("poi", 0, 0).unwrap();
+    assert_eq!(data_version(&poi_chunk), Some(V1_18_2));
+
+    // the nether raids are renamed in 1.18.2-pre2
+    assert!(!world.join("DIM-1/data/raids_nether.dat").exists());
+    let raids = read_dat(&world.join("DIM-1/data/raids.dat"));
+    assert_eq!(data_version(&raids), Some(V1_18_2));
+    let raids = read_dat(&world.join("DIM1/data/raids_end.dat"));
+    assert_eq!(data_version(&raids), Some(V1_18_2));
+}
+
+#[test]
+fn upgrades_1_18_world() {
+    let world = TestWorld::new("1_18");
+    world.write_level_dat(V1_18_2);
+    world.write_chunk("region", 0, 0, &new_format_chunk(0, 0, V1_18_2));
+    world.write_chunk("DIM-1/region", 0, 0, &new_format_chunk(0, 0, V1_18_2));
+    world.write_chunk("entities", 0, 0, &entity_chunk(0, 0, V1_18_2));
+    world.write_chunk("poi", 0, 0, &poi_chunk(V1_18_2));
+    write_dat(
+        &world.join(format!("playerdata/{PLAYER_UUID}.dat")),
+        &player(V1_18_2),
+    );
+    write_dat(&world.join("data/raids.dat"), &raids(V1_18_2));
+    write_dat(&world.join("DIM-1/data/raids.dat"), &raids(V1_18_2));
+
+    // forcing retrogen only affects chunks from before 1.18
+    world.upgrade(
+        "1.20.4",
+        &["--blending", "on", "--below-zero-retrogen", "on"],
+    );
+
+    let level_dat = world.read_level_dat();
+    assert_eq!(data_version(&level_dat), Some(V1_20_4));
+    assert!(level_dat.contains_key("WorldGenSettings"));
+
+    for regions in ["region", "DIM-1/region"] {
+        let chunk = world.read_chunk(regions, 0, 0).unwrap();
+        assert_eq!(data_version(&chunk), Some(V1_20_4), "{regions}");
+        assert!(!chunk.contains_key("blending_data"), "{regions}");
+        assert!(!chunk.contains_key("below_zero_retrogen"), "{regions}");
+        assert_eq!(chunk.get("yPos").and_then(|y| y.as_i32()), Some(-4));
+    }
+    let entity_chunk = world.read_chunk("entities", 0, 0).unwrap();
+    assert_eq!(data_version(&entity_chunk), Some(V1_20_4));
+    let poi_chunk = world.read_chunk("poi", 0, 0).unwrap();
+    assert_eq!(data_version(&poi_chunk), Some(V1_20_4));
+
+    let player = read_dat(&world.join(format!("playerdata/{PLAYER_UUID}.dat")));
+    assert_eq!(data_version(&player), Some(V1_20_4));
+    for raids_file in ["data/raids.dat", "DIM-1/data/raids.dat"] {
+        let raids = read_dat(&world.join(raids_file));
+        assert_eq!(data_version(&raids), Some(V1_20_4), "{raids_file}");
+    }
+}
+
+#[test]
+fn merges_split_entities_into_existing_entity_chunks() {
+    let world = TestWorld::new("merge_entities");
+    world.write_level_dat(V1_13);
+    world.write_chunk("region", 0, 0, &paletted_chunk(0, 0, V1_13, "full"));
+    // the world was opened in a newer version, which already wrote the same pig along with a cow
+    let (most, least) = (0x0123_4567_89ab_4defi64, -0x7edc_ba98_7654_3210i64);
+    let uuid = |most: i64, least: i64| {
+        JValue::IntArray(vec![
+            (most >> 32) as i32,
+            most as i32,
+            (least >> 32) as i32,
+            least as i32,
+        ])
+    };
+    let mut existing_pig = pig(8.0, 8.0);
+    existing_pig.remove("UUIDMost");
+    existing_pig.remove("UUIDLeast");
+    existing_pig.insert("UUID", uuid(most, least));
+    let mut cow = existing_pig.clone();
+    cow.insert("id", JavaStr::from_str("minecraft:cow"));
+    cow.insert("UUID", uuid(most + 1, least));
+    let entity_chunk = jcompound! {
+        "DataVersion" => V1_17_1,
+        "Position" => JValue::IntArray(vec![0, 0]),
+        "Entities" => JList::Compound(vec![existing_pig, cow]),
+    };
+    world.write_chunk("entities", 0, 0, &entity_chunk);
+
+    world.upgrade("1.18.2", &[]);
+
+    let entity_chunk = world.read_chunk("entities", 0, 0).unwrap();
+    assert_eq!(data_version(&entity_chunk), Some(V1_18_2));
+    let Some(JValue::List(JList::Compound(entities))) = entity_chunk.get("Entities") else {
+        panic!("entity chunk has no entities");
+    };
+    assert_eq!(entities.len(), 2);
+}
+
+#[test]
+fn gives_duplicate_entities_fresh_uuids() {
+    let world = TestWorld::new("duplicate_uuids");
+    world.write_level_dat(V1_13);
+    world.write_chunk("region", 0, 0, &paletted_chunk(0, 0, V1_13, "full"));
+    // a copy of the same pig, leashed to a second pig in the same chunk
+    let mut copied_chunk = paletted_chunk(1, 0, V1_13, "full");
+    let Some(JValue::Compound(level)) = copied_chunk.get_mut("Level") else {
+        unreachable!()
+    };
+    let Some(JValue::List(JList::Compound(entities))) = level.get_mut("Entities") else {
+        unreachable!()
+    };
+    let mut leashed_pig = pig(20.0, 8.0);
+    leashed_pig.insert("UUIDMost", 0x0123_4567_89ab_4defi64 + 1);
+    leashed_pig.insert(
+        "Leash",
+        jcompound! {
+            "UUIDMost" => 0x0123_4567_89ab_4defi64,
+            "UUIDLeast" => -0x7edc_ba98_7654_3210i64,
+        },
+    );
+    entities.push(leashed_pig);
+    world.write_chunk("region", 1, 0, &copied_chunk);
+
+    world.upgrade("1.18.2", &["--duplicate-uuids", "fix"]);
+
+    let mut pig_uuids = Vec::new();
+    for chunk_x in 0..2 {
+        let entity_chunk = world.read_chunk("entities", chunk_x, 0).unwrap();
+        let Some(JValue::List(JList::Compound(entities))) = entity_chunk.get("Entities") else {
+            panic!("entity chunk has no entities");
+        };
+        let Some(JValue::IntArray(uuid)) = entities[0].get("UUID") else {
+            panic!("pig has no UUID");
+        };
+        if let Some(JValue::Compound(leash)) = entities.get(1).and_then(|pig| pig.get("Leash")) {
+            assert_eq!(leash.get("UUID"), Some(&JValue::IntArray(uuid.clone())));
+        }
+        pig_uuids.push(uuid.clone());
+    }
+    assert_ne!(pig_uuids[0], pig_uuids[1]);
+}
+
+#[test]
+fn fixes_duplicate_uuids_in_up_to_date_world() {
+    let world = TestWorld::new("duplicate_uuids_up_to_date");
+    world.write_level_dat(V1_20_4);
+    for chunk_x in 0..2 {
+        let mut entity_chunk = entity_chunk(chunk_x, 0, V1_20_4);
+        let Some(JValue::List(JList::Compound(entities))) = entity_chunk.get_mut("Entities") else {
+            unreachable!()
+        };
+        entities[0].insert("UUID", JValue::IntArray(vec![1, 2, 3, 4]));
+        world.write_chunk("entities", chunk_x, 0, &entity_chunk);
+    }
+
+    world.upgrade("1.20.4", &["--duplicate-uuids", "fix"]);
+
+    let uuids: Vec<_> = (0..2)
+        .map(|chunk_x| {
+            let entity_chunk = world.read_chunk("entities", chunk_x, 0).unwrap();
+            let Some(JValue::List(JList::Compound(entities))) = entity_chunk.get("Entities") else {
+                panic!("entity chunk has no entities");
+            };
+            entities[0].get("UUID").cloned()
+        })
+        .collect();
+    assert!(uuids.contains(&Some(JValue::IntArray(vec![1, 2, 3, 4]))));
+    assert_ne!(uuids[0], uuids[1]);
+}
+
+#[test]
+fn moves_chunks_in_the_wrong_slot() {
+    let world = TestWorld::new("wrong_slot_move");
+    world.write_level_dat(V1_20_4);
+    world.write_chunk("region", 0, 0, &new_format_chunk(2, 0, V1_20_4));
+    // the slot this one wants is taken, so it stays where it is
+    world.write_chunk("region", 1, 0, &new_format_chunk(3, 0, V1_20_4));
+    world.write_chunk("region", 3, 0, &new_format_chunk(3, 0, V1_20_4));
+    world.write_chunk("entities", 0, 0, &entity_chunk(0, 0, V1_20_4));
+
+    world.upgrade("1.20.4", &["--wrong-slot-chunks", "move"]);
+
+    assert!(world.read_chunk("region", 0, 0).is_none());
+    let moved = world.read_chunk("region", 2, 0).unwrap();
+    assert_eq!(moved.get("xPos"), Some(&JValue::Int(2)));
+    assert!(world.read_chunk("region", 1, 0).is_some());
+    // the entities go along with the chunk
+    assert!(world.read_chunk("entities", 0, 0).is_none());
+    let moved_entities = world.read_chunk("entities", 2, 0).unwrap();
+    assert_eq!(
+        moved_entities.get("Position"),
+        Some(&JValue::IntArray(vec![2, 0]))
+    );
+}
+
+#[test]
+fn rewrites_position_of_chunks_in_the_wrong_slot() {
+    let world = TestWorld::new("wrong_slot_rewrite");
+    world.write_level_dat(V1_13);
+    world.write_chunk("region", 0, 0, &paletted_chunk(4, 4, V1_13, "full"));
+
+    world.upgrade("1.18.2", &["--wrong-slot-chunks", "rewrite"]);
+
+    let chunk = world.read_chunk("region", 0, 0).unwrap();
+    assert_eq!(data_version(&chunk), Some(V1_18_2));
+    assert_eq!(chunk.get("xPos"), Some(&JValue::Int(0)));
+    assert_eq!(chunk.get("zPos"), Some(&JValue::Int(0)));
+}
+
+#[test]
+fn prunes_uninhabited_chunks() {
+    let world = TestWorld::new("prune_inhabited");
+    world.write_level_dat(V1_20_4);
+    world.write_chunk("region", 0, 0, &new_format_chunk(0, 0, V1_20_4));
+    let mut visited_chunk = new_format_chunk(1, 0, V1_20_4);
+    visited_chunk.insert("InhabitedTime", 1000i64);
+    world.write_chunk("region", 1, 0, &visited_chunk);
+    world.write_chunk("entities", 0, 0, &entity_chunk(0, 0, V1_20_4));
+    world.write_chunk("entities", 1, 0, &entity_chunk(1, 0, V1_20_4));
+
+    world.upgrade("1.20.4", &["--prune-inhabited-below", "100"]);
+
+    assert!(world.read_chunk("region", 0, 0).is_none());
+    assert!(world.read_chunk("entities", 0, 0).is_none());
+    assert!(world.read_chunk("region", 1, 0).is_some());
+    assert!(world.read_chunk("entities", 1, 0).is_some());
+}
+
+#[test]
+fn drops_proto_chunks() {
+    let world = TestWorld::new("drop_proto_chunks");
+    world.write_level_dat(V1_13);
+    world.write_chunk("region", 0, 0, &paletted_chunk(0, 0, V1_13, "decorated"));
+    world.write_chunk("region", 1, 0, &paletted_chunk(1, 0, V1_13, "lighted"));
+    world.write_chunk("region", 2, 0, &paletted_chunk(2, 0, V1_13, "full"));
+
+    world.upgrade("1.18.2", &["--drop-proto-chunks", "light"]);
+
+    assert!(world.read_chunk("region", 0, 0).is_none());
+    assert!(world.read_chunk("region", 1, 0).is_some());
+    assert!(world.read_chunk("region", 2, 0).is_some());
+}
+
+#[test]
+fn disables_blending_and_below_zero_retrogen() {
+    let upgrade = |name: &str, args: &[&str]| {
+        let world = TestWorld::new(name);
+        world.write_level_dat(V1_13);
+        world.write_chunk("region", 0, 0, &paletted_chunk(0, 0, V1_13, "full"));
+        world.upgrade("1.18.2", args);
+        world.read_chunk("region", 0, 0).unwrap()
+    };
+
+    let chunk = upgrade("retrogen_auto", &[]);
+    assert!(chunk.contains_key("blending_data"));
+
+    let chunk = upgrade(
+        "retrogen_off",
+        &[
+            "--blending",
+            "overworld=off",
+            "--below-zero-retrogen",
+            "off",
+        ],
+    );
+    assert!(!chunk.contains_key("blending_data"));
+    assert!(!chunk.contains_key("below_zero_retrogen"));
+}
+
+#[test]
+fn only_forces_retrogen_in_the_overworld() {
+    let world = TestWorld::new("retrogen_nether");
+    world.write_level_dat(V1_13);
+    world.write_chunk("DIM-1/region", 0, 0, &paletted_chunk(0, 0, V1_13, "full"));
+
+    assert!(!run(&[
+        world.path.as_os_str(),
+        OsStr::new("1.18.2"),
+        OsStr::new("--blending"),
+        OsStr::new("the_nether=on"),
+    ]));
+
+    world.upgrade(
+        "1.18.2",
+        &["--blending", "on", "--below-zero-retrogen", "on"],
+    );
+
+    let chunk = world.read_chunk("DIM-1/region", 0, 0).unwrap();
+    assert_eq!(data_version(&chunk), Some(V1_18_2));
+    assert!(!chunk.contains_key("blending_data"));
+    assert!(!chunk.contains_key("below_zero_retrogen"));
+    let Some(JValue::List(JList::Compound(sections))) = chunk.get("sections") else {
+        panic!("chunk has no sections");
+    };
+    assert!(sections
+        .iter()
+        .all(|section| section.get("Y").and_then(|y| y.as_i32()) >= Some(0)));
+}
+
+#[test]
+fn upgrades_dimensions_missing_from_level_dat() {
+    let world = TestWorld::new("unlisted_dimension");
+    world.write_level_dat(V1_17_1);
+    let regions = "dimensions/test/nested/custom/region";
+    world.write_chunk(regions, 0, 0, &paletted_chunk(0, 0, V1_17_1, "full"));
+
+    world.upgrade("1.18.2", &[]);
+
+    let chunk = world.read_chunk(regions, 0, 0).unwrap();
+    assert_eq!(data_version(&chunk), Some(V1_18_2));
+}
+
+#[test]
+fn upgrades_stale_backup_of_up_to_date_playerdata() {
+    let world = TestWorld::new("stale_player_backup");
+    world.write_level_dat(V1_20_4);
+    let player_path = world.join(format!("playerdata/{PLAYER_UUID}.dat"));
+    write_dat(&player_path, &player(V1_20_4));
+    let old_player_path = player_path.with_extension("dat_old");
+    write_dat(&old_player_path, &player(V1_17_1));
+
+    world.upgrade("1.20.4", &["--old-files", "refresh"]);
+
+    assert_eq!(data_version(&read_dat(&old_player_path)), Some(V1_20_4));
+}
+
+#[test]
+fn keeps_recovered_level_dat_that_cannot_be_upgraded() {
+    let world = TestWorld::new("recover_newer_level_dat");
+    world.write_level_dat(V1_20_4);
+    std::fs::copy(world.join("level.dat"), world.join("level.dat_old")).unwrap();
+    std::fs::write(world.join("level.dat"), b"not a level.dat").unwrap();
+
+    // level.dat_old is newer than the target version, so it can't be upgraded
+    run(&[world.path.as_os_str(), OsStr::new("1.18.2")]);
+
+    assert_eq!(
+        std::fs::read(world.join("level.dat_corrupt")).unwrap(),
+        b"not a level.dat"
+    );
+    assert_eq!(data_version(&world.read_level_dat()), Some(V1_20_4));
+}
+
+#[test]
+fn retries_quarantined_files_only_inside_the_world() {
+    let world = TestWorld::new("retry_quarantine");
+    let quarantine = world.join("quarantine");
+    let escaped_name = format!(
+        "{}-escaped.dat",
+        world.path.file_name().unwrap().to_string_lossy()
+    );
+    for (name, path) in [
+        ("inside.dat", format!("playerdata/{PLAYER_UUID}.dat")),
+        ("outside.dat", format!("../{escaped_name}")),
+    ] {
+        write_dat(&quarantine.join(name), &player(V1_12_2));
+        write_json(
+            &quarantine.join(format!("{name}.quarantine.json")),
+            jcompound! {
+                "kind" => JavaStr::from_str("file"),
+                "path" => JavaStr::from_str(&path),
+                "type" => JavaStr::from_str("player"),
+                "default_version" => 99,
+                "format" => JavaStr::from_str("nbt"),
+                "reason" => JavaStr::from_str("test"),
+            },
+        );
+    }
+    std::fs::create_dir_all(world.join("playerdata")).unwrap();
+
+    assert!(run(&[
+        OsStr::new("retry-quarantine"),
+        quarantine.as_os_str(),
+        world.path.as_os_str(),
+        OsStr::new("1.20.4"),
+    ]));
+
+    let player = read_dat(&world.join(format!("playerdata/{PLAYER_UUID}.dat")));
+    assert_eq!(data_version(&player), Some(V1_20_4));
+    assert!(!world.path.with_file_name(&escaped_name).exists());
+    assert!(quarantine.join("outside.dat").exists());
+}
+
+#[test]
+fn writes_chunks_whose_only_change_is_the_data_version() {
+    let world = TestWorld::new("version_only_change");
+    world.write_level_dat(V1_18_2);
+    world.write_chunk("poi", 0, 0, &poi_chunk(V1_18_2));
+    world.write_chunk("poi", 1, 0, &poi_chunk(V1_20_4));
+    // clear the timestamps of both chunks, which are set again when a chunk is written
+    let region_path = world.join("poi/r.0.0.mca");
+    let mut region = std::fs::read(&region_path).unwrap();
+    region[4096..4104].fill(0);
+    std::fs::write(&region_path, &region).unwrap();
+
+    world.upgrade("1.20.4", &[]);
+
+    let chunk = world.read_chunk("poi", 0, 0).unwrap();
+    assert_eq!(data_version(&chunk), Some(V1_20_4));
+    let region = std::fs::read(&region_path).unwrap();
+    assert_ne!(region[4096..4100], [0; 4], "upgraded chunk wasn't written");
+    assert_eq!(region[4100..4104], [0; 4], "up to date chunk was rewritten");
+}
+
+#[test]
+fn quarantines_external_chunks_with_their_data() {
+    let world = TestWorld::new("quarantine_external");
+    world.write_level_dat(V1_17_1);
+    world.write_chunk("region", 0, 0, &paletted_chunk(0, 0, V1_17_1, "full"));
+
+    // point the chunk at a truncated external file, so that it fails to read
+    let region_path = world.join("region/r.0.0.mca");
+    let mut region = std::fs::read(&region_path).unwrap();
+    let offset = (u32::from_be_bytes(region[..4].try_into().unwrap()) >> 8) as usize * 4096;
+    let length = u32::from_be_bytes(region[offset..offset + 4].try_into().unwrap()) as usize;
+    let compression = region[offset + 4];
+    let external_data = region[offset + 5..offset + 4 + length / 2].to_vec();
+    region[offset..offset + 4].copy_from_slice(&1u32.to_be_bytes());
+    region[offset + 4] = compression | 0x80;
+    std::fs::write(&region_path, &region).unwrap();
+    std::fs::write(world.join("region/c.0.0.mcc"), &external_data).unwrap();
+
+    let quarantine = world.join("quarantine");
+    world.upgrade("1.18.2", &["--quarantine", quarantine.to_str().unwrap()]);
+
+    let payload = std::fs::read(quarantine.join("region/c.0.0.chunk")).unwrap();
+    assert_eq!(payload[..4], (external_data.len() as u32 + 1).to_be_bytes());
+    assert_eq!(payload[4], compression);
+    assert_eq!(payload[5..], external_data[..]);
+    assert!(quarantine
+        .join("region/c.0.0.chunk.quarantine.json")
+        .exists());
+}
+
+#[test]
+fn leaves_up_to_date_world_untouched() {
+    let world = TestWorld::new("up_to_date");
+    world.write_level_dat(V1_20_4);
+    world.write_chunk("region", 0, 0, &new_format_chunk(0, 0, V1_20_4));
+    world.write_chunk("entities", 0, 0, &entity_chunk(0, 0, V1_20_4));
+    let player_path = world.join(format!("playerdata/{PLAYER_UUID}.dat"));
+    write_dat(&player_path, &player(V1_20_4));
+    let region = std::fs::read(world.join("region/r.0.0.mca")).unwrap();
+    let entities = std::fs::read(world.join("entities/r.0.0.mca")).unwrap();
+    let player = std::fs::read(&player_path).unwrap();
+
+    world.upgrade("1.20.4", &[]);
+
+    assert_eq!(
+        std::fs::read(world.join("region/r.0.0.mca")).unwrap(),
+        region
+    );
+    assert_eq!(
+        std::fs::read(world.join("entities/r.0.0.mca")).unwrap(),
+        entities
+    );
+    assert_eq!(std::fs::read(&player_path).unwrap(), player);
+}
+
+#[test]
+fn dry_run_writes_nothing() {
+    let world = TestWorld::new("dry_run");
+    world.write_level_dat(V1_12_2);
+    world.write_chunk("region", 0, 0, &legacy_chunk(0, 0));
+    write_dat(
+        &world.join("data/Village.dat"),
+        &legacy_structure_data("Village"),
+    );
+    let level_dat = std::fs::read(world.join("level.dat")).unwrap();
+    let region = std::fs::read(world.join("region/r.0.0.mca")).unwrap();
+
+    world.upgrade("1.20.4", &["--dry-run"]);
+
+    assert_eq!(std::fs::read(world.join("level.dat")).unwrap(), level_dat);
+    assert_eq!(
+        std::fs::read(world.join("region/r.0.0.mca")).unwrap(),
+        region
+    );
+    assert!(world.join("data/Village.dat").exists());
+    assert!(!world.join("entities/r.0.0.mca").exists());
+}
+
+#[test]
+fn refuses_to_downgrade() {
+    let world = TestWorld::new("downgrade");
+    world.write_level_dat(V1_18_2);
+    world.write_chunk("region", 0, 0, &new_format_chunk(0, 0, V1_20_4));
+
+    world.upgrade("1.18.2", &[]);
+
+    let chunk = world.read_chunk("region", 0, 0).unwrap();
+    assert_eq!(data_version(&chunk), Some(V1_20_4));
+}
+
+#[test]
+fn reports_ids_missing_from_registries_in_up_to_date_chunks() {
+    let world = TestWorld::new("unknown_ids_registries");
+    world.write_level_dat(V1_20_4);
+    let mut chunk = new_format_chunk(0, 0, V1_20_4);
+    let Some(JValue::List(JList::Compound(sections))) = chunk.get_mut("sections") else {
+        unreachable!()
+    };
+    let Some(JValue::Compound(block_states)) = sections[0].get_mut("block_states") else {
+        unreachable!()
+    };
+    block_states.insert(
+        "palette",
+        JList::Compound(vec![
+            jcompound! { "Name" => JavaStr::from_str("minecraft:stone") },
+            // renamed to short_grass in 1.20.3
+            jcompound! { "Name" => JavaStr::from_str("minecraft:grass") },
+        ]),
+    );
+    world.write_chunk("region", 0, 0, &chunk);
+    let registry = |id: &str| {
+        jcompound! {
+            "entries" => jcompound! { id => jcompound! { "protocol_id" => 0 } },
+        }
+    };
+    let registries_path = world.join("registries.json");
+    write_json(
+        &registries_path,
+        jcompound! {
+            "minecraft:block" => registry("minecraft:stone"),
+            "minecraft:block_entity_type" => registry("minecraft:chest"),
+            "minecraft:entity_type" => registry("minecraft:pig"),
+            "minecraft:item" => registry("minecraft:stone"),
+        },
+    );
+    let report_path = world.join("unknown_ids.json");
+
+    world.upgrade(
+        "1.20.4",
+        &[
+            "--registries",
+            registries_path.to_str().unwrap(),
+            "--unknown-id-report",
+            report_path.to_str().unwrap(),
+        ],
+    );
+
+    let report = read_json(&report_path);
+    let Some(JValue::Compound(blocks)) = report.get("blocks") else {
+        panic!("report has no blocks");
+    };
+    assert_eq!(blocks.len(), 1);
+    assert!(blocks.contains_key("minecraft:grass"));
+}
+
+#[test]
+fn reports_and_remaps_unknown_ids() {
+    let world = TestWorld::new("unknown_ids");
+    world.write_level_dat(V1_18_2);
+    let mut chunk = new_format_chunk(0, 0, V1_18_2);
+    let Some(JValue::List(JList::Compound(sections))) = chunk.get_mut("sections") else {
+        unreachable!()
+    };
+    let Some(JValue::Compound(block_states)) = sections[0].get_mut("block_states") else {
+        unreachable!()
+    };
+    block_states.insert(
+        "palette",
+        JList::Compound(vec![
+            jcompound! { "Name" => JavaStr::from_str("examplemod:copper_ore") },
+            jcompound! { "Name" => JavaStr::from_str("othermod:machine") },
+        ]),
+    );
+    world.write_chunk("region", 0, 0, &chunk);
+    let remap_path = world.join("remap.json");
+    write_json(
+        &remap_path,
+        jcompound! { "examplemod:copper_ore" => JavaStr::from_str("minecraft:copper_ore") },
+    );
+    let report_path = world.join("unknown_ids.json");
+
+    world.upgrade(
+        "1.20.4",
+        &[
+            "--remap-ids",
+            remap_path.to_str().unwrap(),
+            "--unknown-id-report",
+            report_path.to_str().unwrap(),
+        ],
+    );
+
+    let chunk = world.read_chunk("region", 0, 0).unwrap();
+    let Some(JValue::List(JList::Compound(sections))) = chunk.get("sections") else {
+        panic!("chunk has no sections");
+    };
+    let Some(JValue::Compound(block_states)) = sections[0].get("block_states") else {
+        panic!("section has no block states");
+    };
+    let Some(JValue::List(JList::Compound(palette))) = block_states.get("palette") else {
+        panic!("section has no palette");
+    };
+    assert!(
+        matches!(palette[0].get("Name"), Some(JValue::String(name)) if name == "minecraft:copper_ore")
+    );
+
+    let report = read_json(&report_path);
+    let Some(JValue::Compound(blocks)) = report.get("blocks") else {
+        panic!("report has no blocks");
+    };
+    assert_eq!(blocks.len(), 1);
+    let Some(JValue::Compound(machine)) = blocks.get("othermod:machine") else {
+        panic!("othermod:machine wasn't reported");
+    };
+    assert_eq!(machine.get("count").and_then(|v| v.as_i32()), Some(1));
+}